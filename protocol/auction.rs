@@ -0,0 +1,246 @@
+use crate::collateral::CollateralType;
+use crate::event::{record_auction_bid, record_close_auction, record_reset_auction};
+use crate::guard::{GuardAuction, GuardPrincipal};
+use crate::logs::INFO;
+use crate::management::transfer_stablecoin_from;
+use crate::numeric::{UsdBtc, CKBTC, TAL};
+use crate::stablecoin::StablecoinType;
+use crate::{mutate_state, read_state, ProtocolError, MIN_TAL_AMOUNT, SEC_NANOS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Time for the price of an auction to decay from its start price to its floor price.
+pub const AUCTION_DURATION_NANOS: u64 = 30 * 60 * SEC_NANOS;
+pub const CHECK_AUCTIONS_INTERVAL: Duration = Duration::from_secs(60);
+/// Auctions start above the oracle price so that bidders set the discount.
+const AUCTION_START_PREMIUM: Decimal = dec!(1.1);
+const AUCTION_FLOOR: Decimal = dec!(0.8);
+/// Times an auction restarts before its debt is booked as bad debt.
+pub const MAX_AUCTION_RESTARTS: u64 = 48;
+
+/// Collateral of a liquidated vault offered at a decaying price, keyed by the vault id.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Auction {
+    pub vault_id: u64,
+    pub owner: Principal,
+    pub collateral_type: CollateralType,
+    pub stablecoin: StablecoinType,
+    /// Collateral left to sell.
+    pub collateral_amount: CKBTC,
+    /// Debt left to cover, expressed in the stablecoin.
+    pub debt: TAL,
+    /// Oracle price of the collateral in the stablecoin when the auction (re)started.
+    pub oracle_price: UsdBtc,
+    pub start_time: u64,
+    /// Times the auction reached its floor price and restarted.
+    pub restarts: u64,
+}
+
+impl Auction {
+    /// Price of the collateral in the stablecoin, decaying linearly from a premium
+    /// over the oracle price down to the floor.
+    pub fn current_price(&self, now: u64) -> UsdBtc {
+        let elapsed = now
+            .saturating_sub(self.start_time)
+            .min(AUCTION_DURATION_NANOS);
+        let decay = Decimal::from_u64(elapsed).unwrap()
+            / Decimal::from_u64(AUCTION_DURATION_NANOS).unwrap();
+        let factor = AUCTION_START_PREMIUM - (AUCTION_START_PREMIUM - AUCTION_FLOOR) * decay;
+        UsdBtc::from(self.oracle_price.0 * factor)
+    }
+
+    pub fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.start_time) >= AUCTION_DURATION_NANOS
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct AuctionStatus {
+    pub vault_id: u64,
+    pub owner: Principal,
+    pub collateral_type: CollateralType,
+    pub stablecoin: StablecoinType,
+    pub collateral_amount: u64,
+    pub debt: u64,
+    pub current_price: f64,
+    pub start_time: u64,
+}
+
+impl AuctionStatus {
+    pub fn new(auction: &Auction, now: u64) -> Self {
+        Self {
+            vault_id: auction.vault_id,
+            owner: auction.owner,
+            collateral_type: auction.collateral_type,
+            stablecoin: auction.stablecoin,
+            collateral_amount: auction.collateral_amount.to_u64(),
+            debt: auction.debt.to_u64(),
+            current_price: auction.current_price(now).to_f64(),
+            start_time: auction.start_time,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct BidSuccess {
+    pub block_index: u64,
+    pub stablecoin_amount: u64,
+    pub collateral_amount: u64,
+}
+
+/// Buys collateral of an auction at its current price, spending at most `max_amount`
+/// of the auctioned stablecoin, which is burnt to cover the debt.
+pub async fn bid(
+    vault_id: u64,
+    max_amount: u64,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+) -> Result<BidSuccess, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+    let _guard_auction = GuardAuction::new(vault_id)?;
+
+    let max_amount: TAL = max_amount.into();
+
+    if max_amount < MIN_TAL_AMOUNT {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: MIN_TAL_AMOUNT.to_u64(),
+        });
+    }
+
+    let auction = match read_state(|s| s.auctions.get(&vault_id).cloned()) {
+        Some(auction) => auction,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "no auction for vault {vault_id}"
+            )))
+        }
+    };
+
+    let price = auction.current_price(ic_cdk::api::time());
+    let mut stablecoin_amount = max_amount.min(auction.debt);
+    let mut collateral_amount = stablecoin_amount / price;
+    if collateral_amount > auction.collateral_amount {
+        collateral_amount = auction.collateral_amount;
+        stablecoin_amount = collateral_amount * price;
+    }
+    if collateral_amount <= read_state(|s| s.get_collateral_ledger_fee(auction.collateral_type)) {
+        return Err(ProtocolError::GenericError(
+            "bid is too low to cover the collateral transfer fee".to_string(),
+        ));
+    }
+
+    match transfer_stablecoin_from(
+        stablecoin_amount,
+        caller,
+        from_subaccount,
+        auction.stablecoin,
+    )
+    .await
+    {
+        Ok(block_index) => {
+            // The auction may have been closed or bid on during the transfer.
+            let still_open = mutate_state(|s| match s.auctions.get(&vault_id) {
+                Some(current)
+                    if stablecoin_amount <= current.debt
+                        && collateral_amount <= current.collateral_amount =>
+                {
+                    record_auction_bid(
+                        s,
+                        vault_id,
+                        caller,
+                        stablecoin_amount,
+                        collateral_amount,
+                        to,
+                        block_index,
+                    );
+                    true
+                }
+                _ => false,
+            });
+            if !still_open {
+                log!(
+                    INFO,
+                    "[bid] auction of vault {vault_id} changed during the transfer, refunding {stablecoin_amount} {} to {caller}",
+                    auction.stablecoin
+                );
+                let refund_to = Account {
+                    owner: caller,
+                    subaccount: from_subaccount,
+                };
+                crate::refund_stablecoin(stablecoin_amount, refund_to, auction.stablecoin).await;
+                return Err(ProtocolError::GenericError(format!(
+                    "auction of vault {vault_id} changed during the bid, the stablecoin is refunded"
+                )));
+            }
+            log!(
+                INFO,
+                "[bid] {caller} bought {collateral_amount} of the collateral of vault {vault_id} for {stablecoin_amount} {}",
+                auction.stablecoin
+            );
+            ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+                ic_cdk::spawn(crate::process_pending_transfer())
+            });
+            Ok(BidSuccess {
+                block_index,
+                stablecoin_amount: stablecoin_amount.to_u64(),
+                collateral_amount: collateral_amount.to_u64(),
+            })
+        }
+        Err(transfer_from_error) => Err(ProtocolError::TransferFromError(
+            transfer_from_error,
+            stablecoin_amount.to_u64(),
+        )),
+    }
+}
+
+/// Restarts the auctions that reached their floor price at the current oracle price, the
+/// auctions restarted `MAX_AUCTION_RESTARTS` times are closed.
+pub fn check_auctions() {
+    let now = ic_cdk::api::time();
+    let expired_auctions: Vec<(u64, u64, Option<UsdBtc>)> = read_state(|s| {
+        s.auctions
+            .values()
+            // Auctions with a bid in flight are checked on the next run.
+            .filter(|auction| {
+                auction.is_expired(now) && !s.auction_guards.contains(&auction.vault_id)
+            })
+            .map(|auction| {
+                (
+                    auction.vault_id,
+                    auction.restarts,
+                    s.get_collateral_rate_in(auction.collateral_type, auction.stablecoin),
+                )
+            })
+            .collect()
+    });
+    for (vault_id, restarts, oracle_price) in expired_auctions {
+        if restarts >= MAX_AUCTION_RESTARTS {
+            log!(
+                INFO,
+                "[check_auctions] closing auction of vault {vault_id} after {restarts} restarts"
+            );
+            mutate_state(|s| record_close_auction(s, vault_id));
+            continue;
+        }
+        match oracle_price {
+            Some(oracle_price) => {
+                log!(
+                    INFO,
+                    "[check_auctions] restarting auction of vault {vault_id} at {oracle_price}"
+                );
+                mutate_state(|s| record_reset_auction(s, vault_id, oracle_price, now));
+            }
+            None => log!(
+                INFO,
+                "[check_auctions] cannot restart auction of vault {vault_id} without a price"
+            ),
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use crate::numeric::{Ratio, UsdBtc, CKBTC, TAL};
+use crate::ProtocolError;
+use candid::{CandidType, Deserialize, Principal};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use std::fmt;
+
+/// Minimum collateral ratio accepted when registering a new collateral type.
+pub const MINIMUM_REGISTRABLE_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.0));
+
+/// Identifies the token backing a vault.
+#[derive(
+    CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum CollateralType {
+    /// The ckBTC ledger configured at init, vaults opened before
+    /// multi-collateral support are all backed by ckBTC.
+    #[default]
+    CkBtc,
+    /// Any other ICRC-2 token, identified by its ledger principal.
+    Icrc(Principal),
+}
+
+impl fmt::Display for CollateralType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CollateralType::CkBtc => write!(f, "ckBTC"),
+            CollateralType::Icrc(ledger_principal) => write!(f, "ICRC({ledger_principal})"),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddCollateralTypeArg {
+    pub ledger_principal: Principal,
+    /// Symbol of the token on the exchange rate canister, e.g. "ETH" or "ICP".
+    pub xrc_symbol: String,
+    pub decimals: u8,
+    pub minimum_collateral_ratio_e8s: u64,
+    pub debt_ceiling: u64,
+    pub ledger_fee: u64,
+    /// Smallest amount of the token accepted as margin, in base units. Defaults to a
+    /// thousandth of a token, see [default_minimum_amount].
+    #[serde(default)]
+    pub minimum_amount: Option<u64>,
+}
+
+/// Configuration of a collateral type registered on top of ckBTC.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CollateralConfig {
+    pub xrc_symbol: String,
+    pub decimals: u8,
+    /// Collateral ratio under which vaults backed by this token get liquidated.
+    pub minimum_collateral_ratio: Ratio,
+    /// Maximum amount of TAL that can be borrowed against this token.
+    pub debt_ceiling: TAL,
+    pub ledger_fee: CKBTC,
+    /// Smallest amount of the token accepted as margin.
+    pub minimum_amount: CKBTC,
+    /// Last USD price of 10^8 base units of the token, so that amounts can be
+    /// valued with the same arithmetic as ckBTC whatever the token decimals.
+    pub last_rate: Option<UsdBtc>,
+    /// Last timestamp of the fetched price.
+    pub last_timestamp: Option<u64>,
+}
+
+impl From<AddCollateralTypeArg> for CollateralConfig {
+    fn from(arg: AddCollateralTypeArg) -> Self {
+        Self {
+            xrc_symbol: arg.xrc_symbol,
+            decimals: arg.decimals,
+            minimum_collateral_ratio: ratio_from_e8s(arg.minimum_collateral_ratio_e8s),
+            debt_ceiling: TAL::from(arg.debt_ceiling),
+            ledger_fee: CKBTC::from(arg.ledger_fee),
+            minimum_amount: CKBTC::from(
+                arg.minimum_amount
+                    .unwrap_or_else(|| default_minimum_amount(arg.decimals)),
+            ),
+            last_rate: None,
+            last_timestamp: None,
+        }
+    }
+}
+
+impl CollateralConfig {
+    /// Converts the USD price of one whole token into the price of 10^8 base units.
+    pub fn normalize_rate(&self, usd_price: Decimal) -> UsdBtc {
+        let decimals = self.decimals as i64 - 8;
+        let scale = Decimal::from_u64(10_u64.pow(decimals.unsigned_abs() as u32))
+            .expect("failed to construct decimal from u64");
+        if decimals >= 0 {
+            UsdBtc::from(usd_price / scale)
+        } else {
+            UsdBtc::from(usd_price * scale)
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct CollateralStatus {
+    pub collateral_type: CollateralType,
+    pub ledger_principal: Principal,
+    pub xrc_symbol: String,
+    pub minimum_collateral_ratio: f64,
+    pub debt_ceiling: u64,
+    pub minimum_amount: u64,
+    pub total_margin: u64,
+    pub total_borrowed: u64,
+    pub last_rate: f64,
+    pub last_timestamp: u64,
+}
+
+/// A thousandth of a token with `decimals` decimals, in base units: the same as
+/// [crate::MIN_CKBTC_AMOUNT] for 8 decimals.
+pub fn default_minimum_amount(decimals: u8) -> u64 {
+    10_u64.pow(decimals.saturating_sub(3) as u32)
+}
+
+pub fn ratio_from_e8s(value_e8s: u64) -> Ratio {
+    Ratio::from(Decimal::from_u64(value_e8s).unwrap() / dec!(100_000_000))
+}
+
+pub fn validate_add_collateral_type_arg(arg: &AddCollateralTypeArg) -> Result<(), ProtocolError> {
+    if arg.xrc_symbol.is_empty() {
+        return Err(ProtocolError::GenericError(
+            "collateral xrc symbol cannot be empty".to_string(),
+        ));
+    }
+    if arg.decimals > 18 {
+        return Err(ProtocolError::GenericError(format!(
+            "collateral tokens with more than 18 decimals are not supported, got {}",
+            arg.decimals
+        )));
+    }
+    if ratio_from_e8s(arg.minimum_collateral_ratio_e8s) < MINIMUM_REGISTRABLE_COLLATERAL_RATIO {
+        return Err(ProtocolError::GenericError(format!(
+            "minimum collateral ratio should be at least {MINIMUM_REGISTRABLE_COLLATERAL_RATIO}"
+        )));
+    }
+    if crate::state::read_state(|s| s.ckbtc_ledger_principal == arg.ledger_principal) {
+        return Err(ProtocolError::GenericError(
+            "ckBTC is already the default collateral".to_string(),
+        ));
+    }
+    Ok(())
+}
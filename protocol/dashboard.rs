@@ -52,6 +52,10 @@ pub fn build_dashboard() -> Vec<u8> {
                     <h3>Metadata</h3>
                     {}
                 </div>
+                <div>
+                    <h3>BTC Price (7 days)</h3>
+                    {}
+                </div>
                 <div>
                     <h3>Vault Table</h3>
                     <table>
@@ -59,15 +63,51 @@ pub fn build_dashboard() -> Vec<u8> {
                             <tr>
                                 <th>Vault Id</th>
                                 <th>Owner</th>
-                                <th>Borrowed TAL</th>
-                                <th>ckBTC margin</th>
+                                <th>Collateral</th>
+                                <th>Stablecoin</th>
+                                <th>Borrowed</th>
+                                <th>Margin</th>
                             </tr>
                         </thead>
                         <tbody>{}</tbody>
                     </table>
                 </div>
                 <div>
-                    <h3>Liquidity Table</h3>
+                    <h3>Collateral Types</h3>
+                    <table>
+                        <thead>
+                            <tr>
+                                <th>Collateral</th>
+                                <th>Symbol</th>
+                                <th>Minimum Collateral Ratio</th>
+                                <th>Debt Ceiling</th>
+                                <th>Rate</th>
+                                <th>Margin</th>
+                                <th>Debt value (USD)</th>
+                            </tr>
+                        </thead>
+                        <tbody>{}</tbody>
+                    </table>
+                </div>
+                <div>
+                    <h3>Stablecoins</h3>
+                    <table>
+                        <thead>
+                            <tr>
+                                <th>Stablecoin</th>
+                                <th>Symbol</th>
+                                <th>Peg</th>
+                                <th>Peg Rate</th>
+                                <th>Borrowing Fee</th>
+                                <th>Borrowed</th>
+                                <th>Liquidity Provided</th>
+                            </tr>
+                        </thead>
+                        <tbody>{}</tbody>
+                    </table>
+                </div>
+                <div>
+                    <h3>TAL Liquidity Table</h3>
                     <table>
                         <thead>
                             <tr>
@@ -84,6 +124,7 @@ pub fn build_dashboard() -> Vec<u8> {
                         <thead>
                             <tr>
                                 <th>Owner</th>
+                                <th>Collateral</th>
                                 <th>Amount</th>
                             </tr>
                         </thead>
@@ -104,7 +145,10 @@ pub fn build_dashboard() -> Vec<u8> {
     </html>
     ",
         construct_metadata_table(),
+        construct_price_chart(),
         construct_vault_table(),
+        construct_collateral_table(),
+        construct_stablecoin_table(),
         construct_liquidity_table(),
         construct_liquidity_returns(),
         display_logs()
@@ -149,6 +193,10 @@ fn construct_metadata_table() -> String {
                         <th>Last BTC Price Timestamp</th>
                         <td class=\"ts-class\">{}</td>
                     </tr>
+                    <tr>
+                        <th>BTC Price Status</th>
+                        <td>{:?}</td>
+                    </tr>
                     <tr>
                         <th>Total Collateral Ratio</th>
                         <td>{}%</td>
@@ -177,6 +225,7 @@ fn construct_metadata_table() -> String {
             s.xrc_principal,
             last_btc_rate.unwrap_or(crate::UsdBtc::from(rust_decimal::Decimal::ZERO)),
             last_btc_timetsamp.unwrap_or(0),
+            s.btc_price_status(),
             s.total_collateral_ratio.to_f64() * 100.0,
             s.total_borrowed_tal_amount(),
             s.fee.to_f64() * 100.0,
@@ -186,10 +235,45 @@ fn construct_metadata_table() -> String {
     })
 }
 
+fn construct_price_chart() -> String {
+    const WIDTH: f64 = 800.0;
+    const HEIGHT: f64 = 200.0;
+    const PERIOD: u64 = 7 * 24 * 60 * 60 * crate::SEC_NANOS;
+    const RESOLUTION: u64 = 60 * 60 * crate::SEC_NANOS;
+
+    let now = ic_cdk::api::time();
+    let from = now.saturating_sub(PERIOD);
+    let prices = crate::storage::price_history(from, now, RESOLUTION, crate::MAX_PRICE_HISTORY_LEN);
+    if prices.is_empty() {
+        return "<p>No price recorded yet.</p>".to_string();
+    }
+    let rates: Vec<f64> = prices
+        .iter()
+        .map(|(_timestamp, rate)| rate.to_f64())
+        .collect();
+    let min_rate = rates.iter().cloned().fold(f64::MAX, f64::min);
+    let max_rate = rates.iter().cloned().fold(f64::MIN, f64::max);
+    let range = (max_rate - min_rate).max(1.0);
+    let points = with_utf8_buffer(|buf| {
+        for ((timestamp, _rate), rate) in prices.iter().zip(rates.iter()) {
+            let x = (timestamp - from) as f64 / PERIOD as f64 * WIDTH;
+            let y = HEIGHT - (rate - min_rate) / range * HEIGHT;
+            write!(buf, "{x:.1},{y:.1} ").unwrap();
+        }
+    });
+    format!(
+        "<svg width=\"{WIDTH}\" height=\"{HEIGHT}\" style=\"border: thin solid;\">
+            <polyline fill=\"none\" stroke=\"#f7931a\" stroke-width=\"2\" points=\"{points}\" />
+        </svg>
+        <p>Low: {min_rate:.2} USD, high: {max_rate:.2} USD, last: {:.2} USD</p>",
+        rates.last().unwrap()
+    )
+}
+
 fn construct_vault_table() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
-            for (_vault_id, vault) in s.vault_id_to_vaults.iter() {
+            for vault in s.vaults() {
                 write!(
                     buf,
                     "
@@ -198,10 +282,14 @@ fn construct_vault_table() -> String {
                     <td>{}</td>
                     <td>{}</td>
                     <td>{}</td>
+                    <td>{}</td>
+                    <td>{}</td>
                 </tr>
                 ",
                     vault.vault_id,
                     vault.owner,
+                    vault.collateral_type,
+                    vault.stablecoin,
                     (vault.borrowed_tal_amount),
                     (vault.ckbtc_margin_amount),
                 )
@@ -209,7 +297,7 @@ fn construct_vault_table() -> String {
             }
             write!(
                 buf,
-                "<tr><td colspan='2' style='text-align: right;'><b>Total</b></td><td>{}</td><td>{}</td></tr>",
+                "<tr><td colspan='4' style='text-align: right;'><b>Total (TAL, ckBTC margin)</b></td><td>{}</td><td>{}</td></tr>",
                 s.total_borrowed_tal_amount(),
                 s.total_ckbtc_margin_amount()
             )
@@ -218,10 +306,83 @@ fn construct_vault_table() -> String {
     })
 }
 
+fn construct_collateral_table() -> String {
+    use crate::collateral::CollateralType;
+
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            write!(
+                buf,
+                "<tr><td>{}</td><td>BTC</td><td>{}</td><td>-</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                CollateralType::CkBtc,
+                s.get_collateral_minimum_ratio(CollateralType::CkBtc),
+                s.last_btc_rate
+                    .unwrap_or(crate::UsdBtc::from(rust_decimal::Decimal::ZERO)),
+                s.total_ckbtc_margin_amount(),
+                s.total_debt_value_on(CollateralType::CkBtc)
+            )
+            .unwrap();
+            for (ledger_principal, config) in s.collaterals.iter() {
+                let collateral_type = CollateralType::Icrc(*ledger_principal);
+                write!(
+                    buf,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    collateral_type,
+                    config.xrc_symbol,
+                    config.minimum_collateral_ratio,
+                    config.debt_ceiling,
+                    config
+                        .last_rate
+                        .unwrap_or(crate::UsdBtc::from(rust_decimal::Decimal::ZERO)),
+                    s.total_margin_amount(collateral_type),
+                    s.total_debt_value_on(collateral_type)
+                )
+                .unwrap();
+            }
+        })
+    })
+}
+
+fn construct_stablecoin_table() -> String {
+    use crate::stablecoin::StablecoinType;
+
+    with_utf8_buffer(|buf| {
+        read_state(|s| {
+            write!(
+                buf,
+                "<tr><td>{}</td><td>TAL</td><td>USD</td><td>1</td><td>{}%</td><td>{}</td><td>{}</td></tr>",
+                StablecoinType::Tal,
+                s.fee.to_f64() * 100.0,
+                s.total_borrowed_tal_amount(),
+                s.total_provided_liquidity_amount(StablecoinType::Tal)
+            )
+            .unwrap();
+            for (ledger_principal, config) in s.stablecoins.iter() {
+                let stablecoin = StablecoinType::Icrc(*ledger_principal);
+                write!(
+                    buf,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}%</td><td>{}</td><td>{}</td></tr>",
+                    stablecoin,
+                    config.symbol,
+                    config.peg_symbol,
+                    config
+                        .last_peg_rate
+                        .map(|rate| rate.to_string())
+                        .unwrap_or("-".to_string()),
+                    config.borrowing_fee.to_f64() * 100.0,
+                    s.total_borrowed_amount_of(stablecoin),
+                    s.total_provided_liquidity_amount(stablecoin)
+                )
+                .unwrap();
+            }
+        })
+    })
+}
+
 fn construct_liquidity_table() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
-            for (principal, amount) in s.liquidity_pool.iter() {
+            for (principal, amount) in s.liquidity_pool.deposits() {
                 write!(
                     buf,
                     "
@@ -230,15 +391,14 @@ fn construct_liquidity_table() -> String {
                     <td>{}</td>
                 </tr>
                 ",
-                    principal,
-                    (*amount)
+                    principal, amount
                 )
                 .unwrap();
             }
             write!(
                 buf,
                 "<tr><td colspan='1' style='text-align: right;'><b>Total Liquidity Provided</b></td><td>{}</td></tr>",
-                s.total_provided_liquidity_amount()
+                s.total_provided_liquidity_amount(crate::stablecoin::StablecoinType::Tal)
             )
             .unwrap();
         });
@@ -248,13 +408,22 @@ fn construct_liquidity_table() -> String {
 fn construct_liquidity_returns() -> String {
     with_utf8_buffer(|buf| {
         read_state(|s| {
-            for (principal, amount) in s.liquidity_returns.iter() {
-                write!(buf, "<tr><td>{}</td><td>{}</td></tr>", principal, (*amount)).unwrap();
+            for (principal, returns) in s.all_liquidity_returns().iter() {
+                for (collateral_type, amount) in returns.iter() {
+                    write!(
+                        buf,
+                        "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                        principal,
+                        collateral_type,
+                        (*amount)
+                    )
+                    .unwrap();
+                }
             }
             write!(
                 buf,
-                "<tr><td colspan='1' style='text-align: right;'><b>Total Rewards Available</b></td><td>{}</td></tr>",
-                s.total_available_returns()
+                "<tr><td colspan='2' style='text-align: right;'><b>Total ckBTC Rewards Available</b></td><td>{}</td></tr>",
+                s.total_available_returns(crate::collateral::CollateralType::CkBtc)
             )
             .unwrap();
         })
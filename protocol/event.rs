@@ -1,20 +1,33 @@
+use crate::collateral::{AddCollateralTypeArg, CollateralType};
+use crate::liquidity_pool::CompoundingPreference;
 use crate::numeric::{UsdBtc, CKBTC, TAL};
-use crate::state::{PendingMarginTransfer, State};
-use crate::storage::record_event;
-use crate::vault::Vault;
-use crate::{InitArg, Mode, UpgradeArg};
+use crate::oracle::PriceSource;
+use crate::stablecoin::StablecoinType;
+use crate::state::{PendingMarginTransfer, PendingStablecoinTransfer, State};
+use crate::storage::{record_event, record_price};
+use crate::vault::{OperatorPermissions, Vault};
+use crate::{InitArg, Mode, UpgradeArg, MAX_REDEEMED_VAULTS};
 use candid::{CandidType, Principal};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use serde::{Deserialize, Serialize};
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Event {
     #[serde(rename = "open_vault")]
-    OpenVault { vault: Vault, block_index: u64 },
+    OpenVault {
+        vault: Vault,
+        block_index: u64,
+        #[serde(default)]
+        from_subaccount: Option<Subaccount>,
+    },
 
     #[serde(rename = "close_vault")]
     CloseVault {
         vault_id: u64,
         block_index: Option<u64>,
+        /// Account receiving the margin, the owner if not set.
+        #[serde(default)]
+        to: Option<Account>,
     },
 
     #[serde(rename = "margin_transfer")]
@@ -25,6 +38,22 @@ pub enum Event {
         vault_id: u64,
         mode: Mode,
         btc_rate: UsdBtc,
+        /// Account rewarded for triggering the liquidation.
+        #[serde(default)]
+        keeper: Option<Account>,
+        /// Margin seized on top of the value of the debt, the rest stays in the vault.
+        #[serde(default)]
+        penalty: Option<CKBTC>,
+        /// Margin taken out of the vault, the keeper reward included. Not replayed, the
+        /// split is recomputed from the fields above.
+        #[serde(default)]
+        seized_margin: Option<CKBTC>,
+        /// Part of the seized margin paid to the keeper.
+        #[serde(default)]
+        keeper_reward: Option<CKBTC>,
+        /// Margin left in the vault for its owner.
+        #[serde(default)]
+        remaining_margin: Option<CKBTC>,
     },
 
     #[serde(rename = "redemption_on_vaults")]
@@ -34,6 +63,13 @@ pub enum Event {
         tal_amount: TAL,
         fee_amount: TAL,
         tal_block_index: u64,
+        #[serde(default)]
+        collateral_type: CollateralType,
+        #[serde(default)]
+        from_subaccount: Option<Subaccount>,
+        /// Account receiving the redeemed collateral, the owner if not set.
+        #[serde(default)]
+        to: Option<Account>,
     },
 
     #[serde(rename = "redemption_transfered")]
@@ -42,6 +78,19 @@ pub enum Event {
         ckbtc_block_index: u64,
     },
 
+    /// Stablecoin taken from `to` and minted back, `block_index` is None when the mint
+    /// failed and the refund was queued.
+    #[serde(rename = "refund_stablecoin")]
+    RefundStablecoin {
+        to: Account,
+        amount: TAL,
+        stablecoin: StablecoinType,
+        block_index: Option<u64>,
+    },
+
+    #[serde(rename = "stablecoin_transfered")]
+    StablecoinTransfered { transfer_id: u64, block_index: u64 },
+
     #[serde(rename = "redistribute_vault")]
     RedistributeVault { vault_id: u64 },
 
@@ -51,6 +100,8 @@ pub enum Event {
         borrowed_amount: TAL,
         fee_amount: TAL,
         block_index: u64,
+        #[serde(default)]
+        to: Option<Account>,
     },
 
     #[serde(rename = "repay_to_vault")]
@@ -58,6 +109,8 @@ pub enum Event {
         vault_id: u64,
         repayed_amount: TAL,
         block_index: u64,
+        #[serde(default)]
+        from_subaccount: Option<Subaccount>,
     },
 
     #[serde(rename = "add_margin_to_vault")]
@@ -65,6 +118,45 @@ pub enum Event {
         vault_id: u64,
         margin_added: CKBTC,
         block_index: u64,
+        #[serde(default)]
+        from_subaccount: Option<Subaccount>,
+    },
+
+    #[serde(rename = "merge_vaults")]
+    MergeVaults {
+        target_vault_id: u64,
+        source_vault_id: u64,
+    },
+
+    #[serde(rename = "split_vault")]
+    SplitVault {
+        vault_id: u64,
+        new_vault_id: u64,
+        margin: CKBTC,
+        debt: TAL,
+    },
+
+    #[serde(rename = "transfer_vault")]
+    TransferVault {
+        vault_id: u64,
+        previous_owner: Principal,
+        new_owner: Principal,
+    },
+
+    #[serde(rename = "set_vault_operator")]
+    SetVaultOperator {
+        vault_id: u64,
+        operator: Principal,
+        /// Permissions granted to the operator, revoked if not set.
+        permissions: Option<OperatorPermissions>,
+    },
+
+    #[serde(rename = "withdraw_margin_from_vault")]
+    WithdrawMarginFromVault {
+        vault_id: u64,
+        margin_withdrawn: CKBTC,
+        /// Account receiving the margin, the owner if not set.
+        to: Option<Account>,
     },
 
     #[serde(rename = "provide_liquidity")]
@@ -72,6 +164,10 @@ pub enum Event {
         amount: TAL,
         block_index: u64,
         caller: Principal,
+        #[serde(default)]
+        stablecoin: StablecoinType,
+        #[serde(default)]
+        from_subaccount: Option<Subaccount>,
     },
 
     #[serde(rename = "withdraw_liquidity")]
@@ -79,15 +175,93 @@ pub enum Event {
         amount: TAL,
         block_index: u64,
         caller: Principal,
+        #[serde(default)]
+        stablecoin: StablecoinType,
+        #[serde(default)]
+        to: Option<Account>,
+    },
+
+    #[serde(rename = "request_withdrawal")]
+    RequestWithdrawal {
+        caller: Principal,
+        amount: TAL,
+        stablecoin: StablecoinType,
+        timestamp: u64,
     },
 
     #[serde(rename = "claim_liquidity_returns")]
     ClaimLiquidityReturns {
         amount: CKBTC,
+        /// None when the transfer failed and was queued.
+        block_index: Option<u64>,
+        caller: Principal,
+        #[serde(default)]
+        collateral_type: CollateralType,
+        #[serde(default)]
+        to: Option<Account>,
+    },
+
+    #[serde(rename = "claim_fee_returns")]
+    ClaimFeeReturns {
+        amount: TAL,
         block_index: u64,
         caller: Principal,
+        stablecoin: StablecoinType,
+        #[serde(default)]
+        to: Option<Account>,
+    },
+
+    #[serde(rename = "set_compounding_preference")]
+    SetCompoundingPreference {
+        caller: Principal,
+        preference: CompoundingPreference,
+    },
+
+    #[serde(rename = "accrue_stability_fee")]
+    AccrueStabilityFee { timestamp: u64 },
+
+    /// BTC/USD price agreed on by the price sources.
+    #[serde(rename = "btc_price_update")]
+    BtcPriceUpdate {
+        rate: UsdBtc,
+        timestamp: u64,
+        sources: Vec<PriceSource>,
+    },
+
+    #[serde(rename = "start_auction")]
+    StartAuction {
+        vault_id: u64,
+        oracle_price: UsdBtc,
+        start_time: u64,
     },
 
+    #[serde(rename = "reset_auction")]
+    ResetAuction {
+        vault_id: u64,
+        oracle_price: UsdBtc,
+        start_time: u64,
+    },
+
+    #[serde(rename = "close_auction")]
+    CloseAuction { vault_id: u64 },
+
+    #[serde(rename = "auction_bid")]
+    AuctionBid {
+        vault_id: u64,
+        bidder: Principal,
+        stablecoin_amount: TAL,
+        collateral_amount: CKBTC,
+        to: Option<Account>,
+        block_index: u64,
+    },
+
+    /// Any queued collateral transfer, the tag predates the keeper rewards.
+    #[serde(rename = "auction_transfered")]
+    CollateralTransfered { transfer_id: u64, block_index: u64 },
+
+    #[serde(rename = "add_collateral_type")]
+    AddCollateralType(AddCollateralTypeArg),
+
     #[serde(rename = "init")]
     Init(InitArg),
 
@@ -105,13 +279,41 @@ impl Event {
             Event::LiquidateVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::RedemptionOnVaults { .. } => true,
             Event::RedemptionTransfered { .. } => false,
+            Event::RefundStablecoin { .. } => false,
+            Event::StablecoinTransfered { .. } => false,
             Event::RedistributeVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::BorrowFromVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::RepayToVault { vault_id, .. } => vault_id == filter_vault_id,
             Event::AddMarginToVault { vault_id, .. } => vault_id == filter_vault_id,
+            Event::WithdrawMarginFromVault { vault_id, .. } => vault_id == filter_vault_id,
+            Event::MergeVaults {
+                target_vault_id,
+                source_vault_id,
+            } => target_vault_id == filter_vault_id || source_vault_id == filter_vault_id,
+            Event::SplitVault {
+                vault_id,
+                new_vault_id,
+                ..
+            } => vault_id == filter_vault_id || new_vault_id == filter_vault_id,
+            Event::TransferVault { vault_id, .. } => vault_id == filter_vault_id,
+            Event::SetVaultOperator { vault_id, .. } => vault_id == filter_vault_id,
             Event::ProvideLiquidity { .. } => false,
             Event::WithdrawLiquidity { .. } => false,
+            Event::RequestWithdrawal { .. } => false,
             Event::ClaimLiquidityReturns { .. } => false,
+            Event::ClaimFeeReturns { .. } => false,
+            Event::SetCompoundingPreference { preference, .. } => matches!(
+                preference,
+                CompoundingPreference::AddToVault { vault_id } if vault_id == filter_vault_id
+            ),
+            Event::AccrueStabilityFee { .. } => false,
+            Event::BtcPriceUpdate { .. } => false,
+            Event::StartAuction { vault_id, .. } => vault_id == filter_vault_id,
+            Event::ResetAuction { vault_id, .. } => vault_id == filter_vault_id,
+            Event::CloseAuction { vault_id } => vault_id == filter_vault_id,
+            Event::AuctionBid { vault_id, .. } => vault_id == filter_vault_id,
+            Event::CollateralTransfered { .. } => false,
+            Event::AddCollateralType(_) => false,
             Event::Init(_) => false,
             Event::Upgrade(_) => false,
         }
@@ -140,30 +342,31 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
     let mut vault_id = 0;
     for event in events {
         match event {
-            Event::OpenVault {
-                vault,
-                block_index: _,
-            } => {
+            Event::OpenVault { vault, .. } => {
                 vault_id += 1;
                 state.open_vault(vault);
             }
             Event::CloseVault {
                 vault_id,
                 block_index: _,
-            } => state.close_vault(vault_id),
+                to,
+            } => state.close_vault(vault_id, to),
             Event::LiquidateVault {
                 vault_id,
                 mode,
                 btc_rate,
-            } => state.liquidate_vault(vault_id, mode, btc_rate),
+                keeper,
+                penalty,
+                ..
+            } => state.liquidate_vault(vault_id, mode, btc_rate, keeper, penalty),
             Event::RedistributeVault { vault_id } => state.redistribute_vault(vault_id),
             Event::BorrowFromVault {
                 vault_id,
                 borrowed_amount,
                 fee_amount,
-                block_index: _,
+                ..
             } => {
-                state.provide_liquidity(fee_amount, state.developer_principal);
+                state.collect_fee(fee_amount, state.vault_id_to_vaults[&vault_id].stablecoin);
                 state.borrow_from_vault(vault_id, borrowed_amount)
             }
             Event::RedemptionOnVaults {
@@ -172,19 +375,51 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
                 tal_amount,
                 fee_amount,
                 tal_block_index,
+                collateral_type,
+                to,
+                ..
             } => {
-                state.provide_liquidity(fee_amount, state.developer_principal);
-                state.redeem_on_vaults(tal_amount, current_btc_rate);
-                let margin: CKBTC = tal_amount / current_btc_rate;
-                state
-                    .pending_redemption_transfer
-                    .insert(tal_block_index, PendingMarginTransfer { owner, margin });
+                state.collect_fee(fee_amount, StablecoinType::Tal);
+                // Recorded amounts could be filled when they were recorded, redemptions
+                // recorded before the bound was introduced used any number of vaults.
+                let redeemed_amount = state.redeem_on_vaults(
+                    tal_amount,
+                    current_btc_rate,
+                    collateral_type,
+                    usize::MAX,
+                );
+                let margin: CKBTC = redeemed_amount / current_btc_rate;
+                state.pending_redemption_transfer.insert(
+                    tal_block_index,
+                    PendingMarginTransfer {
+                        to: to.unwrap_or(Account::from(owner)),
+                        margin,
+                        collateral_type,
+                    },
+                );
             }
             Event::RedemptionTransfered {
                 tal_block_index, ..
             } => {
                 state.pending_redemption_transfer.remove(&tal_block_index);
             }
+            Event::RefundStablecoin {
+                to,
+                amount,
+                stablecoin,
+                block_index,
+            } => {
+                if block_index.is_none() {
+                    state.push_stablecoin_transfer(PendingStablecoinTransfer {
+                        to,
+                        amount,
+                        stablecoin,
+                    });
+                }
+            }
+            Event::StablecoinTransfered { transfer_id, .. } => {
+                state.pending_stablecoin_transfers.remove(&transfer_id);
+            }
             Event::AddMarginToVault {
                 vault_id,
                 margin_added,
@@ -197,15 +432,112 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
             } => {
                 state.repay_to_vault(vault_id, repayed_amount);
             }
-            Event::ProvideLiquidity { amount, caller, .. } => {
-                state.provide_liquidity(amount, caller);
+            Event::MergeVaults {
+                target_vault_id,
+                source_vault_id,
+            } => state.merge_vaults(target_vault_id, source_vault_id),
+            Event::SplitVault {
+                vault_id: split_vault_id,
+                new_vault_id,
+                margin,
+                debt,
+            } => {
+                vault_id += 1;
+                state.split_vault(split_vault_id, new_vault_id, margin, debt);
+            }
+            Event::TransferVault {
+                vault_id,
+                new_owner,
+                ..
+            } => state.transfer_vault(vault_id, new_owner),
+            Event::SetVaultOperator {
+                vault_id,
+                operator,
+                permissions,
+            } => state.set_vault_operator(vault_id, operator, permissions),
+            Event::WithdrawMarginFromVault {
+                vault_id,
+                margin_withdrawn,
+                to,
+            } => {
+                state.withdraw_margin_from_vault(vault_id, margin_withdrawn, to);
+            }
+            Event::ProvideLiquidity {
+                amount,
+                caller,
+                stablecoin,
+                ..
+            } => {
+                state.provide_liquidity(amount, caller, stablecoin);
             }
-            Event::WithdrawLiquidity { amount, caller, .. } => {
-                state.withdraw_liquidity(amount, caller);
+            Event::WithdrawLiquidity {
+                amount,
+                caller,
+                stablecoin,
+                ..
+            } => {
+                state.withdraw_liquidity(amount, caller, stablecoin);
+            }
+            Event::RequestWithdrawal {
+                caller,
+                amount,
+                stablecoin,
+                timestamp,
+            } => state.request_withdrawal(caller, amount, stablecoin, timestamp),
+            Event::ClaimLiquidityReturns {
+                amount,
+                block_index,
+                caller,
+                collateral_type,
+                to,
+            } => {
+                state.claim_liquidity_returns(amount, caller, collateral_type);
+                if block_index.is_none() {
+                    state.push_collateral_transfer(PendingMarginTransfer {
+                        to: to.unwrap_or(Account::from(caller)),
+                        margin: amount,
+                        collateral_type,
+                    });
+                }
             }
-            Event::ClaimLiquidityReturns { amount, caller, .. } => {
-                state.claim_liquidity_returns(amount, caller);
+            Event::ClaimFeeReturns {
+                amount,
+                caller,
+                stablecoin,
+                ..
+            } => {
+                state.claim_fee_returns(amount, caller, stablecoin);
             }
+            Event::SetCompoundingPreference { caller, preference } => {
+                state.set_compounding_preference(caller, preference)
+            }
+            Event::AccrueStabilityFee { timestamp } => state.accrue_stability_fee(timestamp),
+            Event::BtcPriceUpdate {
+                rate, timestamp, ..
+            } => state.set_btc_rate(rate, timestamp),
+            Event::StartAuction {
+                vault_id,
+                oracle_price,
+                start_time,
+            } => state.start_auction(vault_id, oracle_price, start_time),
+            Event::ResetAuction {
+                vault_id,
+                oracle_price,
+                start_time,
+            } => state.reset_auction(vault_id, oracle_price, start_time),
+            Event::CloseAuction { vault_id } => state.close_auction(vault_id),
+            Event::AuctionBid {
+                vault_id,
+                bidder,
+                stablecoin_amount,
+                collateral_amount,
+                to,
+                ..
+            } => state.auction_bid(vault_id, bidder, stablecoin_amount, collateral_amount, to),
+            Event::CollateralTransfered { transfer_id, .. } => {
+                state.pending_collateral_transfers.remove(&transfer_id);
+            }
+            Event::AddCollateralType(arg) => state.add_collateral_type(arg),
             Event::Init(_) => panic!("should have only one init event"),
             Event::Upgrade(upgrade_args) => {
                 state.upgrade(upgrade_args);
@@ -219,13 +551,32 @@ pub fn replay(mut events: impl Iterator<Item = Event>) -> Result<State, ReplayLo
     Ok(state)
 }
 
-pub fn record_liquidate_vault(state: &mut State, vault_id: u64, mode: Mode, btc_rate: UsdBtc) {
+pub fn record_liquidate_vault(
+    state: &mut State,
+    vault_id: u64,
+    mode: Mode,
+    btc_rate: UsdBtc,
+    keeper: Option<Account>,
+) {
+    let penalty = state.compute_liquidation_penalty(vault_id, mode, btc_rate);
+    let split = state.compute_liquidation_split(
+        &state.get_vault(vault_id).expect("bug: vault not found"),
+        mode,
+        btc_rate,
+        keeper.is_some(),
+        penalty,
+    );
     record_event(&Event::LiquidateVault {
         vault_id,
         mode,
         btc_rate,
+        keeper,
+        penalty,
+        seized_margin: Some(split.seized_margin),
+        keeper_reward: Some(split.keeper_reward),
+        remaining_margin: Some(split.remaining_margin),
     });
-    state.liquidate_vault(vault_id, mode, btc_rate);
+    state.liquidate_vault(vault_id, mode, btc_rate, keeper, penalty);
 }
 
 pub fn record_redistribute_vault(state: &mut State, vault_id: u64) {
@@ -237,58 +588,137 @@ pub fn record_provide_liquidity(
     state: &mut State,
     amount: TAL,
     caller: Principal,
+    stablecoin: StablecoinType,
+    from_subaccount: Option<Subaccount>,
     block_index: u64,
 ) {
     record_event(&Event::ProvideLiquidity {
         amount,
         block_index,
         caller,
+        stablecoin,
+        from_subaccount,
     });
-    state.provide_liquidity(amount, caller);
+    state.provide_liquidity(amount, caller, stablecoin);
 }
 
 pub fn record_withdraw_liquidity(
     state: &mut State,
     amount: TAL,
     caller: Principal,
+    stablecoin: StablecoinType,
+    to: Option<Account>,
     block_index: u64,
 ) {
     record_event(&Event::WithdrawLiquidity {
         amount,
         block_index,
         caller,
+        stablecoin,
+        to,
     });
-    state.withdraw_liquidity(amount, caller);
+    state.withdraw_liquidity(amount, caller, stablecoin);
+}
+
+pub fn record_request_withdrawal(
+    state: &mut State,
+    caller: Principal,
+    amount: TAL,
+    stablecoin: StablecoinType,
+    timestamp: u64,
+) {
+    record_event(&Event::RequestWithdrawal {
+        caller,
+        amount,
+        stablecoin,
+        timestamp,
+    });
+    state.request_withdrawal(caller, amount, stablecoin, timestamp);
 }
 
 pub fn record_claim_liquidity_returns(
     state: &mut State,
     amount: CKBTC,
     caller: Principal,
-    block_index: u64,
+    collateral_type: CollateralType,
+    to: Option<Account>,
+    block_index: Option<u64>,
 ) {
     record_event(&Event::ClaimLiquidityReturns {
         amount,
         block_index,
         caller,
+        collateral_type,
+        to,
     });
-    state.claim_liquidity_returns(amount, caller);
+    state.claim_liquidity_returns(amount, caller, collateral_type);
+    if block_index.is_none() {
+        state.push_collateral_transfer(PendingMarginTransfer {
+            to: to.unwrap_or(Account::from(caller)),
+            margin: amount,
+            collateral_type,
+        });
+    }
 }
 
-pub fn record_open_vault(state: &mut State, vault: Vault, block_index: u64) {
+pub fn record_set_compounding_preference(
+    state: &mut State,
+    caller: Principal,
+    preference: CompoundingPreference,
+) {
+    record_event(&Event::SetCompoundingPreference { caller, preference });
+    state.set_compounding_preference(caller, preference);
+}
+
+pub fn record_claim_fee_returns(
+    state: &mut State,
+    amount: TAL,
+    caller: Principal,
+    stablecoin: StablecoinType,
+    to: Option<Account>,
+    block_index: u64,
+) {
+    record_event(&Event::ClaimFeeReturns {
+        amount,
+        block_index,
+        caller,
+        stablecoin,
+        to,
+    });
+    state.claim_fee_returns(amount, caller, stablecoin);
+}
+
+pub fn record_add_collateral_type(state: &mut State, arg: AddCollateralTypeArg) {
+    record_event(&Event::AddCollateralType(arg.clone()));
+    state.add_collateral_type(arg);
+}
+
+pub fn record_open_vault(
+    state: &mut State,
+    vault: Vault,
+    from_subaccount: Option<Subaccount>,
+    block_index: u64,
+) {
     record_event(&Event::OpenVault {
         vault: vault.clone(),
         block_index,
+        from_subaccount,
     });
     state.open_vault(vault);
 }
 
-pub fn record_close_vault(state: &mut State, vault_id: u64, block_index: Option<u64>) {
+pub fn record_close_vault(
+    state: &mut State,
+    vault_id: u64,
+    block_index: Option<u64>,
+    to: Option<Account>,
+) {
     record_event(&Event::CloseVault {
         vault_id,
         block_index,
+        to,
     });
-    state.close_vault(vault_id);
+    state.close_vault(vault_id, to);
 }
 
 pub fn record_margin_transfer(state: &mut State, vault_id: u64, block_index: u64) {
@@ -304,6 +734,7 @@ pub fn record_borrow_from_vault(
     vault_id: u64,
     borrowed_amount: TAL,
     fee_amount: TAL,
+    to: Option<Account>,
     block_index: u64,
 ) {
     record_event(&Event::BorrowFromVault {
@@ -311,21 +742,24 @@ pub fn record_borrow_from_vault(
         block_index,
         fee_amount,
         borrowed_amount,
+        to,
     });
     state.borrow_from_vault(vault_id, borrowed_amount);
-    state.provide_liquidity(fee_amount, state.developer_principal);
+    state.collect_fee(fee_amount, state.vault_id_to_vaults[&vault_id].stablecoin);
 }
 
 pub fn record_repayed_to_vault(
     state: &mut State,
     vault_id: u64,
     repayed_amount: TAL,
+    from_subaccount: Option<Subaccount>,
     block_index: u64,
 ) {
     record_event(&Event::RepayToVault {
         vault_id,
         block_index,
         repayed_amount,
+        from_subaccount,
     });
     state.repay_to_vault(vault_id, repayed_amount);
 }
@@ -334,22 +768,170 @@ pub fn record_add_margin_to_vault(
     state: &mut State,
     vault_id: u64,
     margin_added: CKBTC,
+    from_subaccount: Option<Subaccount>,
     block_index: u64,
 ) {
     record_event(&Event::AddMarginToVault {
         vault_id,
         margin_added,
         block_index,
+        from_subaccount,
     });
     state.add_margin_to_vault(vault_id, margin_added);
 }
 
+pub fn record_accrue_stability_fee(state: &mut State, timestamp: u64) {
+    record_event(&Event::AccrueStabilityFee { timestamp });
+    state.accrue_stability_fee(timestamp);
+}
+
+pub fn record_btc_price_update(
+    state: &mut State,
+    rate: UsdBtc,
+    timestamp: u64,
+    sources: Vec<PriceSource>,
+) {
+    record_event(&Event::BtcPriceUpdate {
+        rate,
+        timestamp,
+        sources,
+    });
+    record_price(timestamp, rate);
+    state.set_btc_rate(rate, timestamp);
+}
+
+pub fn record_merge_vaults(state: &mut State, target_vault_id: u64, source_vault_id: u64) {
+    record_event(&Event::MergeVaults {
+        target_vault_id,
+        source_vault_id,
+    });
+    state.merge_vaults(target_vault_id, source_vault_id);
+}
+
+pub fn record_split_vault(
+    state: &mut State,
+    vault_id: u64,
+    new_vault_id: u64,
+    margin: CKBTC,
+    debt: TAL,
+) {
+    record_event(&Event::SplitVault {
+        vault_id,
+        new_vault_id,
+        margin,
+        debt,
+    });
+    state.split_vault(vault_id, new_vault_id, margin, debt);
+}
+
+pub fn record_start_auction(
+    state: &mut State,
+    vault_id: u64,
+    oracle_price: UsdBtc,
+    start_time: u64,
+) {
+    record_event(&Event::StartAuction {
+        vault_id,
+        oracle_price,
+        start_time,
+    });
+    state.start_auction(vault_id, oracle_price, start_time);
+}
+
+pub fn record_reset_auction(
+    state: &mut State,
+    vault_id: u64,
+    oracle_price: UsdBtc,
+    start_time: u64,
+) {
+    record_event(&Event::ResetAuction {
+        vault_id,
+        oracle_price,
+        start_time,
+    });
+    state.reset_auction(vault_id, oracle_price, start_time);
+}
+
+pub fn record_close_auction(state: &mut State, vault_id: u64) {
+    record_event(&Event::CloseAuction { vault_id });
+    state.close_auction(vault_id);
+}
+
+pub fn record_auction_bid(
+    state: &mut State,
+    vault_id: u64,
+    bidder: Principal,
+    stablecoin_amount: TAL,
+    collateral_amount: CKBTC,
+    to: Option<Account>,
+    block_index: u64,
+) {
+    record_event(&Event::AuctionBid {
+        vault_id,
+        bidder,
+        stablecoin_amount,
+        collateral_amount,
+        to,
+        block_index,
+    });
+    state.auction_bid(vault_id, bidder, stablecoin_amount, collateral_amount, to);
+}
+
+pub fn record_collateral_transfered(state: &mut State, transfer_id: u64, block_index: u64) {
+    record_event(&Event::CollateralTransfered {
+        transfer_id,
+        block_index,
+    });
+    state.pending_collateral_transfers.remove(&transfer_id);
+}
+
+pub fn record_transfer_vault(state: &mut State, vault_id: u64, new_owner: Principal) {
+    record_event(&Event::TransferVault {
+        vault_id,
+        previous_owner: state.vault_id_to_vaults[&vault_id].owner,
+        new_owner,
+    });
+    state.transfer_vault(vault_id, new_owner);
+}
+
+pub fn record_set_vault_operator(
+    state: &mut State,
+    vault_id: u64,
+    operator: Principal,
+    permissions: Option<OperatorPermissions>,
+) {
+    record_event(&Event::SetVaultOperator {
+        vault_id,
+        operator,
+        permissions,
+    });
+    state.set_vault_operator(vault_id, operator, permissions);
+}
+
+pub fn record_withdraw_margin_from_vault(
+    state: &mut State,
+    vault_id: u64,
+    margin_withdrawn: CKBTC,
+    to: Option<Account>,
+) {
+    record_event(&Event::WithdrawMarginFromVault {
+        vault_id,
+        margin_withdrawn,
+        to,
+    });
+    state.withdraw_margin_from_vault(vault_id, margin_withdrawn, to);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn record_redemption_on_vaults(
     state: &mut State,
     owner: Principal,
     tal_amount: TAL,
     fee_amount: TAL,
     current_btc_rate: UsdBtc,
+    collateral_type: CollateralType,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
     tal_block_index: u64,
 ) {
     record_event(&Event::RedemptionOnVaults {
@@ -358,13 +940,26 @@ pub fn record_redemption_on_vaults(
         tal_amount,
         fee_amount,
         tal_block_index,
+        collateral_type,
+        from_subaccount,
+        to,
     });
-    state.provide_liquidity(fee_amount, state.developer_principal);
-    state.redeem_on_vaults(tal_amount, current_btc_rate);
-    let margin: CKBTC = tal_amount / current_btc_rate;
-    state
-        .pending_redemption_transfer
-        .insert(tal_block_index, PendingMarginTransfer { owner, margin });
+    state.collect_fee(fee_amount, StablecoinType::Tal);
+    let redeemed_amount = state.redeem_on_vaults(
+        tal_amount,
+        current_btc_rate,
+        collateral_type,
+        MAX_REDEEMED_VAULTS,
+    );
+    let margin: CKBTC = redeemed_amount / current_btc_rate;
+    state.pending_redemption_transfer.insert(
+        tal_block_index,
+        PendingMarginTransfer {
+            to: to.unwrap_or(Account::from(owner)),
+            margin,
+            collateral_type,
+        },
+    );
 }
 
 pub fn record_redemption_transfered(
@@ -378,3 +973,33 @@ pub fn record_redemption_transfered(
     });
     state.pending_redemption_transfer.remove(&tal_block_index);
 }
+
+pub fn record_refund_stablecoin(
+    state: &mut State,
+    to: Account,
+    amount: TAL,
+    stablecoin: StablecoinType,
+    block_index: Option<u64>,
+) {
+    record_event(&Event::RefundStablecoin {
+        to,
+        amount,
+        stablecoin,
+        block_index,
+    });
+    if block_index.is_none() {
+        state.push_stablecoin_transfer(PendingStablecoinTransfer {
+            to,
+            amount,
+            stablecoin,
+        });
+    }
+}
+
+pub fn record_stablecoin_transfered(state: &mut State, transfer_id: u64, block_index: u64) {
+    record_event(&Event::StablecoinTransfered {
+        transfer_id,
+        block_index,
+    });
+    state.pending_stablecoin_transfers.remove(&transfer_id);
+}
@@ -45,6 +45,29 @@ impl Drop for GuardPrincipal {
     }
 }
 
+/// Guards an auction from receiving concurrent bids.
+#[must_use]
+pub struct GuardAuction {
+    vault_id: u64,
+}
+
+impl GuardAuction {
+    pub fn new(vault_id: u64) -> Result<Self, GuardError> {
+        mutate_state(|s| {
+            if !s.auction_guards.insert(vault_id) {
+                return Err(GuardError::AlreadyProcessing);
+            }
+            Ok(Self { vault_id })
+        })
+    }
+}
+
+impl Drop for GuardAuction {
+    fn drop(&mut self) {
+        mutate_state(|s| s.auction_guards.remove(&self.vault_id));
+    }
+}
+
 #[must_use]
 pub struct TimerLogicGuard(());
 
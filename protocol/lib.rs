@@ -1,17 +1,23 @@
-use crate::event::{record_liquidate_vault, record_redistribute_vault};
+use crate::event::{record_liquidate_vault, record_redistribute_vault, record_start_auction};
 use crate::guard::GuardError;
+use crate::liquidity_pool::CompoundingPreference;
 use crate::logs::{DEBUG, INFO};
 use crate::numeric::{Ratio, UsdBtc, CKBTC, TAL};
-use crate::state::{mutate_state, read_state, Mode};
+use crate::oracle::{PriceKind, PriceStatus};
+use crate::stablecoin::{AddStablecoinArg, StablecoinType};
+use crate::state::{mutate_state, read_state, Mode, State};
 use crate::vault::Vault;
 use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
 use icrc_ledger_types::icrc1::transfer::TransferError;
 use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
 use serde::Serialize;
 
+pub mod auction;
+pub mod collateral;
 pub mod dashboard;
 pub mod event;
 pub mod guard;
@@ -19,6 +25,9 @@ pub mod liquidity_pool;
 pub mod logs;
 pub mod management;
 pub mod numeric;
+pub mod oracle;
+pub mod simulation;
+pub mod stablecoin;
 pub mod state;
 pub mod storage;
 pub mod vault;
@@ -36,6 +45,13 @@ pub const MIN_TAL_AMOUNT: TAL = TAL::new(1_000_000_000);
 
 pub const RECOVERY_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.5));
 pub const MINIMUM_COLLATERAL_RATIO: Ratio = Ratio::new(dec!(1.1));
+pub const DEFAULT_LIQUIDATION_PENALTY: Ratio = Ratio::new(dec!(0.1));
+/// Seconds a withdrawal request can be used once its cooldown ended.
+pub const DEFAULT_WITHDRAWAL_WINDOW_SECS: u64 = 24 * 60 * 60;
+/// Maximum number of vaults a single redemption takes debt from.
+pub const MAX_REDEEMED_VAULTS: usize = 50;
+/// Maximum number of prices returned by `get_price_history`.
+pub const MAX_PRICE_HISTORY_LEN: usize = 2_000;
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ProtocolArg {
@@ -50,11 +66,83 @@ pub struct InitArg {
     pub ckbtc_ledger_principal: Principal,
     pub fee_e8s: u64,
     pub developer_principal: Principal,
+    /// Stablecoins minted on top of TAL.
+    pub stablecoins: Option<Vec<AddStablecoinArg>>,
+    /// Maximum USD value of the debt of all the vaults.
+    #[serde(default)]
+    pub global_debt_ceiling: Option<u64>,
+    /// Maximum USD value of the debt of the vaults of a single principal.
+    #[serde(default)]
+    pub principal_debt_ceiling: Option<u64>,
 }
 
-#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(CandidType, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UpgradeArg {
     pub mode: Option<Mode>,
+    /// Stablecoins to register, or to update if already known.
+    pub stablecoins: Option<Vec<AddStablecoinArg>>,
+    /// Yearly stability fee accrued on borrowed amounts: e8s.
+    #[serde(default)]
+    pub stability_fee_rate_e8s: Option<u64>,
+    #[serde(default)]
+    pub global_debt_ceiling: Option<u64>,
+    #[serde(default)]
+    pub principal_debt_ceiling: Option<u64>,
+    /// Removes the global debt ceiling, before `global_debt_ceiling` is applied.
+    #[serde(default)]
+    pub clear_global_debt_ceiling: Option<bool>,
+    /// Removes the principal debt ceiling, before `principal_debt_ceiling` is applied.
+    #[serde(default)]
+    pub clear_principal_debt_ceiling: Option<bool>,
+    /// Auction the vaults the liquidity pool cannot cover instead of redistributing them.
+    #[serde(default)]
+    pub enable_auctions: Option<bool>,
+    /// Margin seized on top of the value of the debt of a liquidated vault: e8s.
+    #[serde(default)]
+    pub liquidation_penalty_e8s: Option<u64>,
+    /// Share of the liquidated margin paid to the caller of `liquidate`: e8s.
+    #[serde(default)]
+    pub liquidation_reward_e8s: Option<u64>,
+    /// Share of the borrowing and redemption fees paid to the liquidity providers: e8s.
+    #[serde(default)]
+    pub lp_fee_share_e8s: Option<u64>,
+    /// Seconds between a withdrawal request and the withdrawal of liquidity.
+    #[serde(default)]
+    pub withdrawal_cooldown_secs: Option<u64>,
+    /// Seconds after the cooldown during which a withdrawal request can be used.
+    #[serde(default)]
+    pub withdrawal_window_secs: Option<u64>,
+    /// Exchange rate canisters queried for the BTC price next to the main one.
+    #[serde(default)]
+    pub oracle_xrc_principals: Option<Vec<Principal>>,
+    /// Canister implementing the XRC interface, queried when no other source answers.
+    #[serde(default)]
+    pub fallback_oracle_principal: Option<Principal>,
+    /// Principals allowed to push BTC prices.
+    #[serde(default)]
+    pub price_pushers: Option<Vec<Principal>>,
+    /// Maximum distance between a price source and the median of the sources: e8s.
+    #[serde(default)]
+    pub max_price_deviation_e8s: Option<u64>,
+    /// Minimum number of exchange rates an XRC answer must be computed from.
+    #[serde(default)]
+    pub min_xrc_received_rates: Option<u64>,
+    /// Maximum standard deviation of an XRC answer relative to its rate: e8s.
+    #[serde(default)]
+    pub max_xrc_standard_deviation_e8s: Option<u64>,
+    /// Seconds the BTC TWAP is averaged over, zero to use the spot price.
+    #[serde(default)]
+    pub twap_window_secs: Option<u64>,
+    /// Maximum BTC price change between two fetches before liquidations are frozen,
+    /// zero for no limit: e8s.
+    #[serde(default)]
+    pub max_btc_price_change_e8s: Option<u64>,
+    /// BTC price used to check borrows and margin withdrawals.
+    #[serde(default)]
+    pub borrow_price_kind: Option<PriceKind>,
+    /// BTC price used to find and liquidate unhealthy vaults.
+    #[serde(default)]
+    pub liquidation_price_kind: Option<PriceKind>,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -65,12 +153,33 @@ pub struct ProtocolStatus {
     pub total_tal_borrowed: u64,
     pub total_collateral_ratio: f64,
     pub mode: Mode,
+    pub total_debt_value: u64,
+    pub global_debt_ceiling: Option<u64>,
+    pub principal_debt_ceiling: Option<u64>,
+    /// BTC price averaged over the TWAP window.
+    pub btc_twap: f64,
+    /// Whether the last BTC price moved too much for liquidations to go on.
+    pub liquidations_frozen: bool,
+    /// Whether the BTC price can be used, and why not.
+    pub btc_price_status: PriceStatus,
+    /// Debt left uncovered by the auctions, per stablecoin.
+    pub bad_debt: Vec<(StablecoinType, u64)>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct PricePoint {
+    pub timestamp: u64,
+    pub btc_rate: f64,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct Fees {
     pub borrowing_fee: f64,
     pub redemption_fee: f64,
+    pub stability_fee_rate: f64,
+    pub liquidation_reward: f64,
+    pub liquidation_penalty: f64,
+    pub lp_fee_share: f64,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -79,6 +188,17 @@ pub struct SuccessWithFee {
     pub fee_amount_paid: u64,
 }
 
+#[derive(CandidType, Deserialize, Debug)]
+pub struct RedemptionSuccess {
+    pub block_index: u64,
+    pub fee_amount_paid: u64,
+    /// Amount of TAL taken from the caller, lower than asked when the redemption was
+    /// only partially filled.
+    pub tal_amount_redeemed: u64,
+    /// ckBTC sent to the caller once the ledger fee is paid.
+    pub ckbtc_amount: u64,
+}
+
 #[derive(candid::CandidType, Deserialize)]
 pub struct GetEventsArg {
     pub start: u64,
@@ -92,6 +212,14 @@ pub struct LiquidityStatus {
     pub liquidity_pool_share: f64,
     pub available_liquidity_reward: u64,
     pub total_available_returns: u64,
+    /// Share of the borrowing and redemption fees claimable in the stablecoin of the pool.
+    pub available_fee_reward: u64,
+    pub total_fee_returns: u64,
+    pub compounding_preference: CompoundingPreference,
+    /// Amount requested for withdrawal, still exposed to liquidations.
+    pub pending_withdrawal: Option<u64>,
+    pub withdrawal_unlock_time: Option<u64>,
+    pub withdrawal_expiry_time: Option<u64>,
 }
 
 #[derive(CandidType, Debug, Clone, Deserialize)]
@@ -102,8 +230,19 @@ pub enum ProtocolError {
     AlreadyProcessing,
     AnonymousCallerNotAllowed,
     CallerNotOwner,
-    AmountTooLow { minimum_amount: u64 },
+    CallerNotController,
+    AmountTooLow {
+        minimum_amount: u64,
+    },
     GenericError(String),
+    RedemptionFeeTooHigh {
+        fee_percentage: f64,
+        max_fee_percentage: f64,
+    },
+    RedeemedAmountTooLow {
+        ckbtc_amount: u64,
+        min_ckbtc_out: u64,
+    },
 }
 
 impl From<GuardError> for ProtocolError {
@@ -117,52 +256,117 @@ impl From<GuardError> for ProtocolError {
     }
 }
 
+/// What happens to a vault that fell below the minimum collateral ratio.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum LiquidationAction {
+    Liquidate,
+    StartAuction,
+    Redistribute,
+    Retry,
+    SwitchToReadOnly,
+}
+
+/// Splits the vaults with known prices into the unhealthy ones, along with their
+/// collateral rate, and the healthy ones.
+pub(crate) fn partition_vaults(s: &State) -> (Vec<(Vault, UsdBtc)>, Vec<Vault>) {
+    let mut unhealthy_vaults: Vec<(Vault, UsdBtc)> = vec![];
+    let mut healthy_vault: Vec<Vault> = vec![];
+    for vault in s.vaults() {
+        let collateral_rate =
+            match s.get_liquidation_rate_in(vault.collateral_type, vault.stablecoin) {
+                Some(rate) => rate,
+                // Vaults cannot be assessed until their collateral and peg prices are known.
+                None => continue,
+            };
+        if compute_collateral_ratio(&vault, collateral_rate)
+            < s.get_minimum_liquidation_collateral_ratio(vault.collateral_type)
+        {
+            unhealthy_vaults.push((vault, collateral_rate));
+        } else {
+            healthy_vault.push(vault)
+        }
+    }
+    (unhealthy_vaults, healthy_vault)
+}
+
+pub(crate) fn liquidation_action(
+    s: &State,
+    vault: &Vault,
+    healthy_vaults: &[Vault],
+) -> LiquidationAction {
+    if vault.borrowed_tal_amount <= s.total_provided_liquidity_amount(vault.stablecoin) {
+        LiquidationAction::Liquidate
+    } else if s.auctions_enabled {
+        LiquidationAction::StartAuction
+    } else if healthy_vaults.iter().any(|healthy| {
+        healthy.collateral_type == vault.collateral_type && healthy.stablecoin == vault.stablecoin
+    }) {
+        LiquidationAction::Redistribute
+    } else if s.total_collateral_ratio > Ratio::from(dec!(1.0)) {
+        LiquidationAction::Retry
+    } else {
+        LiquidationAction::SwitchToReadOnly
+    }
+}
+
 pub fn check_vaults() {
-    let last_btc_rate = read_state(|s| s.last_btc_rate.expect("unknown btc rate"));
-    let (unhealthy_vaults, healthy_vault) = read_state(|s| {
-        let mut unhealthy_vaults: Vec<Vault> = vec![];
-        let mut healthy_vault: Vec<Vault> = vec![];
-        for vault in s.vault_id_to_vaults.values() {
-            if compute_collateral_ratio(vault, last_btc_rate)
-                < s.mode.get_minimum_liquidation_collateral_ratio()
-            {
-                unhealthy_vaults.push(vault.clone());
-            } else {
-                healthy_vault.push(vault.clone())
+    if read_state(|s| s.liquidations_frozen) {
+        log!(
+            INFO,
+            "[check_vaults] liquidations are frozen until the btc rate settles"
+        );
+        return;
+    }
+    let (unhealthy_vaults, healthy_vaults) = read_state(partition_vaults);
+    for (vault, collateral_rate) in unhealthy_vaults {
+        match read_state(|s| liquidation_action(s, &vault, &healthy_vaults)) {
+            LiquidationAction::Liquidate => {
+                log!(
+                    INFO,
+                    "[check_vaults] liquidate vault {:?} to liquidity pool with liquidity: {} {}",
+                    vault.clone(),
+                    read_state(|s| s.total_provided_liquidity_amount(vault.stablecoin)),
+                    vault.stablecoin
+                );
+                mutate_state(|s| {
+                    record_liquidate_vault(s, vault.vault_id, s.mode, collateral_rate, None)
+                });
             }
-        }
-        (unhealthy_vaults, healthy_vault)
-    });
-    for vault in unhealthy_vaults {
-        let provided_liquidity = read_state(|s| s.total_provided_liquidity_amount());
-        if vault.borrowed_tal_amount <= provided_liquidity {
-            log!(
-                INFO,
-                "[check_vaults] liquidate vault {:?} to liquidity pool with liquidity: {} TAL",
-                vault.clone(),
-                provided_liquidity
-            );
-            mutate_state(|s| record_liquidate_vault(s, vault.vault_id, s.mode, last_btc_rate));
-        } else if !healthy_vault.is_empty() {
-            log!(
-                INFO,
-                "[check_vaults] redistribute vault {:?} to all the other vaults.",
-                vault.clone()
-            );
-            mutate_state(|s| record_redistribute_vault(s, vault.vault_id));
-        } else if read_state(|s| s.total_collateral_ratio) > Ratio::from(dec!(1.0)) {
-            log!(
+            LiquidationAction::StartAuction => {
+                log!(
+                    INFO,
+                    "[check_vaults] auction vault {:?} at {} {}",
+                    vault.clone(),
+                    collateral_rate,
+                    vault.stablecoin
+                );
+                mutate_state(|s| {
+                    record_start_auction(s, vault.vault_id, collateral_rate, ic_cdk::api::time())
+                });
+            }
+            LiquidationAction::Redistribute => {
+                log!(
+                    INFO,
+                    "[check_vaults] redistribute vault {:?} to all the other vaults.",
+                    vault.clone()
+                );
+                mutate_state(|s| record_redistribute_vault(s, vault.vault_id));
+            }
+            LiquidationAction::Retry => {
+                log!(
                     INFO,
                     "[check_vaults] cannot liquidate vault {:?} not changing mode as protocol is still solvable, will retry later.",
                     vault.clone(),
                 );
-        } else {
-            log!(
-                INFO,
-                "[check_vaults] cannot liquidate vault {:?} switching to read-only.",
-                vault.clone(),
-            );
-            mutate_state(|s| s.mode = Mode::ReadOnly);
+            }
+            LiquidationAction::SwitchToReadOnly => {
+                log!(
+                    INFO,
+                    "[check_vaults] cannot liquidate vault {:?} switching to read-only.",
+                    vault.clone(),
+                );
+                mutate_state(|s| s.mode = Mode::ReadOnly);
+            }
         }
     }
 }
@@ -175,6 +379,37 @@ pub fn compute_collateral_ratio(vault: &Vault, btc_rate: UsdBtc) -> Ratio {
     margin_value / vault.borrowed_tal_amount
 }
 
+/// Mints back stablecoin taken from `to`, the refund is queued for retry when the mint
+/// fails.
+pub(crate) async fn refund_stablecoin(amount: TAL, to: Account, stablecoin: StablecoinType) {
+    if amount == 0 {
+        return;
+    }
+    match crate::management::mint_stablecoin(amount, to, stablecoin).await {
+        Ok(block_index) => {
+            log!(
+                INFO,
+                "[refund_stablecoin] refunded {amount} {stablecoin} to {to} at block {block_index}"
+            );
+            mutate_state(|s| {
+                crate::event::record_refund_stablecoin(s, to, amount, stablecoin, Some(block_index))
+            });
+        }
+        Err(error) => {
+            log!(
+                INFO,
+                "[refund_stablecoin] queuing refund of {amount} {stablecoin} to {to} after error: {error:?}"
+            );
+            mutate_state(|s| {
+                crate::event::record_refund_stablecoin(s, to, amount, stablecoin, None)
+            });
+            ic_cdk_timers::set_timer(std::time::Duration::from_secs(1), || {
+                ic_cdk::spawn(crate::process_pending_transfer())
+            });
+        }
+    }
+}
+
 pub(crate) async fn process_pending_transfer() {
     use crate::state::PendingMarginTransfer;
 
@@ -192,11 +427,12 @@ pub(crate) async fn process_pending_transfer() {
             .map(|(vault_id, margin_transfer)| (*vault_id, *margin_transfer))
             .collect::<Vec<(u64, PendingMarginTransfer)>>()
     });
-    let ckbtc_transfer_fee = read_state(|s| s.ckbtc_ledger_fee);
     for (vault_id, transfer) in pending_transfers {
-        match crate::management::transfer_ckbtc(
-            transfer.margin - ckbtc_transfer_fee,
-            transfer.owner,
+        let transfer_fee = read_state(|s| s.get_collateral_ledger_fee(transfer.collateral_type));
+        match crate::management::transfer_collateral(
+            transfer.margin - transfer_fee,
+            transfer.to,
+            transfer.collateral_type,
         )
         .await
         {
@@ -205,7 +441,7 @@ pub(crate) async fn process_pending_transfer() {
                     INFO,
                     "[transfering_margins] successfully transfered: {} to {}",
                     transfer.margin,
-                    transfer.owner
+                    transfer.to
                 );
                 mutate_state(|s| crate::event::record_margin_transfer(s, vault_id, block_index));
             }
@@ -226,9 +462,12 @@ pub(crate) async fn process_pending_transfer() {
     });
 
     for (tal_block_index, pending_transfer) in pending_transfers {
-        match crate::management::transfer_ckbtc(
-            pending_transfer.margin - ckbtc_transfer_fee,
-            pending_transfer.owner,
+        let transfer_fee =
+            read_state(|s| s.get_collateral_ledger_fee(pending_transfer.collateral_type));
+        match crate::management::transfer_collateral(
+            pending_transfer.margin - transfer_fee,
+            pending_transfer.to,
+            pending_transfer.collateral_type,
         )
         .await
         {
@@ -237,7 +476,7 @@ pub(crate) async fn process_pending_transfer() {
                     INFO,
                     "[transfering_redemptions] successfully transfered: {} to {}",
                     pending_transfer.margin,
-                    pending_transfer.owner
+                    pending_transfer.to
                 );
                 mutate_state(|s| {
                     crate::event::record_redemption_transfered(s, tal_block_index, block_index)
@@ -252,8 +491,85 @@ pub(crate) async fn process_pending_transfer() {
         }
     }
 
+    let pending_transfers = read_state(|s| {
+        s.pending_collateral_transfers
+            .iter()
+            .map(|(transfer_id, margin_transfer)| (*transfer_id, *margin_transfer))
+            .collect::<Vec<(u64, PendingMarginTransfer)>>()
+    });
+
+    for (transfer_id, pending_transfer) in pending_transfers {
+        let transfer_fee =
+            read_state(|s| s.get_collateral_ledger_fee(pending_transfer.collateral_type));
+        match crate::management::transfer_collateral(
+            pending_transfer.margin - transfer_fee,
+            pending_transfer.to,
+            pending_transfer.collateral_type,
+        )
+        .await
+        {
+            Ok(block_index) => {
+                log!(
+                    INFO,
+                    "[transfering_collaterals] successfully transfered: {} to {}",
+                    pending_transfer.margin,
+                    pending_transfer.to
+                );
+                mutate_state(|s| {
+                    crate::event::record_collateral_transfered(s, transfer_id, block_index)
+                });
+            }
+            Err(error) => log!(
+                DEBUG,
+                "[transfering_collaterals] failed to transfer margin: {}, with error: {}",
+                pending_transfer.margin,
+                error
+            ),
+        }
+    }
+
+    let pending_transfers = read_state(|s| {
+        s.pending_stablecoin_transfers
+            .iter()
+            .map(|(transfer_id, transfer)| (*transfer_id, *transfer))
+            .collect::<Vec<(u64, crate::state::PendingStablecoinTransfer)>>()
+    });
+
+    for (transfer_id, pending_transfer) in pending_transfers {
+        match crate::management::mint_stablecoin(
+            pending_transfer.amount,
+            pending_transfer.to,
+            pending_transfer.stablecoin,
+        )
+        .await
+        {
+            Ok(block_index) => {
+                log!(
+                    INFO,
+                    "[transfering_stablecoins] successfully transfered: {} {} to {}",
+                    pending_transfer.amount,
+                    pending_transfer.stablecoin,
+                    pending_transfer.to
+                );
+                mutate_state(|s| {
+                    crate::event::record_stablecoin_transfered(s, transfer_id, block_index)
+                });
+            }
+            Err(error) => log!(
+                DEBUG,
+                "[transfering_stablecoins] failed to transfer: {} {}, with error: {}",
+                pending_transfer.amount,
+                pending_transfer.stablecoin,
+                error
+            ),
+        }
+    }
+
     if read_state(|s| {
-        !s.pending_margin_transfers.is_empty() || !s.pending_redemption_transfer.is_empty()
+        !s.pending_margin_transfers.is_empty()
+            || !s.pending_redemption_transfer.is_empty()
+            || !s.pending_collateral_transfers.is_empty()
+            || !s.pending_stablecoin_transfers.is_empty()
     }) {
         ic_cdk_timers::set_timer(std::time::Duration::from_secs(1), || {
             ic_cdk::spawn(crate::process_pending_transfer())
@@ -1,14 +1,337 @@
+use crate::collateral::CollateralType;
 use crate::event::{
-    record_claim_liquidity_returns, record_provide_liquidity, record_withdraw_liquidity,
+    record_claim_fee_returns, record_claim_liquidity_returns, record_provide_liquidity,
+    record_request_withdrawal, record_set_compounding_preference, record_withdraw_liquidity,
 };
 use crate::guard::GuardPrincipal;
 use crate::logs::INFO;
-use crate::management::{mint_tal, transfer_ckbtc, transfer_tal_from};
+use crate::management::{mint_stablecoin, transfer_collateral, transfer_stablecoin_from};
+use crate::stablecoin::StablecoinType;
 use crate::{mutate_state, read_state, ProtocolError, CKBTC, MIN_LIQUIDITY_AMOUNT, TAL};
+use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::TransferError;
+use rust_decimal::prelude::{FromPrimitive, ToPrimitive};
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
 
-pub async fn provide_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+/// Factor the product is scaled up by when it gets too small to stay precise.
+const SCALE_FACTOR: Decimal = dec!(1_000_000_000);
+/// Amount a withdrawal or a claim can exceed the balance by. Events recorded before
+/// liquidations were accounted by the pool debited each provider separately and round
+/// differently.
+pub const MAX_ROUNDING_DUST: u64 = 1;
+
+/// What happens to the collateral a provider earns from liquidations.
+#[derive(CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CompoundingPreference {
+    /// Returns accumulate until they are claimed.
+    #[default]
+    Claim,
+    /// Returns in the collateral of the vault are added to its margin.
+    AddToVault { vault_id: u64 },
+    /// Returns are sold to redemptions first, the TAL paid is deposited in the TAL pool.
+    ProvideLiquidity,
+}
+
+/// Amount a provider asked to withdraw from a pool, it stays in the pool and keeps
+/// absorbing liquidations until it is withdrawn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WithdrawalRequest {
+    pub amount: TAL,
+    pub requested_at: u64,
+}
+
+/// Deposit of a liquidity provider along with the accumulators of the pool when it
+/// was last updated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Deposit {
+    amount: TAL,
+    product: Decimal,
+    sums: BTreeMap<CollateralType, Decimal>,
+    fee_sum: Decimal,
+    epoch: u64,
+    scale: u64,
+}
+
+/// Returns of a provider realized when its deposit is updated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PoolGains {
+    pub collateral: BTreeMap<CollateralType, CKBTC>,
+    /// Share of the borrowing and redemption fees, in the stablecoin of the pool.
+    pub fees: TAL,
+}
+
+/// Liquidity pool of a stablecoin. Liquidations are accounted with a running product
+/// of the share of each deposit left and running sums of the collateral gained per
+/// unit deposited, so that deposits, withdrawals and liquidations are independent of
+/// the number of liquidity providers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StabilityPool {
+    deposits: BTreeMap<Principal, Deposit>,
+    total_deposits: TAL,
+    product: Decimal,
+    /// Collateral gained per unit deposited, keyed by epoch and scale.
+    sums: BTreeMap<(u64, u64), BTreeMap<CollateralType, Decimal>>,
+    /// Fees earned per unit deposited, keyed by epoch and scale.
+    fee_sums: BTreeMap<(u64, u64), Decimal>,
+    /// Incremented every time a liquidation empties the pool.
+    epoch: u64,
+    /// Incremented every time the product is scaled up.
+    scale: u64,
+}
+
+impl Default for StabilityPool {
+    fn default() -> Self {
+        Self {
+            deposits: BTreeMap::new(),
+            total_deposits: TAL::from(0),
+            product: Decimal::ONE,
+            sums: BTreeMap::new(),
+            fee_sums: BTreeMap::new(),
+            epoch: 0,
+            scale: 0,
+        }
+    }
+}
+
+fn to_decimal(amount: u64) -> Decimal {
+    Decimal::from_u64(amount).expect("failed to construct decimal from u64")
+}
+
+fn to_amount(value: Decimal) -> u64 {
+    value.to_u64().expect("failed to cast decimal as u64")
+}
+
+impl StabilityPool {
+    pub fn total_deposits(&self) -> TAL {
+        self.total_deposits
+    }
+
+    /// Current deposit of every provider, after the liquidations it absorbed.
+    pub fn deposits(&self) -> impl Iterator<Item = (Principal, TAL)> + '_ {
+        self.deposits
+            .iter()
+            .map(|(provider, deposit)| (*provider, self.compounded_deposit(deposit)))
+    }
+
+    pub fn deposit_of(&self, provider: Principal) -> TAL {
+        self.deposits
+            .get(&provider)
+            .map(|deposit| self.compounded_deposit(deposit))
+            .unwrap_or(TAL::from(0))
+    }
+
+    /// Collateral gained by the provider since its deposit was last updated.
+    pub fn collateral_gains_of(&self, provider: Principal) -> BTreeMap<CollateralType, CKBTC> {
+        self.deposits
+            .get(&provider)
+            .map(|deposit| self.collateral_gains(deposit))
+            .unwrap_or_default()
+    }
+
+    /// Fees earned by the provider since its deposit was last updated.
+    pub fn fee_gains_of(&self, provider: Principal) -> TAL {
+        self.deposits
+            .get(&provider)
+            .map(|deposit| self.fee_gains(deposit))
+            .unwrap_or(TAL::from(0))
+    }
+
+    fn gains_of(&self, provider: Principal) -> PoolGains {
+        PoolGains {
+            collateral: self.collateral_gains_of(provider),
+            fees: self.fee_gains_of(provider),
+        }
+    }
+
+    /// Adds to the deposit of the provider, returns the returns earned until then.
+    pub fn deposit(&mut self, provider: Principal, amount: TAL) -> PoolGains {
+        let gains = self.gains_of(provider);
+        let deposit = self.deposit_of(provider) + amount;
+        self.total_deposits += amount;
+        self.update_deposit(provider, deposit);
+        gains
+    }
+
+    /// Removes from the deposit of the provider, returns the returns earned until then.
+    pub fn withdraw(&mut self, provider: Principal, amount: TAL) -> PoolGains {
+        let gains = self.gains_of(provider);
+        let deposit = self.deposit_of(provider);
+        assert!(
+            deposit + TAL::from(MAX_ROUNDING_DUST) >= amount,
+            "cannot withdraw {amount} out of a deposit of {deposit}"
+        );
+        let amount = amount.min(deposit);
+        self.total_deposits = self.total_deposits.saturating_sub(amount);
+        self.update_deposit(provider, deposit - amount);
+        gains
+    }
+
+    /// Snapshots the deposit of the provider, returns the returns earned until then.
+    pub fn settle(&mut self, provider: Principal) -> PoolGains {
+        if !self.deposits.contains_key(&provider) {
+            return PoolGains {
+                collateral: BTreeMap::new(),
+                fees: TAL::from(0),
+            };
+        }
+        let gains = self.gains_of(provider);
+        let deposit = self.deposit_of(provider);
+        self.update_deposit(provider, deposit);
+        gains
+    }
+
+    /// Shares `fee` across the deposits, pro rata.
+    pub fn distribute_fee(&mut self, fee: TAL) {
+        assert!(self.total_deposits > 0);
+        *self.fee_sums.entry((self.epoch, self.scale)).or_default() +=
+            to_decimal(fee.to_u64()) / to_decimal(self.total_deposits.to_u64()) * self.product;
+    }
+
+    /// Burns `debt` out of the deposits and shares `collateral` across them, pro rata.
+    /// When the pool is emptied, the deposits are removed and their returns are
+    /// returned.
+    pub fn offset(
+        &mut self,
+        debt: TAL,
+        collateral_type: CollateralType,
+        collateral: CKBTC,
+    ) -> BTreeMap<Principal, PoolGains> {
+        assert!(self.total_deposits > 0 && self.total_deposits >= debt);
+        let total_deposits = to_decimal(self.total_deposits.to_u64());
+        *self
+            .sums
+            .entry((self.epoch, self.scale))
+            .or_default()
+            .entry(collateral_type)
+            .or_default() += to_decimal(collateral.to_u64()) / total_deposits * self.product;
+
+        let remaining_share = Decimal::ONE - to_decimal(debt.to_u64()) / total_deposits;
+        let mut product = self.product * remaining_share;
+        if product < Decimal::ONE / SCALE_FACTOR {
+            product = self.product * SCALE_FACTOR * remaining_share;
+            self.scale += 1;
+        }
+        if debt == self.total_deposits || product.is_zero() {
+            // The pool is emptied, deposits of the previous epochs are worth nothing.
+            self.epoch += 1;
+            self.scale = 0;
+            self.product = Decimal::ONE;
+            self.total_deposits = TAL::from(0);
+            let providers: Vec<Principal> = self.deposits.keys().cloned().collect();
+            return providers
+                .into_iter()
+                .map(|provider| {
+                    let gains = self.gains_of(provider);
+                    self.deposits.remove(&provider);
+                    (provider, gains)
+                })
+                .collect();
+        }
+        self.product = product;
+        self.total_deposits -= debt;
+        BTreeMap::new()
+    }
+
+    fn update_deposit(&mut self, provider: Principal, amount: TAL) {
+        if amount == 0 {
+            self.deposits.remove(&provider);
+            return;
+        }
+        self.deposits.insert(
+            provider,
+            Deposit {
+                amount,
+                product: self.product,
+                sums: self
+                    .sums
+                    .get(&(self.epoch, self.scale))
+                    .cloned()
+                    .unwrap_or_default(),
+                fee_sum: self
+                    .fee_sums
+                    .get(&(self.epoch, self.scale))
+                    .copied()
+                    .unwrap_or_default(),
+                epoch: self.epoch,
+                scale: self.scale,
+            },
+        );
+    }
+
+    fn compounded_deposit(&self, deposit: &Deposit) -> TAL {
+        if deposit.epoch != self.epoch {
+            return TAL::from(0);
+        }
+        let remaining_share = match self.scale - deposit.scale {
+            0 => self.product / deposit.product,
+            1 => self.product / deposit.product / SCALE_FACTOR,
+            _ => return TAL::from(0),
+        };
+        TAL::from(to_amount(
+            to_decimal(deposit.amount.to_u64()) * remaining_share,
+        ))
+    }
+
+    fn collateral_gains(&self, deposit: &Deposit) -> BTreeMap<CollateralType, CKBTC> {
+        let no_sums = BTreeMap::new();
+        let sums = self
+            .sums
+            .get(&(deposit.epoch, deposit.scale))
+            .unwrap_or(&no_sums);
+        // Gains made after one scale change are scaled down, later ones are negligible.
+        let next_sums = self
+            .sums
+            .get(&(deposit.epoch, deposit.scale + 1))
+            .unwrap_or(&no_sums);
+        sums.keys()
+            .chain(next_sums.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|collateral_type| {
+                let sum = sums.get(collateral_type).copied().unwrap_or_default();
+                let snapshot = deposit
+                    .sums
+                    .get(collateral_type)
+                    .copied()
+                    .unwrap_or_default();
+                let next_sum = next_sums.get(collateral_type).copied().unwrap_or_default();
+                let gain_per_unit = (sum - snapshot + next_sum / SCALE_FACTOR) / deposit.product;
+                let gain = CKBTC::from(to_amount(
+                    to_decimal(deposit.amount.to_u64()) * gain_per_unit,
+                ));
+                (gain > 0).then_some((*collateral_type, gain))
+            })
+            .collect()
+    }
+
+    fn fee_gains(&self, deposit: &Deposit) -> TAL {
+        let sum = self
+            .fee_sums
+            .get(&(deposit.epoch, deposit.scale))
+            .copied()
+            .unwrap_or_default();
+        let next_sum = self
+            .fee_sums
+            .get(&(deposit.epoch, deposit.scale + 1))
+            .copied()
+            .unwrap_or_default();
+        let gain_per_unit = (sum - deposit.fee_sum + next_sum / SCALE_FACTOR) / deposit.product;
+        TAL::from(to_amount(
+            to_decimal(deposit.amount.to_u64()) * gain_per_unit,
+        ))
+    }
+}
+
+pub async fn provide_liquidity(
+    amount: u64,
+    stablecoin: StablecoinType,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
@@ -20,11 +343,27 @@ pub async fn provide_liquidity(amount: u64) -> Result<u64, ProtocolError> {
         });
     }
 
-    match transfer_tal_from(amount, caller).await {
+    if !read_state(|s| s.is_stablecoin_registered(stablecoin)) {
+        return Err(ProtocolError::GenericError(format!(
+            "unknown stablecoin: {stablecoin}"
+        )));
+    }
+
+    match transfer_stablecoin_from(amount, caller, from_subaccount, stablecoin).await {
         Ok(block_index) => {
-            log!(INFO, "[provide_liquidity] {caller} provided {amount}",);
+            log!(
+                INFO,
+                "[provide_liquidity] {caller} provided {amount} {stablecoin}",
+            );
             mutate_state(|s| {
-                record_provide_liquidity(s, amount, caller, block_index);
+                record_provide_liquidity(
+                    s,
+                    amount,
+                    caller,
+                    stablecoin,
+                    from_subaccount,
+                    block_index,
+                );
             });
             Ok(block_index)
         }
@@ -35,7 +374,11 @@ pub async fn provide_liquidity(amount: u64) -> Result<u64, ProtocolError> {
     }
 }
 
-pub async fn withdraw_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+pub async fn withdraw_liquidity(
+    amount: u64,
+    stablecoin: StablecoinType,
+    to: Option<Account>,
+) -> Result<u64, ProtocolError> {
     let caller = ic_cdk::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
@@ -47,23 +390,29 @@ pub async fn withdraw_liquidity(amount: u64) -> Result<u64, ProtocolError> {
         });
     }
 
-    let provided_liquidity = read_state(|s| {
-        s.liquidity_pool
-            .get(&caller)
-            .cloned()
-            .expect("no provided liquidity")
-    });
+    if !read_state(|s| s.is_stablecoin_registered(stablecoin)) {
+        return Err(ProtocolError::GenericError(format!(
+            "unknown stablecoin: {stablecoin}"
+        )));
+    }
+
+    let provided_liquidity = read_state(|s| s.get_provided_liquidity(caller, stablecoin));
     if amount > provided_liquidity {
         return Err(ProtocolError::GenericError(format!(
             "cannot withdraw: {amount}, provided: {provided_liquidity}"
         )));
     }
 
-    match mint_tal(amount, caller).await {
+    read_state(|s| s.check_withdrawal(caller, stablecoin, amount, ic_cdk::api::time()))?;
+
+    match mint_stablecoin(amount, to.unwrap_or(Account::from(caller)), stablecoin).await {
         Ok(block_index) => {
-            log!(INFO, "[withdraw_liquidity] {caller} withdrew {amount}",);
+            log!(
+                INFO,
+                "[withdraw_liquidity] {caller} withdrew {amount} {stablecoin}",
+            );
             mutate_state(|s| {
-                record_withdraw_liquidity(s, amount, caller, block_index);
+                record_withdraw_liquidity(s, amount, caller, stablecoin, to, block_index);
             });
             Ok(block_index)
         }
@@ -71,20 +420,86 @@ pub async fn withdraw_liquidity(amount: u64) -> Result<u64, ProtocolError> {
     }
 }
 
-pub async fn claim_liquidity_returns() -> Result<u64, ProtocolError> {
+/// Starts the cooldown after which `amount` can be withdrawn until the withdrawal
+/// window closes, replacing any previous request for the pool.
+pub fn request_withdrawal(amount: u64, stablecoin: StablecoinType) -> Result<(), ProtocolError> {
     let caller = ic_cdk::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let return_amount = read_state(|s| *s.liquidity_returns.get(&caller).expect("No reward"));
+    let amount: TAL = amount.into();
+
+    if amount < MIN_LIQUIDITY_AMOUNT {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: MIN_LIQUIDITY_AMOUNT.to_u64(),
+        });
+    }
+
+    let provided_liquidity = read_state(|s| s.get_provided_liquidity(caller, stablecoin));
+    if amount > provided_liquidity {
+        return Err(ProtocolError::GenericError(format!(
+            "cannot request: {amount}, provided: {provided_liquidity}"
+        )));
+    }
+
+    log!(
+        INFO,
+        "[request_withdrawal] {caller} requested to withdraw {amount} {stablecoin}",
+    );
+    mutate_state(|s| record_request_withdrawal(s, caller, amount, stablecoin, ic_cdk::api::time()));
+    Ok(())
+}
+
+/// Claims `amount` of the collateral returns of the caller, all of them by default.
+/// The ledger fee is paid out of the claimed amount, and claims whose transfer fails
+/// are queued with the other pending transfers.
+pub async fn claim_liquidity_returns(
+    collateral_type: CollateralType,
+    amount: Option<u64>,
+    to: Option<Account>,
+) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let available_returns = read_state(|s| s.get_liquidity_returns_of(caller, collateral_type));
+    if available_returns == 0 {
+        return Err(ProtocolError::GenericError(format!(
+            "no {collateral_type} returns to claim"
+        )));
+    }
+    let return_amount = amount.map(CKBTC::from).unwrap_or(available_returns);
+    if return_amount > available_returns {
+        return Err(ProtocolError::GenericError(format!(
+            "cannot claim: {return_amount}, available: {available_returns}"
+        )));
+    }
+    let transfer_fee = read_state(|s| s.get_collateral_ledger_fee(collateral_type));
+    if return_amount <= transfer_fee {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: (transfer_fee + CKBTC::from(1)).to_u64(),
+        });
+    }
 
-    match transfer_ckbtc(return_amount, caller).await {
+    match transfer_collateral(
+        return_amount - transfer_fee,
+        to.unwrap_or(Account::from(caller)),
+        collateral_type,
+    )
+    .await
+    {
         Ok(block_index) => {
             log!(
                 INFO,
-                "[claim_liquidity_returns] {caller} claimed {return_amount}",
+                "[claim_liquidity_returns] {caller} claimed {return_amount} of {collateral_type}",
             );
             mutate_state(|s| {
-                record_claim_liquidity_returns(s, return_amount, caller, block_index);
+                record_claim_liquidity_returns(
+                    s,
+                    return_amount,
+                    caller,
+                    collateral_type,
+                    to,
+                    Some(block_index),
+                );
             });
             Ok(block_index)
         }
@@ -95,10 +510,81 @@ pub async fn claim_liquidity_returns() -> Result<u64, ProtocolError> {
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.ckbtc_ledger_fee = CKBTC::from(expected_fee);
+                    s.set_collateral_ledger_fee(collateral_type, CKBTC::from(expected_fee));
                 });
             };
-            Err(ProtocolError::TransferError(transfer_error))
+            log!(
+                INFO,
+                "[claim_liquidity_returns] queuing claim of {return_amount} of {collateral_type} by {caller} after error: {transfer_error}",
+            );
+            mutate_state(|s| {
+                record_claim_liquidity_returns(s, return_amount, caller, collateral_type, to, None);
+            });
+            ic_cdk_timers::set_timer(std::time::Duration::from_secs(1), || {
+                ic_cdk::spawn(crate::process_pending_transfer())
+            });
+            Err(ProtocolError::TemporarilyUnavailable(format!(
+                "failed to transfer returns with error: {transfer_error}, the claim is queued for retry"
+            )))
         }
     }
 }
+
+pub async fn claim_fee_returns(
+    stablecoin: StablecoinType,
+    to: Option<Account>,
+) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let return_amount = read_state(|s| s.get_fee_returns_of(caller, stablecoin));
+    if return_amount == 0 {
+        return Err(ProtocolError::GenericError(format!(
+            "no {stablecoin} fee returns to claim"
+        )));
+    }
+
+    match mint_stablecoin(
+        return_amount,
+        to.unwrap_or(Account::from(caller)),
+        stablecoin,
+    )
+    .await
+    {
+        Ok(block_index) => {
+            log!(
+                INFO,
+                "[claim_fee_returns] {caller} claimed {return_amount} {stablecoin} of fees",
+            );
+            mutate_state(|s| {
+                record_claim_fee_returns(s, return_amount, caller, stablecoin, to, block_index);
+            });
+            Ok(block_index)
+        }
+        Err(transfer_error) => Err(ProtocolError::TransferError(transfer_error)),
+    }
+}
+
+pub fn set_compounding_preference(preference: CompoundingPreference) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    if let CompoundingPreference::AddToVault { vault_id } = preference {
+        match read_state(|s| s.vault_id_to_vaults.get(&vault_id).map(|vault| vault.owner)) {
+            Some(owner) if owner == caller => (),
+            Some(_) => return Err(ProtocolError::CallerNotOwner),
+            None => {
+                return Err(ProtocolError::GenericError(format!(
+                    "unknown vault: {vault_id}"
+                )))
+            }
+        }
+    }
+
+    log!(
+        INFO,
+        "[set_compounding_preference] {caller} set its compounding preference to {preference:?}",
+    );
+    mutate_state(|s| record_set_compounding_preference(s, caller, preference));
+    Ok(())
+}
@@ -2,14 +2,24 @@ use candid::{candid_method, Principal};
 use ic_canister_log::log;
 use ic_canisters_http_types::{HttpRequest, HttpResponse, HttpResponseBuilder};
 use ic_cdk_macros::{init, post_upgrade, query, update};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use protocol_canister::auction::{AuctionStatus, BidSuccess};
+use protocol_canister::collateral::{AddCollateralTypeArg, CollateralStatus, CollateralType};
 use protocol_canister::event::Event;
+use protocol_canister::liquidity_pool::CompoundingPreference;
 use protocol_canister::logs::INFO;
 use protocol_canister::numeric::UsdBtc;
-use protocol_canister::state::{read_state, replace_state, Mode, State};
+use protocol_canister::simulation::PriceSimulation;
+use protocol_canister::stablecoin::{StablecoinStatus, StablecoinType};
+use protocol_canister::state::{mutate_state, read_state, replace_state, Mode, State};
 use protocol_canister::storage::events;
-use protocol_canister::vault::{CandidVault, OpenVaultSuccess, VaultArg};
+use protocol_canister::vault::{
+    CandidVault, OpenVaultAndBorrowSuccess, OpenVaultSuccess, OperatorPermissions, VaultArg,
+    VaultOperator,
+};
 use protocol_canister::{
-    Fees, GetEventsArg, LiquidityStatus, ProtocolArg, ProtocolError, ProtocolStatus, SuccessWithFee,
+    Fees, GetEventsArg, LiquidityStatus, PricePoint, ProtocolArg, ProtocolError, ProtocolStatus,
+    RedemptionSuccess, SuccessWithFee, MAX_PRICE_HISTORY_LEN, MIN_CKBTC_AMOUNT, SEC_NANOS,
 };
 use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::prelude::ToPrimitive;
@@ -60,6 +70,24 @@ fn validate_call() -> Result<(), ProtocolError> {
     read_state(|s| s.check_price_not_too_old())
 }
 
+fn validate_controller() -> Result<(), ProtocolError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        return Err(ProtocolError::CallerNotController);
+    }
+    Ok(())
+}
+
+fn validate_stablecoin(
+    arg: &protocol_canister::stablecoin::AddStablecoinArg,
+    taler_ledger_principal: Principal,
+) {
+    if let Err(e) =
+        protocol_canister::stablecoin::validate_add_stablecoin_arg(arg, taler_ledger_principal)
+    {
+        ic_cdk::trap(&format!("invalid stablecoin {}: {:?}", arg.symbol, e));
+    }
+}
+
 fn validate_mode() -> Result<(), ProtocolError> {
     match read_state(|s| s.mode) {
         Mode::ReadOnly => {
@@ -76,6 +104,10 @@ fn setup_timers() {
     ic_cdk_timers::set_timer_interval(protocol_canister::xrc::FETCHING_BTC_RATE_INTERVAL, || {
         ic_cdk::spawn(protocol_canister::xrc::fetch_btc_rate())
     });
+    ic_cdk_timers::set_timer_interval(
+        protocol_canister::auction::CHECK_AUCTIONS_INTERVAL,
+        protocol_canister::auction::check_auctions,
+    );
 }
 
 fn main() {}
@@ -85,11 +117,10 @@ fn main() {}
 fn init(arg: ProtocolArg) {
     match arg {
         ProtocolArg::Init(init_arg) => {
-            log!(
-                INFO,
-                "[init] initialized ckCoins with args: {:?}",
-                init_arg
-            );
+            for stablecoin in init_arg.stablecoins.iter().flatten() {
+                validate_stablecoin(stablecoin, init_arg.taler_ledger_principal);
+            }
+            log!(INFO, "[init] initialized ckCoins with args: {:?}", init_arg);
             protocol_canister::storage::record_event(&Event::Init(init_arg.clone()));
             replace_state(State::from(init_arg));
         }
@@ -101,13 +132,13 @@ fn init(arg: ProtocolArg) {
 #[post_upgrade]
 fn post_upgrade(arg: ProtocolArg) {
     use protocol_canister::event::replay;
-    use protocol_canister::storage::{count_events, events, record_event};
+    use protocol_canister::storage::{backfill_price_history, count_events, events, record_event};
 
     let start = ic_cdk::api::instruction_counter();
 
     log!(INFO, "[upgrade]: replaying {} events", count_events());
 
-    match arg {
+    let new_stablecoins = match arg {
         ProtocolArg::Init(_) => ic_cdk::trap("expected Upgrade got Init"),
         ProtocolArg::Upgrade(upgrade_args) => {
             log!(
@@ -115,9 +146,17 @@ fn post_upgrade(arg: ProtocolArg) {
                 "[upgrade]: updating configuration with {:?}",
                 upgrade_args
             );
+            let new_stablecoins = upgrade_args.stablecoins.clone();
+            if upgrade_args.stability_fee_rate_e8s.is_some() {
+                // Accrue at the previous rate up to the upgrade.
+                record_event(&Event::AccrueStabilityFee {
+                    timestamp: ic_cdk::api::time(),
+                });
+            }
             record_event(&Event::Upgrade(upgrade_args));
+            new_stablecoins
         }
-    }
+    };
 
     let state = replay(events()).unwrap_or_else(|e| {
         ic_cdk::trap(&format!(
@@ -126,7 +165,16 @@ fn post_upgrade(arg: ProtocolArg) {
         ))
     });
 
+    // Trapping reverts the upgrade, including the recorded event.
+    for stablecoin in new_stablecoins.iter().flatten() {
+        validate_stablecoin(stablecoin, state.taler_ledger_principal);
+    }
+    if let Err(e) = state.check_liquidation_reward() {
+        ic_cdk::trap(&format!("[upgrade]: invalid liquidation reward: {e}"));
+    }
+
     replace_state(state);
+    backfill_price_history();
 
     let end = ic_cdk::api::instruction_counter();
 
@@ -152,15 +200,46 @@ fn get_protocol_status() -> ProtocolStatus {
         total_tal_borrowed: s.total_borrowed_tal_amount().to_u64(),
         total_collateral_ratio: s.total_collateral_ratio.to_f64(),
         mode: s.mode,
+        total_debt_value: s.total_debt_value().to_u64(),
+        global_debt_ceiling: s.global_debt_ceiling.map(|ceiling| ceiling.to_u64()),
+        principal_debt_ceiling: s.principal_debt_ceiling.map(|ceiling| ceiling.to_u64()),
+        btc_twap: s
+            .btc_twap()
+            .or(s.last_btc_rate)
+            .unwrap_or(UsdBtc::from(Decimal::ZERO))
+            .to_f64(),
+        liquidations_frozen: s.liquidations_frozen,
+        btc_price_status: s.btc_price_status(),
+        bad_debt: s
+            .bad_debt
+            .iter()
+            .map(|(stablecoin, debt)| (*stablecoin, debt.to_u64()))
+            .collect(),
     })
 }
 
+#[candid_method(query)]
+#[query]
+fn get_price_history(from: u64, to: u64, resolution: u64) -> Vec<PricePoint> {
+    protocol_canister::storage::price_history(from, to, resolution, MAX_PRICE_HISTORY_LEN)
+        .into_iter()
+        .map(|(timestamp, rate)| PricePoint {
+            timestamp,
+            btc_rate: rate.to_f64(),
+        })
+        .collect()
+}
+
 #[candid_method(query)]
 #[query]
 fn get_fees(redeemed_amount: u64) -> Fees {
     read_state(|s| Fees {
-        borrowing_fee: s.get_borrowing_fee().to_f64(),
+        borrowing_fee: s.get_borrowing_fee(StablecoinType::Tal).to_f64(),
         redemption_fee: s.get_redemption_fee(redeemed_amount.into()).to_f64(),
+        stability_fee_rate: s.stability_fee_rate.to_f64(),
+        liquidation_reward: s.liquidation_reward_rate.to_f64(),
+        liquidation_penalty: s.liquidation_penalty.to_f64(),
+        lp_fee_share: s.lp_fee_share.to_f64(),
     })
 }
 
@@ -196,21 +275,39 @@ fn get_events(args: GetEventsArg) -> Vec<Event> {
 
 #[candid_method(query)]
 #[query]
-fn get_liquidity_status(owner: Principal) -> LiquidityStatus {
-    let total_liquidity_provided = read_state(|s| s.total_provided_liquidity_amount());
+fn get_liquidity_status(owner: Principal, stablecoin: Option<StablecoinType>) -> LiquidityStatus {
+    let stablecoin = stablecoin.unwrap_or_default();
+    let total_liquidity_provided = read_state(|s| s.total_provided_liquidity_amount(stablecoin));
     let liquidity_pool_share = if total_liquidity_provided == 0 {
         0.0
     } else {
         read_state(|s| {
-            (s.get_provided_liquidity(owner) / s.total_provided_liquidity_amount()).to_f64()
+            (s.get_provided_liquidity(owner, stablecoin) / total_liquidity_provided).to_f64()
         })
     };
+    let pending_withdrawal = read_state(|s| s.get_pending_withdrawal(owner, stablecoin));
     read_state(|s| LiquidityStatus {
-        liquidity_provided: s.get_provided_liquidity(owner).to_u64(),
-        total_liquidity_provided: s.total_provided_liquidity_amount().to_u64(),
+        liquidity_provided: s.get_provided_liquidity(owner, stablecoin).to_u64(),
+        total_liquidity_provided: total_liquidity_provided.to_u64(),
         liquidity_pool_share,
-        available_liquidity_reward: s.get_liquidity_returns_of(owner).to_u64(),
-        total_available_returns: s.total_available_returns().to_u64(),
+        available_liquidity_reward: s
+            .get_liquidity_returns_of(owner, CollateralType::CkBtc)
+            .to_u64(),
+        total_available_returns: s.total_available_returns(CollateralType::CkBtc).to_u64(),
+        available_fee_reward: s.get_fee_returns_of(owner, stablecoin).to_u64(),
+        total_fee_returns: s.total_fee_returns(stablecoin).to_u64(),
+        compounding_preference: s
+            .compounding_preferences
+            .get(&owner)
+            .cloned()
+            .unwrap_or_default(),
+        pending_withdrawal: pending_withdrawal.map(|request| request.amount.to_u64()),
+        withdrawal_unlock_time: pending_withdrawal
+            .map(|request| request.requested_at + s.withdrawal_cooldown_secs * SEC_NANOS),
+        withdrawal_expiry_time: pending_withdrawal.map(|request| {
+            request.requested_at
+                + (s.withdrawal_cooldown_secs + s.withdrawal_window_secs) * SEC_NANOS
+        }),
     })
 }
 
@@ -221,69 +318,286 @@ fn get_vaults(target: Option<Principal>) -> Vec<CandidVault> {
         Some(target) => read_state(|s| match s.principal_to_vault_ids.get(&target) {
             Some(vault_ids) => vault_ids
                 .iter()
-                .map(|id| {
-                    let vault = s.vault_id_to_vaults.get(id).cloned().unwrap();
-                    CandidVault {
-                        owner: vault.owner,
-                        borrowed_tal_amount: vault.borrowed_tal_amount.to_u64(),
-                        ckbtc_margin_amount: vault.ckbtc_margin_amount.to_u64(),
-                        vault_id: vault.vault_id,
-                    }
-                })
+                .map(|id| CandidVault::from(s.get_vault(*id).unwrap()))
                 .collect(),
             None => vec![],
         }),
         None => read_state(|s| {
-            s.vault_id_to_vaults
-                .values()
-                .map(|vault| CandidVault {
-                    owner: vault.owner,
-                    borrowed_tal_amount: vault.borrowed_tal_amount.to_u64(),
-                    ckbtc_margin_amount: vault.ckbtc_margin_amount.to_u64(),
-                    vault_id: vault.vault_id,
-                })
+            s.vaults()
+                .map(CandidVault::from)
                 .collect::<Vec<CandidVault>>()
         }),
     }
 }
 
+#[candid_method(query)]
+#[query]
+fn simulate_price(btc_rate: f64) -> PriceSimulation {
+    let btc_rate = match Decimal::from_f64(btc_rate) {
+        Some(rate) if rate > Decimal::ZERO => UsdBtc::from(rate),
+        _ => ic_cdk::trap("expected a positive BTC rate"),
+    };
+    protocol_canister::simulation::simulate_price(btc_rate)
+}
+
+#[candid_method(query)]
+#[query]
+fn get_auctions() -> Vec<AuctionStatus> {
+    let now = ic_cdk::api::time();
+    read_state(|s| {
+        s.auctions
+            .values()
+            .map(|auction| AuctionStatus::new(auction, now))
+            .collect()
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn get_vault_operators(vault_id: u64) -> Vec<VaultOperator> {
+    read_state(|s| match s.vault_operators.get(&vault_id) {
+        Some(operators) => operators
+            .iter()
+            .map(|(operator, permissions)| VaultOperator {
+                operator: *operator,
+                permissions: *permissions,
+            })
+            .collect(),
+        None => vec![],
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn get_collateral_types() -> Vec<CollateralStatus> {
+    read_state(|s| {
+        let mut collateral_types = vec![CollateralStatus {
+            collateral_type: CollateralType::CkBtc,
+            ledger_principal: s.ckbtc_ledger_principal,
+            xrc_symbol: "BTC".to_string(),
+            minimum_collateral_ratio: s
+                .get_collateral_minimum_ratio(CollateralType::CkBtc)
+                .to_f64(),
+            debt_ceiling: u64::MAX,
+            minimum_amount: MIN_CKBTC_AMOUNT.to_u64(),
+            total_margin: s.total_ckbtc_margin_amount().to_u64(),
+            total_borrowed: s.total_debt_value_on(CollateralType::CkBtc).to_u64(),
+            last_rate: s.last_btc_rate.unwrap_or(UsdBtc::from(dec!(0))).to_f64(),
+            last_timestamp: s.last_btc_timestamp.unwrap_or(0),
+        }];
+        for (ledger_principal, config) in s.collaterals.iter() {
+            let collateral_type = CollateralType::Icrc(*ledger_principal);
+            collateral_types.push(CollateralStatus {
+                collateral_type,
+                ledger_principal: *ledger_principal,
+                xrc_symbol: config.xrc_symbol.clone(),
+                minimum_collateral_ratio: config.minimum_collateral_ratio.to_f64(),
+                debt_ceiling: config.debt_ceiling.to_u64(),
+                minimum_amount: config.minimum_amount.to_u64(),
+                total_margin: s.total_margin_amount(collateral_type).to_u64(),
+                total_borrowed: s.total_debt_value_on(collateral_type).to_u64(),
+                last_rate: config.last_rate.unwrap_or(UsdBtc::from(dec!(0))).to_f64(),
+                last_timestamp: config.last_timestamp.unwrap_or(0),
+            });
+        }
+        collateral_types
+    })
+}
+
+#[candid_method(query)]
+#[query]
+fn get_stablecoins() -> Vec<StablecoinStatus> {
+    read_state(|s| {
+        let mut stablecoins = vec![StablecoinStatus {
+            stablecoin: StablecoinType::Tal,
+            ledger_principal: s.taler_ledger_principal,
+            symbol: "TAL".to_string(),
+            peg_symbol: protocol_canister::stablecoin::USD_PEG.to_string(),
+            borrowing_fee: s.fee.to_f64(),
+            total_borrowed: s.total_borrowed_tal_amount().to_u64(),
+            total_liquidity_provided: s
+                .total_provided_liquidity_amount(StablecoinType::Tal)
+                .to_u64(),
+            last_peg_rate: 1.0,
+            last_peg_timestamp: 0,
+        }];
+        for (ledger_principal, config) in s.stablecoins.iter() {
+            let stablecoin = StablecoinType::Icrc(*ledger_principal);
+            stablecoins.push(StablecoinStatus {
+                stablecoin,
+                ledger_principal: *ledger_principal,
+                symbol: config.symbol.clone(),
+                peg_symbol: config.peg_symbol.clone(),
+                borrowing_fee: config.borrowing_fee.to_f64(),
+                total_borrowed: s.total_borrowed_amount_of(stablecoin).to_u64(),
+                total_liquidity_provided: s.total_provided_liquidity_amount(stablecoin).to_u64(),
+                last_peg_rate: config
+                    .last_peg_rate
+                    .map(|rate| rate.to_f64())
+                    .unwrap_or(0.0),
+                last_peg_timestamp: config.last_peg_timestamp.unwrap_or(0),
+            });
+        }
+        stablecoins
+    })
+}
+
 // Vault related operations
 
 #[candid_method(update)]
 #[update]
-async fn redeem_ckbtc(tal_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
+async fn redeem_ckbtc(
+    tal_amount: u64,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+    max_fee_percentage: Option<f64>,
+    min_ckbtc_out: Option<u64>,
+    collateral_type: Option<CollateralType>,
+) -> Result<RedemptionSuccess, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(
+        protocol_canister::vault::redeem_ckbtc(
+            tal_amount,
+            collateral_type.unwrap_or_default(),
+            from_subaccount,
+            to,
+            max_fee_percentage,
+            min_ckbtc_out,
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn open_vault(
+    ckbtc_margin: u64,
+    collateral_type: Option<CollateralType>,
+    stablecoin: Option<StablecoinType>,
+    from_subaccount: Option<Subaccount>,
+) -> Result<OpenVaultSuccess, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        protocol_canister::vault::open_vault(
+            ckbtc_margin,
+            collateral_type.unwrap_or_default(),
+            stablecoin.unwrap_or_default(),
+            from_subaccount,
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn open_vault_and_borrow(
+    ckbtc_margin: u64,
+    tal_amount: u64,
+    collateral_type: Option<CollateralType>,
+    stablecoin: Option<StablecoinType>,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+) -> Result<OpenVaultAndBorrowSuccess, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(
+        protocol_canister::vault::open_vault_and_borrow(
+            ckbtc_margin,
+            tal_amount,
+            collateral_type.unwrap_or_default(),
+            stablecoin.unwrap_or_default(),
+            from_subaccount,
+            to,
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn borrow_from_vault(
+    arg: VaultArg,
+    to: Option<Account>,
+) -> Result<SuccessWithFee, ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(protocol_canister::vault::borrow_from_vault(arg, to).await)
+}
+
+#[candid_method(update)]
+#[update]
+async fn repay_to_vault(
+    arg: VaultArg,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(protocol_canister::vault::repay_to_vault(arg, from_subaccount).await)
+}
+
+#[candid_method(update)]
+#[update]
+async fn add_margin_to_vault(
+    arg: VaultArg,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(protocol_canister::vault::add_margin_to_vault(arg, from_subaccount).await)
+}
+
+#[candid_method(update)]
+#[update]
+async fn withdraw_margin_from_vault(
+    arg: VaultArg,
+    to: Option<Account>,
+) -> Result<(), ProtocolError> {
     validate_call()?;
     validate_mode()?;
-    check_postcondition(protocol_canister::vault::redeem_ckbtc(tal_amount).await)
+    check_postcondition(protocol_canister::vault::withdraw_margin_from_vault(arg, to).await)
 }
 
 #[candid_method(update)]
 #[update]
-async fn open_vault(ckbtc_margin: u64) -> Result<OpenVaultSuccess, ProtocolError> {
+fn merge_vaults(target_vault_id: u64, source_vault_id: u64) -> Result<(), ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::vault::open_vault(ckbtc_margin).await)
+    check_postcondition(protocol_canister::vault::merge_vaults(
+        target_vault_id,
+        source_vault_id,
+    ))
 }
 
 #[candid_method(update)]
 #[update]
-async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, ProtocolError> {
+fn split_vault(vault_id: u64, margin: u64, debt: u64) -> Result<u64, ProtocolError> {
     validate_call()?;
     validate_mode()?;
-    check_postcondition(protocol_canister::vault::borrow_from_vault(arg).await)
+    check_postcondition(protocol_canister::vault::split_vault(
+        vault_id, margin, debt,
+    ))
 }
 
 #[candid_method(update)]
 #[update]
-async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+fn transfer_vault(vault_id: u64, new_owner: Principal) -> Result<(), ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::vault::repay_to_vault(arg).await)
+    check_postcondition(protocol_canister::vault::transfer_vault(
+        vault_id, new_owner,
+    ))
 }
 
 #[candid_method(update)]
 #[update]
-async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+fn set_vault_operator(
+    vault_id: u64,
+    operator: Principal,
+    permissions: Option<OperatorPermissions>,
+) -> Result<(), ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::vault::add_margin_to_vault(arg).await)
+    check_postcondition(protocol_canister::vault::set_vault_operator(
+        vault_id,
+        operator,
+        permissions,
+    ))
 }
 
 #[candid_method(update)]
@@ -293,27 +607,144 @@ async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
     check_postcondition(protocol_canister::vault::close_vault(vault_id).await)
 }
 
+#[candid_method(update)]
+#[update]
+fn liquidate(vault_id: u64, to: Option<Account>) -> Result<(), ProtocolError> {
+    validate_call()?;
+    validate_mode()?;
+    check_postcondition(protocol_canister::vault::liquidate(vault_id, to))
+}
+
 // Liquidity related operations
 
 #[candid_method(update)]
 #[update]
-async fn provide_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+async fn provide_liquidity(
+    amount: u64,
+    stablecoin: Option<StablecoinType>,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        protocol_canister::liquidity_pool::provide_liquidity(
+            amount,
+            stablecoin.unwrap_or_default(),
+            from_subaccount,
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+async fn withdraw_liquidity(
+    amount: u64,
+    stablecoin: Option<StablecoinType>,
+    to: Option<Account>,
+) -> Result<u64, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        protocol_canister::liquidity_pool::withdraw_liquidity(
+            amount,
+            stablecoin.unwrap_or_default(),
+            to,
+        )
+        .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+fn request_withdrawal(
+    amount: u64,
+    stablecoin: Option<StablecoinType>,
+) -> Result<(), ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::liquidity_pool::provide_liquidity(amount).await)
+    check_postcondition(protocol_canister::liquidity_pool::request_withdrawal(
+        amount,
+        stablecoin.unwrap_or_default(),
+    ))
 }
 
 #[candid_method(update)]
 #[update]
-async fn withdraw_liquidity(amount: u64) -> Result<u64, ProtocolError> {
+async fn claim_liquidity_returns(
+    collateral_type: Option<CollateralType>,
+    to: Option<Account>,
+    amount: Option<u64>,
+) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::liquidity_pool::withdraw_liquidity(amount).await)
+    check_postcondition(
+        protocol_canister::liquidity_pool::claim_liquidity_returns(
+            collateral_type.unwrap_or_default(),
+            amount,
+            to,
+        )
+        .await,
+    )
 }
 
 #[candid_method(update)]
 #[update]
-async fn claim_liquidity_returns() -> Result<u64, ProtocolError> {
+async fn claim_fee_returns(
+    stablecoin: Option<StablecoinType>,
+    to: Option<Account>,
+) -> Result<u64, ProtocolError> {
     validate_call()?;
-    check_postcondition(protocol_canister::liquidity_pool::claim_liquidity_returns().await)
+    check_postcondition(
+        protocol_canister::liquidity_pool::claim_fee_returns(stablecoin.unwrap_or_default(), to)
+            .await,
+    )
+}
+
+#[candid_method(update)]
+#[update]
+fn set_compounding_preference(preference: CompoundingPreference) -> Result<(), ProtocolError> {
+    validate_call()?;
+    check_postcondition(protocol_canister::liquidity_pool::set_compounding_preference(preference))
+}
+
+// Auction related operations
+
+#[candid_method(update)]
+#[update]
+async fn bid(
+    vault_id: u64,
+    max_amount: u64,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+) -> Result<BidSuccess, ProtocolError> {
+    validate_call()?;
+    check_postcondition(
+        protocol_canister::auction::bid(vault_id, max_amount, from_subaccount, to).await,
+    )
+}
+
+// Oracle related operations
+
+#[candid_method(update)]
+#[update]
+fn push_btc_price(rate_e8s: u64, timestamp: u64) -> Result<(), ProtocolError> {
+    validate_call()?;
+    // No check_postcondition: pushes only update the transient pushed prices, which are
+    // not recorded as events and are left out of the replay comparison.
+    protocol_canister::oracle::push_btc_price(rate_e8s, timestamp)
+}
+
+// Governance related operations
+
+#[candid_method(update)]
+#[update]
+fn add_collateral_type(arg: AddCollateralTypeArg) -> Result<(), ProtocolError> {
+    validate_controller()?;
+    protocol_canister::collateral::validate_add_collateral_type_arg(&arg)?;
+    log!(
+        INFO,
+        "[add_collateral_type] registering collateral: {:?}",
+        arg
+    );
+    mutate_state(|s| protocol_canister::event::record_add_collateral_type(s, arg));
+    check_postcondition(Ok(()))
 }
 
 #[query]
@@ -348,13 +779,14 @@ fn http_request(req: HttpRequest) -> HttpResponse {
 
                 w.encode_gauge(
                     "elliptic_total_provided_liquidity_amount",
-                    s.total_provided_liquidity_amount().to_u64() as f64,
+                    s.total_provided_liquidity_amount(StablecoinType::Tal)
+                        .to_u64() as f64,
                     "Provided amount of liquidity.",
                 )?;
 
                 w.encode_gauge(
                     "elliptic_liquidity_providers_count",
-                    s.liquidity_pool.len() as f64,
+                    s.liquidity_pool.deposits().count() as f64,
                     "Count of liquidity providers.",
                 )?;
 
@@ -366,7 +798,7 @@ fn http_request(req: HttpRequest) -> HttpResponse {
 
                 w.encode_gauge(
                     "elliptic_liquidity_providers_rewards",
-                    s.total_available_returns().to_u64() as f64,
+                    s.total_available_returns(CollateralType::CkBtc).to_u64() as f64,
                     "Available rewards for liquidity providers.",
                 )?;
 
@@ -392,6 +824,12 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     .expect("failed to construct decimal from u64")
                     / dec!(100_000_000);
 
+                w.encode_counter(
+                    "elliptic_rejected_xrc_rates",
+                    s.rejected_xrc_rates_count as f64,
+                    "Number of low-confidence BTC rates rejected.",
+                )?;
+
                 w.encode_gauge(
                     "elliptic_total_ckbtc_margin",
                     total_ckbtc_dec.to_f64().unwrap(),
@@ -422,6 +860,28 @@ fn http_request(req: HttpRequest) -> HttpResponse {
                     "TCR.",
                 )?;
 
+                w.encode_gauge(
+                    "elliptic_total_debt_value",
+                    s.total_debt_value().to_u64() as f64 / 100_000_000.0,
+                    "Total USD value of the debt.",
+                )?;
+
+                if let Some(global_debt_ceiling) = s.global_debt_ceiling {
+                    w.encode_gauge(
+                        "elliptic_global_debt_ceiling",
+                        global_debt_ceiling.to_u64() as f64 / 100_000_000.0,
+                        "Global debt ceiling in USD.",
+                    )?;
+                }
+
+                if let Some(principal_debt_ceiling) = s.principal_debt_ceiling {
+                    w.encode_gauge(
+                        "elliptic_principal_debt_ceiling",
+                        principal_debt_ceiling.to_u64() as f64 / 100_000_000.0,
+                        "Debt ceiling of a single principal in USD.",
+                    )?;
+                }
+
                 Ok(())
             })
         }
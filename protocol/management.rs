@@ -1,9 +1,11 @@
+use crate::collateral::CollateralType;
 use crate::numeric::{CKBTC, TAL};
+use crate::stablecoin::StablecoinType;
 use crate::state::read_state;
 use candid::{Nat, Principal};
 use ic_icrc1_client_cdk::{CdkRuntime, ICRC1Client};
 use ic_xrc_types::{Asset, AssetClass, GetExchangeRateRequest, GetExchangeRateResult};
-use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
 use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
 use std::fmt;
@@ -65,16 +67,50 @@ impl fmt::Display for Reason {
     }
 }
 
-/// Query the XRC canister to retrieve the last BTC/USD price.
+/// Query an XRC canister to retrieve the last BTC/USD price.
 /// https://github.com/dfinity/exchange-rate-canister
-pub async fn fetch_btc_price() -> Result<GetExchangeRateResult, String> {
+pub async fn fetch_btc_price(xrc_principal: Principal) -> Result<GetExchangeRateResult, String> {
+    fetch_usd_rate(
+        xrc_principal,
+        Asset {
+            symbol: "BTC".to_string(),
+            class: AssetClass::Cryptocurrency,
+        },
+    )
+    .await
+}
+
+/// Query the XRC canister to retrieve the last USD price of a cryptocurrency.
+pub async fn fetch_usd_price(symbol: &str) -> Result<GetExchangeRateResult, String> {
+    fetch_usd_rate(
+        read_state(|s| s.xrc_principal),
+        Asset {
+            symbol: symbol.to_string(),
+            class: AssetClass::Cryptocurrency,
+        },
+    )
+    .await
+}
+
+/// Query the XRC canister to retrieve the last USD price of a fiat currency.
+pub async fn fetch_fiat_usd_price(symbol: &str) -> Result<GetExchangeRateResult, String> {
+    fetch_usd_rate(
+        read_state(|s| s.xrc_principal),
+        Asset {
+            symbol: symbol.to_string(),
+            class: AssetClass::FiatCurrency,
+        },
+    )
+    .await
+}
+
+async fn fetch_usd_rate(
+    xrc_principal: Principal,
+    base_asset: Asset,
+) -> Result<GetExchangeRateResult, String> {
     const XRC_CALL_COST_CYCLES: u64 = 10_000_000_000;
     const XRC_MARGIN_SEC: u64 = 60;
 
-    let btc = Asset {
-        symbol: "BTC".to_string(),
-        class: AssetClass::Cryptocurrency,
-    };
     let usd = Asset {
         symbol: "USD".to_string(),
         class: AssetClass::FiatCurrency,
@@ -83,15 +119,13 @@ pub async fn fetch_btc_price() -> Result<GetExchangeRateResult, String> {
     // Take few minutes back to be sure to have data.
     let timestamp_sec = ic_cdk::api::time() / crate::SEC_NANOS - XRC_MARGIN_SEC;
 
-    // Retrieve last BTC/USD value.
+    // Retrieve last value against USD.
     let args = GetExchangeRateRequest {
-        base_asset: btc,
+        base_asset,
         quote_asset: usd,
         timestamp: Some(timestamp_sec),
     };
 
-    let xrc_principal = read_state(|s| s.xrc_principal);
-
     let res_xrc: Result<(GetExchangeRateResult,), (i32, String)> =
         ic_cdk::api::call::call_with_payment(
             xrc_principal,
@@ -110,18 +144,19 @@ pub async fn fetch_btc_price() -> Result<GetExchangeRateResult, String> {
     }
 }
 
-pub async fn mint_tal(amount: TAL, to: Principal) -> Result<u64, TransferError> {
+pub async fn mint_stablecoin(
+    amount: TAL,
+    to: Account,
+    stablecoin: StablecoinType,
+) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: crate::state::read_state(|s| s.taler_ledger_principal),
+        ledger_canister_id: read_state(|s| s.get_stablecoin_ledger_principal(stablecoin)),
     };
     let block_index = client
         .transfer(TransferArg {
             from_subaccount: None,
-            to: Account {
-                owner: to,
-                subaccount: None,
-            },
+            to,
             fee: None,
             created_at_time: None,
             memo: None,
@@ -135,10 +170,15 @@ pub async fn mint_tal(amount: TAL, to: Principal) -> Result<u64, TransferError>
     Ok(block_index)
 }
 
-pub async fn transfer_tal_from(amount: TAL, caller: Principal) -> Result<u64, TransferFromError> {
+pub async fn transfer_stablecoin_from(
+    amount: TAL,
+    caller: Principal,
+    from_subaccount: Option<Subaccount>,
+    stablecoin: StablecoinType,
+) -> Result<u64, TransferFromError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: read_state(|s| s.taler_ledger_principal),
+        ledger_canister_id: read_state(|s| s.get_stablecoin_ledger_principal(stablecoin)),
     };
     let protocol_id = ic_cdk::id();
     let block_index = client
@@ -146,7 +186,7 @@ pub async fn transfer_tal_from(amount: TAL, caller: Principal) -> Result<u64, Tr
             spender_subaccount: None,
             from: Account {
                 owner: caller,
-                subaccount: None,
+                subaccount: from_subaccount,
             },
             to: Account {
                 owner: protocol_id,
@@ -165,22 +205,24 @@ pub async fn transfer_tal_from(amount: TAL, caller: Principal) -> Result<u64, Tr
     Ok(block_index)
 }
 
-pub async fn transfer_ckbtc_from(
+pub async fn transfer_collateral_from(
     amount: CKBTC,
     caller: Principal,
+    from_subaccount: Option<Subaccount>,
+    collateral_type: CollateralType,
 ) -> Result<u64, TransferFromError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: read_state(|s| s.ckbtc_ledger_principal),
+        ledger_canister_id: read_state(|s| s.get_collateral_ledger_principal(collateral_type)),
     };
     let protocol_id = ic_cdk::id();
-    let ckbtc_transfer_fee = read_state(|s| s.ckbtc_ledger_fee);
+    let ckbtc_transfer_fee = read_state(|s| s.get_collateral_ledger_fee(collateral_type));
     let block_index = client
         .transfer_from(TransferFromArgs {
             spender_subaccount: None,
             from: Account {
                 owner: caller,
-                subaccount: None,
+                subaccount: from_subaccount,
             },
             to: Account {
                 owner: protocol_id,
@@ -199,19 +241,20 @@ pub async fn transfer_ckbtc_from(
     Ok(block_index)
 }
 
-pub async fn transfer_ckbtc(amount: CKBTC, to: Principal) -> Result<u64, TransferError> {
+pub async fn transfer_collateral(
+    amount: CKBTC,
+    to: Account,
+    collateral_type: CollateralType,
+) -> Result<u64, TransferError> {
     let client = ICRC1Client {
         runtime: CdkRuntime,
-        ledger_canister_id: crate::state::read_state(|s| s.ckbtc_ledger_principal),
+        ledger_canister_id: read_state(|s| s.get_collateral_ledger_principal(collateral_type)),
     };
-    let ckbtc_transfer_fee = read_state(|s| s.ckbtc_ledger_fee);
+    let ckbtc_transfer_fee = read_state(|s| s.get_collateral_ledger_fee(collateral_type));
     let block_index = client
         .transfer(TransferArg {
             from_subaccount: None,
-            to: Account {
-                owner: to,
-                subaccount: None,
-            },
+            to,
             fee: Some(ckbtc_transfer_fee.to_nat()),
             created_at_time: None,
             memo: None,
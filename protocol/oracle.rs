@@ -0,0 +1,284 @@
+use crate::logs::{DEBUG, TRACE_XRC};
+use crate::numeric::{Ratio, UsdBtc};
+use crate::state::{mutate_state, read_state};
+use crate::{ProtocolError, SEC_NANOS};
+use candid::{CandidType, Deserialize, Principal};
+use ic_canister_log::log;
+use ic_xrc_types::{ExchangeRate, GetExchangeRateResult};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+
+/// Pushed prices older than this are not used.
+pub const MAX_PUSHED_PRICE_AGE_NANOS: u64 = 5 * 60 * SEC_NANOS;
+/// Default maximum distance between a sample and the median of all samples.
+pub const DEFAULT_MAX_PRICE_DEVIATION: Ratio = Ratio::new(dec!(0.05));
+
+/// A source of the BTC/USD price.
+#[derive(
+    CandidType, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum PriceSource {
+    /// An exchange rate canister.
+    Xrc(Principal),
+    /// A principal allowed to push prices to the protocol. Only the caller is
+    /// authenticated, the prices are not signed by their data provider.
+    Pushed(Principal),
+    /// A canister implementing the XRC interface, queried when no other source answers.
+    Fallback(Principal),
+}
+
+/// Which BTC price a check uses.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PriceKind {
+    /// The last price agreed on by the sources.
+    Spot,
+    /// The time-weighted average of the last prices.
+    Twap,
+}
+
+/// Whether the BTC price can be used, and why not.
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PriceStatus {
+    /// The BTC price is recent enough to be used.
+    Fresh,
+    /// The BTC price is too old because the exchange rate canisters answered with too
+    /// few sources or too much spread between them.
+    LowConfidence,
+    /// The BTC price is too old or was never fetched.
+    Stale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PriceSample {
+    pub source: PriceSource,
+    pub rate: UsdBtc,
+    /// Timestamp of the price: nanoseconds.
+    pub timestamp: u64,
+}
+
+/// Price agreed on by the sources, timestamped by the newest sample it is made of.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregatedPrice {
+    pub rate: UsdBtc,
+    pub timestamp: u64,
+    pub sources: Vec<PriceSource>,
+}
+
+fn median(samples: &[PriceSample]) -> Option<UsdBtc> {
+    let mut rates: Vec<UsdBtc> = samples.iter().map(|sample| sample.rate).collect();
+    rates.sort();
+    let middle = rates.len() / 2;
+    match rates.len() {
+        0 => None,
+        len if len % 2 == 1 => Some(rates[middle]),
+        _ => Some(UsdBtc::from(
+            (rates[middle - 1].0 + rates[middle].0) / dec!(2),
+        )),
+    }
+}
+
+/// Takes the median of the samples, drops the samples further than `max_deviation` from
+/// it and returns the median of the rest. Returns None if no sample is left.
+pub fn aggregate(samples: &[PriceSample], max_deviation: Ratio) -> Option<AggregatedPrice> {
+    let first_median = median(samples)?;
+    let accepted: Vec<PriceSample> = samples
+        .iter()
+        .filter(|sample| (sample.rate.0 - first_median.0).abs() <= first_median.0 * max_deviation.0)
+        .cloned()
+        .collect();
+    Some(AggregatedPrice {
+        rate: median(&accepted)?,
+        timestamp: accepted.iter().map(|sample| sample.timestamp).max()?,
+        sources: accepted.iter().map(|sample| sample.source).collect(),
+    })
+}
+
+/// Checks that an XRC rate was computed from at least `min_received_rates` base asset
+/// rates, with a standard deviation of at most `max_standard_deviation` of the rate.
+/// Zero disables a threshold.
+pub fn check_xrc_confidence(
+    exchange_rate: &ExchangeRate,
+    min_received_rates: u64,
+    max_standard_deviation: Ratio,
+) -> Result<(), String> {
+    let metadata = &exchange_rate.metadata;
+    let received_rates = metadata.base_asset_num_received_rates as u64;
+    if received_rates < min_received_rates {
+        return Err(format!(
+            "{received_rates} rates received out of {} sources, expected at least {min_received_rates}",
+            metadata.base_asset_num_queried_sources
+        ));
+    }
+    if max_standard_deviation > Ratio::from(Decimal::ZERO) && exchange_rate.rate > 0 {
+        let standard_deviation = Ratio::from(
+            Decimal::from_u64(metadata.standard_deviation).unwrap()
+                / Decimal::from_u64(exchange_rate.rate).unwrap(),
+        );
+        if standard_deviation > max_standard_deviation {
+            return Err(format!(
+                "standard deviation of {standard_deviation} of the rate, expected at most {max_standard_deviation}"
+            ));
+        }
+    }
+    Ok(())
+}
+
+enum SampleError {
+    LowConfidence,
+    Failed,
+}
+
+async fn fetch_xrc_sample(
+    source: PriceSource,
+    principal: Principal,
+) -> Result<PriceSample, SampleError> {
+    match crate::management::fetch_btc_price(principal).await {
+        Ok(GetExchangeRateResult::Ok(exchange_rate_result)) => {
+            let rate = Decimal::from_u64(exchange_rate_result.rate).unwrap()
+                / Decimal::from_u64(10_u64.pow(exchange_rate_result.metadata.decimals)).unwrap();
+            let (min_received_rates, max_standard_deviation) =
+                read_state(|s| (s.min_xrc_received_rates, s.max_xrc_standard_deviation));
+            if let Err(reason) = check_xrc_confidence(
+                &exchange_rate_result,
+                min_received_rates,
+                max_standard_deviation,
+            ) {
+                log!(
+                    TRACE_XRC,
+                    "[FetchPrice] rejected low-confidence btc rate {rate} from {source:?}: {reason}"
+                );
+                mutate_state(|s| s.rejected_xrc_rates_count += 1);
+                return Err(SampleError::LowConfidence);
+            }
+            log!(
+                TRACE_XRC,
+                "[FetchPrice] {source:?} returned btc rate: {rate} with timestamp: {}",
+                exchange_rate_result.timestamp
+            );
+            Ok(PriceSample {
+                source,
+                rate: UsdBtc::from(rate),
+                timestamp: exchange_rate_result.timestamp * SEC_NANOS,
+            })
+        }
+        Ok(GetExchangeRateResult::Err(error)) => {
+            log!(
+                TRACE_XRC,
+                "[FetchPrice] {source:?} failed to return btc rate with error: {error:?}"
+            );
+            Err(SampleError::Failed)
+        }
+        Err(error) => {
+            log!(
+                TRACE_XRC,
+                "[FetchPrice] failed to call {source:?} with error: {error}"
+            );
+            Err(SampleError::Failed)
+        }
+    }
+}
+
+/// Queries every exchange rate canister and collects the fresh pushed prices, the
+/// fallback canister is only queried if none of them yields a price.
+pub async fn fetch_btc_price() -> Option<AggregatedPrice> {
+    let (xrc_principals, fallback_principal, max_deviation) = read_state(|s| {
+        let mut xrc_principals = vec![s.xrc_principal];
+        xrc_principals.extend(s.oracle_xrc_principals.iter().cloned());
+        (
+            xrc_principals,
+            s.fallback_oracle_principal,
+            s.max_price_deviation,
+        )
+    });
+
+    let mut samples = vec![];
+    let mut low_confidence = false;
+    for principal in xrc_principals {
+        match fetch_xrc_sample(PriceSource::Xrc(principal), principal).await {
+            Ok(sample) => samples.push(sample),
+            Err(SampleError::LowConfidence) => low_confidence = true,
+            Err(SampleError::Failed) => (),
+        }
+    }
+    let now = ic_cdk::api::time();
+    samples.extend(read_state(|s| {
+        s.pushed_prices
+            .iter()
+            .filter(|(_pusher, (_rate, timestamp))| {
+                now.saturating_sub(*timestamp) <= MAX_PUSHED_PRICE_AGE_NANOS
+            })
+            .map(|(pusher, (rate, timestamp))| PriceSample {
+                source: PriceSource::Pushed(*pusher),
+                rate: *rate,
+                timestamp: *timestamp,
+            })
+            .collect::<Vec<PriceSample>>()
+    }));
+    if samples.is_empty() {
+        if let Some(principal) = fallback_principal {
+            match fetch_xrc_sample(PriceSource::Fallback(principal), principal).await {
+                Ok(sample) => samples.push(sample),
+                Err(SampleError::LowConfidence) => low_confidence = true,
+                Err(SampleError::Failed) => (),
+            }
+        }
+    }
+
+    let price = aggregate(&samples, max_deviation);
+    match &price {
+        Some(price) => {
+            for sample in samples.iter() {
+                if !price.sources.contains(&sample.source) {
+                    log!(
+                        TRACE_XRC,
+                        "[FetchPrice] rejected btc rate {} from {:?}, too far from {}",
+                        sample.rate,
+                        sample.source,
+                        price.rate
+                    );
+                }
+            }
+        }
+        None => log!(
+            TRACE_XRC,
+            "[FetchPrice] no agreement on the btc rate among {} samples",
+            samples.len()
+        ),
+    }
+    mutate_state(|s| s.btc_price_low_confidence = price.is_none() && low_confidence);
+    price
+}
+
+/// Stores a BTC/USD price pushed by one of the price pushers. The pusher is trusted as
+/// the source of the price, no signature of the data provider is checked.
+/// The price only lands in the transient `pushed_prices`; it reaches the event log as
+/// one of the sources of the next `BtcPriceUpdate` event recorded by a fetch.
+pub fn push_btc_price(rate_e8s: u64, timestamp: u64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::caller();
+    if !read_state(|s| s.price_pushers.contains(&caller)) {
+        return Err(ProtocolError::GenericError(
+            "caller is not a price pusher".to_string(),
+        ));
+    }
+    let now = ic_cdk::api::time();
+    if timestamp > now || now - timestamp > MAX_PUSHED_PRICE_AGE_NANOS {
+        return Err(ProtocolError::GenericError(format!(
+            "price timestamp {timestamp} is not within the last {} seconds",
+            MAX_PUSHED_PRICE_AGE_NANOS / SEC_NANOS
+        )));
+    }
+    if rate_e8s == 0 {
+        return Err(ProtocolError::GenericError(
+            "price cannot be zero".to_string(),
+        ));
+    }
+    let rate = UsdBtc::from(Decimal::from_u64(rate_e8s).unwrap() / dec!(100_000_000));
+    log!(
+        DEBUG,
+        "[push_btc_price] {caller} pushed btc rate: {rate} with timestamp: {timestamp}"
+    );
+    mutate_state(|s| s.pushed_prices.insert(caller, (rate, timestamp)));
+    Ok(())
+}
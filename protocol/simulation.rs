@@ -0,0 +1,153 @@
+use crate::collateral::CollateralType;
+use crate::numeric::UsdBtc;
+use crate::stablecoin::StablecoinType;
+use crate::state::{read_state, Mode, State};
+use crate::{compute_collateral_ratio, liquidation_action, partition_vaults, LiquidationAction};
+use candid::{CandidType, Deserialize, Principal};
+use std::collections::BTreeMap;
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SimulatedVault {
+    pub vault_id: u64,
+    pub owner: Principal,
+    pub collateral_ratio: f64,
+    pub action: LiquidationAction,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LiquidityDebit {
+    pub provider: Principal,
+    pub stablecoin: StablecoinType,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct LiquidityReward {
+    pub provider: Principal,
+    pub collateral_type: CollateralType,
+    pub amount: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct PriceSimulation {
+    pub mode: Mode,
+    pub total_collateral_ratio: f64,
+    pub vaults: Vec<SimulatedVault>,
+    pub liquidity_debits: Vec<LiquidityDebit>,
+    pub liquidity_rewards: Vec<LiquidityReward>,
+}
+
+/// Runs the vault checks against a copy of the state with the given BTC rate, as the
+/// next price fetch would, and reports what would happen without mutating anything.
+pub fn simulate_price(btc_rate: UsdBtc) -> PriceSimulation {
+    let mut state = read_state(|s| s.clone());
+    // Rewards are reported before they are compounded.
+    state.compounding_preferences.clear();
+    let stablecoins = state.stablecoin_types();
+    let liquidity_before = liquidity_pools(&state, &stablecoins);
+    let returns_before = state.all_liquidity_returns();
+
+    // The simulated rate is taken as the next sample, updating the TWAP and the breaker.
+    let timestamp = ic_cdk::api::time().max(state.last_btc_timestamp.unwrap_or(0) + 1);
+    state.set_btc_rate(btc_rate, timestamp);
+    state.update_total_collateral_ratio_and_mode(btc_rate);
+
+    let mut vaults = vec![];
+    if state.mode != Mode::ReadOnly && !state.liquidations_frozen {
+        let (unhealthy_vaults, healthy_vaults) = partition_vaults(&state);
+        for (vault, collateral_rate) in unhealthy_vaults {
+            let action = liquidation_action(&state, &vault, &healthy_vaults);
+            match action {
+                LiquidationAction::Liquidate => {
+                    let penalty = state.compute_liquidation_penalty(
+                        vault.vault_id,
+                        state.mode,
+                        collateral_rate,
+                    );
+                    state.liquidate_vault(
+                        vault.vault_id,
+                        state.mode,
+                        collateral_rate,
+                        None,
+                        penalty,
+                    )
+                }
+                LiquidationAction::StartAuction => {
+                    state.start_auction(vault.vault_id, collateral_rate, ic_cdk::api::time())
+                }
+                LiquidationAction::Redistribute => state.redistribute_vault(vault.vault_id),
+                LiquidationAction::Retry => {}
+                LiquidationAction::SwitchToReadOnly => state.mode = Mode::ReadOnly,
+            }
+            vaults.push(SimulatedVault {
+                vault_id: vault.vault_id,
+                owner: vault.owner,
+                collateral_ratio: compute_collateral_ratio(&vault, collateral_rate).to_f64(),
+                action,
+            });
+        }
+    }
+
+    let liquidity_after = liquidity_pools(&state, &stablecoins);
+    let liquidity_debits = liquidity_before
+        .iter()
+        .filter_map(|(&(stablecoin, provider), &before)| {
+            let after = liquidity_after
+                .get(&(stablecoin, provider))
+                .copied()
+                .unwrap_or(0);
+            (before > after).then(|| LiquidityDebit {
+                provider,
+                stablecoin,
+                amount: before - after,
+            })
+        })
+        .collect();
+    let liquidity_rewards = state
+        .all_liquidity_returns()
+        .iter()
+        .flat_map(|(provider, returns)| {
+            returns.iter().map(|(collateral_type, after)| {
+                let before = returns_before
+                    .get(provider)
+                    .and_then(|returns| returns.get(collateral_type))
+                    .map(|before| before.to_u64())
+                    .unwrap_or(0);
+                (
+                    *provider,
+                    *collateral_type,
+                    after.to_u64().saturating_sub(before),
+                )
+            })
+        })
+        .filter(|(_, _, amount)| *amount > 0)
+        .map(|(provider, collateral_type, amount)| LiquidityReward {
+            provider,
+            collateral_type,
+            amount,
+        })
+        .collect();
+
+    PriceSimulation {
+        mode: state.mode,
+        total_collateral_ratio: state.compute_total_collateral_ratio(btc_rate).to_f64(),
+        vaults,
+        liquidity_debits,
+        liquidity_rewards,
+    }
+}
+
+fn liquidity_pools(
+    state: &State,
+    stablecoins: &[StablecoinType],
+) -> BTreeMap<(StablecoinType, Principal), u64> {
+    stablecoins
+        .iter()
+        .flat_map(|stablecoin| {
+            state
+                .liquidity_pool_of(*stablecoin)
+                .deposits()
+                .map(|(provider, amount)| ((*stablecoin, provider), amount.to_u64()))
+        })
+        .collect()
+}
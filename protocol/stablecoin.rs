@@ -0,0 +1,120 @@
+use crate::liquidity_pool::StabilityPool;
+use crate::numeric::Ratio;
+use crate::ProtocolError;
+use candid::{CandidType, Deserialize, Principal};
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
+use serde::Serialize;
+use std::fmt;
+
+/// Peg asset of stablecoins that do not need a peg rate.
+pub const USD_PEG: &str = "USD";
+
+/// Identifies the stablecoin borrowed by a vault.
+#[derive(
+    CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize,
+)]
+pub enum StablecoinType {
+    /// The TAL ledger configured at init, vaults opened before
+    /// multi-stablecoin support all borrow TAL.
+    #[default]
+    Tal,
+    /// Any other stablecoin minted by the protocol, identified by its ledger principal.
+    Icrc(Principal),
+}
+
+impl fmt::Display for StablecoinType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StablecoinType::Tal => write!(f, "TAL"),
+            StablecoinType::Icrc(ledger_principal) => write!(f, "ICRC({ledger_principal})"),
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddStablecoinArg {
+    /// Ledger of the stablecoin, the protocol must be its minting account.
+    pub ledger_principal: Principal,
+    pub symbol: String,
+    /// Fiat currency the stablecoin is pegged to on the exchange rate canister, e.g. "EUR".
+    pub peg_symbol: String,
+    pub borrowing_fee_e8s: u64,
+}
+
+/// Configuration and liquidity pool of a stablecoin registered on top of TAL.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StablecoinConfig {
+    pub symbol: String,
+    pub peg_symbol: String,
+    /// The fee charged when borrowing this stablecoin.
+    pub borrowing_fee: Ratio,
+    pub liquidity_pool: StabilityPool,
+    /// Last USD price of one unit of the peg asset.
+    pub last_peg_rate: Option<Ratio>,
+    /// Last timestamp of the fetched peg rate.
+    pub last_peg_timestamp: Option<u64>,
+}
+
+impl From<AddStablecoinArg> for StablecoinConfig {
+    fn from(arg: AddStablecoinArg) -> Self {
+        let last_peg_rate = if arg.peg_symbol == USD_PEG {
+            Some(Ratio::from(dec!(1)))
+        } else {
+            None
+        };
+        Self {
+            symbol: arg.symbol,
+            peg_symbol: arg.peg_symbol,
+            borrowing_fee: Ratio::from(
+                Decimal::from_u64(arg.borrowing_fee_e8s).unwrap() / dec!(100_000_000),
+            ),
+            liquidity_pool: StabilityPool::default(),
+            last_peg_rate,
+            last_peg_timestamp: None,
+        }
+    }
+}
+
+impl StablecoinConfig {
+    pub fn is_usd_pegged(&self) -> bool {
+        self.peg_symbol == USD_PEG
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct StablecoinStatus {
+    pub stablecoin: StablecoinType,
+    pub ledger_principal: Principal,
+    pub symbol: String,
+    pub peg_symbol: String,
+    pub borrowing_fee: f64,
+    pub total_borrowed: u64,
+    pub total_liquidity_provided: u64,
+    pub last_peg_rate: f64,
+    pub last_peg_timestamp: u64,
+}
+
+pub fn validate_add_stablecoin_arg(
+    arg: &AddStablecoinArg,
+    taler_ledger_principal: Principal,
+) -> Result<(), ProtocolError> {
+    if arg.symbol.is_empty() || arg.peg_symbol.is_empty() {
+        return Err(ProtocolError::GenericError(
+            "stablecoin symbol and peg symbol cannot be empty".to_string(),
+        ));
+    }
+    if arg.borrowing_fee_e8s > 100_000_000 {
+        return Err(ProtocolError::GenericError(format!(
+            "borrowing fee cannot be above 100%, got {} e8s",
+            arg.borrowing_fee_e8s
+        )));
+    }
+    if arg.ledger_principal == taler_ledger_principal {
+        return Err(ProtocolError::GenericError(
+            "TAL is already the default stablecoin".to_string(),
+        ));
+    }
+    Ok(())
+}
@@ -1,11 +1,20 @@
+use crate::auction::Auction;
+use crate::collateral::{AddCollateralTypeArg, CollateralConfig, CollateralType};
+use crate::liquidity_pool::{
+    CompoundingPreference, PoolGains, StabilityPool, WithdrawalRequest, MAX_ROUNDING_DUST,
+};
 use crate::numeric::{Ratio, UsdBtc, CKBTC, TAL};
-use crate::vault::Vault;
+use crate::oracle::{PriceKind, PriceStatus, DEFAULT_MAX_PRICE_DEVIATION};
+use crate::stablecoin::{AddStablecoinArg, StablecoinConfig, StablecoinType};
+use crate::vault::{OperatorPermissions, Vault};
 use crate::{
-    compute_collateral_ratio, InitArg, ProtocolError, UpgradeArg, MINIMUM_COLLATERAL_RATIO,
-    RECOVERY_COLLATERAL_RATIO,
+    compute_collateral_ratio, InitArg, ProtocolError, UpgradeArg, DEFAULT_LIQUIDATION_PENALTY,
+    DEFAULT_WITHDRAWAL_WINDOW_SECS, MAX_REDEEMED_VAULTS, MINIMUM_COLLATERAL_RATIO,
+    MIN_CKBTC_AMOUNT, RECOVERY_COLLATERAL_RATIO, SEC_NANOS,
 };
 use candid::Principal;
 use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::Account;
 use rust_decimal::prelude::FromPrimitive;
 use rust_decimal::Decimal;
 use rust_decimal_macros::dec;
@@ -13,7 +22,7 @@ use serde::Serialize;
 use std::cell::RefCell;
 use std::cmp::max;
 use std::collections::btree_map::Entry::{Occupied, Vacant};
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 use std::fmt;
 
 // Like assert_eq, but returns an error instead of panicking.
@@ -90,8 +99,29 @@ pub type VaultId = u64;
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize, Copy)]
 pub struct PendingMarginTransfer {
-    pub owner: Principal,
+    /// Account receiving the margin.
+    pub to: Account,
     pub margin: CKBTC,
+    pub collateral_type: CollateralType,
+}
+
+/// How a liquidation splits the margin of a vault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LiquidationSplit {
+    /// Margin taken out of the vault, the keeper reward included.
+    pub seized_margin: CKBTC,
+    /// Part of the seized margin paid to the keeper.
+    pub keeper_reward: CKBTC,
+    /// Margin left in the vault for its owner.
+    pub remaining_margin: CKBTC,
+}
+
+/// Stablecoin owed back to an account, queued when minting it failed.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, Serialize, Copy)]
+pub struct PendingStablecoinTransfer {
+    pub to: Account,
+    pub amount: TAL,
+    pub stablecoin: StablecoinType,
 }
 
 thread_local! {
@@ -100,18 +130,66 @@ thread_local! {
 
 pub const DEFAULT_BORROW_FEE: Ratio = Ratio::new(dec!(0.005));
 
+/// Minimum delay between two stability fee accruals.
+pub const STABILITY_FEE_ACCRUAL_INTERVAL_NANOS: u64 = 60 * 60 * crate::SEC_NANOS;
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+/// Age after which the BTC price, or any collateral or peg price, is too old to be used.
+pub const MAX_BTC_PRICE_AGE_NANOS: u64 = 10 * 60 * crate::SEC_NANOS;
+/// A fetched BTC price is recorded when it moved by at least this much since the last
+/// recorded price.
+pub const MIN_RECORDED_BTC_PRICE_CHANGE: Ratio = Ratio::new(dec!(0.005));
+/// A fetched BTC price is recorded when the last recorded price is at least this old,
+/// well within [MAX_BTC_PRICE_AGE_NANOS].
+pub const BTC_PRICE_HEARTBEAT_NANOS: u64 = 5 * 60 * crate::SEC_NANOS;
+
+#[derive(Clone)]
 pub struct State {
     /// Maps vault id to vault.
     pub vault_id_to_vaults: BTreeMap<u64, Vault>,
     /// Maps vault owner to vault ids.
     pub principal_to_vault_ids: BTreeMap<Principal, BTreeSet<u64>>,
-    /// Maps liquidity provider to provided TAL liquidity amount.
-    pub liquidity_pool: BTreeMap<Principal, TAL>,
-    /// Liquidity Pool retruns (in ckBTC)
-    pub liquidity_returns: BTreeMap<Principal, CKBTC>,
+    /// Maps vault id to the principals allowed to operate it on behalf of its owner.
+    pub vault_operators: BTreeMap<VaultId, BTreeMap<Principal, OperatorPermissions>>,
+    /// Liquidity pool of TAL, the pools of the other stablecoins live in their configuration.
+    pub liquidity_pool: StabilityPool,
+    /// Liquidity Pool retruns per collateral type, realized when the provider updates
+    /// its deposits. Returns not yet realized are tracked by the pools.
+    pub liquidity_returns: BTreeMap<Principal, BTreeMap<CollateralType, CKBTC>>,
+    /// Share of the borrowing and redemption fees per stablecoin, realized when the
+    /// provider updates its deposits.
+    pub fee_returns: BTreeMap<Principal, BTreeMap<StablecoinType, TAL>>,
+    /// Providers that opted into compounding their liquidity returns.
+    pub compounding_preferences: BTreeMap<Principal, CompoundingPreference>,
+    /// Seconds a provider must wait between requesting a withdrawal and withdrawing,
+    /// withdrawals are immediate when zero.
+    pub withdrawal_cooldown_secs: u64,
+    /// Seconds after the cooldown during which a withdrawal request can be used, it
+    /// lapses afterwards.
+    pub withdrawal_window_secs: u64,
+    pub withdrawal_requests: BTreeMap<(Principal, StablecoinType), WithdrawalRequest>,
+
+    /// TAL vaults with debt ordered by collateral type and margin per unit of debt, which
+    /// orders them by collateral ratio at any price. Redemptions start from the lowest.
+    pub redemption_index: BTreeSet<(CollateralType, Ratio, VaultId)>,
+    /// Key and debt of every vault in the redemption index, see [State::reindex_vault].
+    pub redemption_index_entries: BTreeMap<VaultId, (CollateralType, Ratio, TAL)>,
+    /// Debt in the redemption index per collateral type, to be scaled by `stability_fee_index`.
+    pub redeemable_debt: BTreeMap<CollateralType, TAL>,
 
     pub pending_margin_transfers: BTreeMap<VaultId, PendingMarginTransfer>,
     pub pending_redemption_transfer: BTreeMap<u64, PendingMarginTransfer>,
+    /// Whether vaults the liquidity pool cannot cover are auctioned instead of redistributed.
+    pub auctions_enabled: bool,
+    pub auctions: BTreeMap<VaultId, Auction>,
+    /// Debt left uncovered by the auctions, per stablecoin.
+    pub bad_debt: BTreeMap<StablecoinType, TAL>,
+    /// Collateral bought in auctions, auction surpluses, liquidation rewards and claims of
+    /// liquidity returns whose transfer failed, keyed by transfer id.
+    pub pending_collateral_transfers: BTreeMap<u64, PendingMarginTransfer>,
+    pub next_collateral_transfer_id: u64,
+    /// Refunds of stablecoin whose mint failed, keyed by transfer id.
+    pub pending_stablecoin_transfers: BTreeMap<u64, PendingStablecoinTransfer>,
+    pub next_stablecoin_transfer_id: u64,
     pub last_redemption_time: u64,
     pub current_base_rate: Ratio,
     /// The mode in which the protocol runs.
@@ -119,6 +197,22 @@ pub struct State {
 
     /// The fee charged when borrowing: e8s.
     pub fee: Ratio,
+    /// Share of the borrowing and redemption fees paid to the liquidity providers, the
+    /// rest goes to the developer.
+    pub lp_fee_share: Ratio,
+    /// Yearly stability fee, compounded every second on borrowed amounts.
+    pub stability_fee_rate: Ratio,
+    /// Share of the liquidated margin paid to whoever triggers a liquidation.
+    pub liquidation_reward_rate: Ratio,
+    /// Margin seized on top of the value of the debt of a liquidated vault, as a share of it.
+    pub liquidation_penalty: Ratio,
+    /// Timestamp up to which the stability fee has been accrued.
+    pub last_stability_fee_accrual: Option<u64>,
+    /// Growth of the borrowed amounts due to the stability fee since the first accrual.
+    pub stability_fee_index: Ratio,
+    /// Value of `stability_fee_index` up to which the stability fee is included in the
+    /// debt of each vault, see [State::settle_stability_fee].
+    pub vault_fee_indexes: BTreeMap<VaultId, Ratio>,
 
     pub developer_principal: Principal,
 
@@ -131,16 +225,62 @@ pub struct State {
     pub xrc_principal: Principal,
     /// Pincipal of the TAL ledger canister.
     pub taler_ledger_principal: Principal,
+    /// Stablecoins minted on top of TAL, keyed by ledger principal.
+    pub stablecoins: BTreeMap<Principal, StablecoinConfig>,
     /// Principal of the ckBTC ledger canister.
     pub ckbtc_ledger_principal: Principal,
     pub ckbtc_ledger_fee: CKBTC,
-    /// Last Bitcoin rate fetched from XRC.
+    /// Collateral types accepted on top of ckBTC, keyed by ledger principal.
+    pub collaterals: BTreeMap<Principal, CollateralConfig>,
+    /// Exchange rate canisters queried for the Bitcoin rate next to `xrc_principal`.
+    pub oracle_xrc_principals: Vec<Principal>,
+    /// Canister implementing the XRC interface, queried when no other source answers.
+    pub fallback_oracle_principal: Option<Principal>,
+    /// Principals allowed to push Bitcoin rates.
+    pub price_pushers: BTreeSet<Principal>,
+    /// Last rate and timestamp pushed by each price pusher. Transient: pushes are not
+    /// recorded as events, only the prices they feed into are, so this map starts empty
+    /// after an upgrade and is left out of [State::check_semantically_eq].
+    pub pushed_prices: BTreeMap<Principal, (UsdBtc, u64)>,
+    /// Maximum distance between a source and the median of the sources, the sources
+    /// further away are ignored.
+    pub max_price_deviation: Ratio,
+    /// Minimum number of rates an XRC answer must be computed from, no minimum when zero.
+    pub min_xrc_received_rates: u64,
+    /// Maximum standard deviation of an XRC answer relative to its rate, no maximum
+    /// when zero.
+    pub max_xrc_standard_deviation: Ratio,
+    /// Number of XRC answers rejected for their low confidence. Not event-sourced: the
+    /// metric restarts from zero after an upgrade.
+    pub rejected_xrc_rates_count: u64,
+    /// Whether the last fetch found no Bitcoin rate because of low-confidence answers.
+    /// Not event-sourced: it is false after an upgrade until the next fetch sets it.
+    pub btc_price_low_confidence: bool,
+    /// Last Bitcoin rate agreed on by the price sources.
     pub last_btc_rate: Option<UsdBtc>,
     /// Last timestamp of fetch Bitcoin rate.
     pub last_btc_timestamp: Option<u64>,
+    /// Timestamps and Bitcoin rates covering the TWAP window, oldest first.
+    pub btc_price_samples: VecDeque<(u64, UsdBtc)>,
+    /// Seconds the Bitcoin TWAP is averaged over, the TWAP is the spot rate when zero.
+    pub twap_window_secs: u64,
+    /// Maximum change of the Bitcoin rate between two samples before liquidations are
+    /// frozen, no limit when zero.
+    pub max_btc_price_change: Ratio,
+    /// Whether the last Bitcoin rate moved more than `max_btc_price_change`.
+    pub liquidations_frozen: bool,
+    /// Bitcoin rate used to check borrows and margin withdrawals.
+    pub borrow_price_kind: PriceKind,
+    /// Bitcoin rate used to find and liquidate unhealthy vaults.
+    pub liquidation_price_kind: PriceKind,
+    /// Maximum USD value of the debt of all the vaults.
+    pub global_debt_ceiling: Option<TAL>,
+    /// Maximum USD value of the debt of the vaults of a single principal.
+    pub principal_debt_ceiling: Option<TAL>,
 
     /// Guards
     pub principal_guards: BTreeSet<Principal>,
+    pub auction_guards: BTreeSet<VaultId>,
     pub is_timer_running: bool,
     pub is_fetching_rate: bool,
 }
@@ -148,37 +288,83 @@ pub struct State {
 impl From<InitArg> for State {
     fn from(args: InitArg) -> Self {
         let fee = Decimal::from_u64(args.fee_e8s).unwrap() / dec!(100_000_000);
-        Self {
+        let mut state = Self {
             last_redemption_time: 0,
             current_base_rate: Ratio::from(Decimal::ZERO),
             fee: Ratio::from(fee),
+            lp_fee_share: Ratio::from(Decimal::ZERO),
+            stability_fee_rate: Ratio::from(Decimal::ZERO),
+            liquidation_reward_rate: Ratio::from(Decimal::ZERO),
+            liquidation_penalty: DEFAULT_LIQUIDATION_PENALTY,
+            last_stability_fee_accrual: None,
+            stability_fee_index: Ratio::from(dec!(1)),
+            vault_fee_indexes: BTreeMap::new(),
             developer_principal: args.developer_principal,
             principal_to_vault_ids: BTreeMap::new(),
+            vault_operators: BTreeMap::new(),
             pending_redemption_transfer: BTreeMap::new(),
             vault_id_to_vaults: BTreeMap::new(),
             xrc_principal: args.xrc_principal,
             taler_ledger_principal: args.taler_ledger_principal,
+            stablecoins: BTreeMap::new(),
             ckbtc_ledger_principal: args.ckbtc_ledger_principal,
             ckbtc_ledger_fee: CKBTC_TRANSFER_FEE,
+            collaterals: BTreeMap::new(),
             mode: Mode::GeneralAvailability,
             total_collateral_ratio: Ratio::from(Decimal::MAX),
             last_btc_timestamp: None,
             last_btc_rate: None,
+            btc_price_samples: VecDeque::new(),
+            twap_window_secs: 0,
+            max_btc_price_change: Ratio::from(Decimal::ZERO),
+            liquidations_frozen: false,
+            borrow_price_kind: PriceKind::Spot,
+            liquidation_price_kind: PriceKind::Twap,
+            oracle_xrc_principals: vec![],
+            fallback_oracle_principal: None,
+            price_pushers: BTreeSet::new(),
+            pushed_prices: BTreeMap::new(),
+            max_price_deviation: DEFAULT_MAX_PRICE_DEVIATION,
+            min_xrc_received_rates: 0,
+            max_xrc_standard_deviation: Ratio::from(Decimal::ZERO),
+            rejected_xrc_rates_count: 0,
+            btc_price_low_confidence: false,
+            global_debt_ceiling: args.global_debt_ceiling.map(TAL::from),
+            principal_debt_ceiling: args.principal_debt_ceiling.map(TAL::from),
             next_available_vault_id: 0,
-            liquidity_pool: BTreeMap::new(),
+            liquidity_pool: StabilityPool::default(),
             liquidity_returns: BTreeMap::new(),
+            fee_returns: BTreeMap::new(),
+            compounding_preferences: BTreeMap::new(),
+            withdrawal_cooldown_secs: 0,
+            withdrawal_window_secs: DEFAULT_WITHDRAWAL_WINDOW_SECS,
+            withdrawal_requests: BTreeMap::new(),
             principal_guards: BTreeSet::new(),
+            redemption_index: BTreeSet::new(),
+            redemption_index_entries: BTreeMap::new(),
+            redeemable_debt: BTreeMap::new(),
             pending_margin_transfers: BTreeMap::new(),
+            auctions_enabled: false,
+            auctions: BTreeMap::new(),
+            bad_debt: BTreeMap::new(),
+            pending_collateral_transfers: BTreeMap::new(),
+            next_collateral_transfer_id: 0,
+            pending_stablecoin_transfers: BTreeMap::new(),
+            next_stablecoin_transfer_id: 0,
+            auction_guards: BTreeSet::new(),
             is_timer_running: false,
             is_fetching_rate: false,
+        };
+        for stablecoin in args.stablecoins.unwrap_or_default() {
+            state.add_stablecoin(stablecoin);
         }
+        state
     }
 }
 
 impl State {
     pub fn check_price_not_too_old(&self) -> Result<(), ProtocolError> {
         let current_time = ic_cdk::api::time();
-        const TEN_MINS_NANOS: u64 = 10 * 60 * 1_000_000_000;
         let last_btc_timestamp = match self.last_btc_timestamp {
             Some(last_btc_timestamp) => last_btc_timestamp,
             None => {
@@ -187,11 +373,16 @@ impl State {
                 ))
             }
         };
-        if current_time.saturating_sub(last_btc_timestamp) > TEN_MINS_NANOS {
+        if !self.is_price_fresh(current_time) {
             log!(
                 crate::INFO,
                 "No recent price entry switching protocol to readonly mode, lastest: {}, current time: {current_time}", last_btc_timestamp
             );
+            if self.btc_price_low_confidence {
+                return Err(ProtocolError::TemporarilyUnavailable(
+                    "Last known BTC price too old, the exchange rate canister returned low-confidence prices".to_string(),
+                ));
+            }
             return Err(ProtocolError::TemporarilyUnavailable(
                 "Last known BTC price too old".to_string(),
             ));
@@ -199,6 +390,51 @@ impl State {
         Ok(())
     }
 
+    /// Whether the last BTC price can still be used at `now`.
+    pub fn is_price_fresh(&self, now: u64) -> bool {
+        self.last_btc_timestamp
+            .map(|timestamp| now.saturating_sub(timestamp) <= MAX_BTC_PRICE_AGE_NANOS)
+            .unwrap_or(false)
+    }
+
+    pub fn btc_price_status(&self) -> PriceStatus {
+        if self.is_price_fresh(ic_cdk::api::time()) {
+            PriceStatus::Fresh
+        } else if self.btc_price_low_confidence {
+            PriceStatus::LowConfidence
+        } else {
+            PriceStatus::Stale
+        }
+    }
+
+    pub fn check_collateral_price_not_too_old(
+        &self,
+        collateral_type: CollateralType,
+    ) -> Result<(), ProtocolError> {
+        let ledger_principal = match collateral_type {
+            CollateralType::CkBtc => return self.check_price_not_too_old(),
+            CollateralType::Icrc(ledger_principal) => ledger_principal,
+        };
+        check_rate_not_too_old(
+            self.collateral_config(ledger_principal).last_timestamp,
+            &collateral_type.to_string(),
+        )
+    }
+
+    pub fn check_peg_price_not_too_old(
+        &self,
+        stablecoin: StablecoinType,
+    ) -> Result<(), ProtocolError> {
+        let config = match stablecoin {
+            StablecoinType::Tal => return Ok(()),
+            StablecoinType::Icrc(ledger_principal) => self.stablecoin_config(ledger_principal),
+        };
+        if config.is_usd_pegged() {
+            return Ok(());
+        }
+        check_rate_not_too_old(config.last_peg_timestamp, &config.peg_symbol)
+    }
+
     pub fn increment_vault_id(&mut self) -> u64 {
         let vault_id = self.next_available_vault_id;
         self.next_available_vault_id += 1;
@@ -209,27 +445,671 @@ impl State {
         if let Some(mode) = args.mode {
             self.mode = mode;
         }
+        for stablecoin in args.stablecoins.unwrap_or_default() {
+            self.add_stablecoin(stablecoin);
+        }
+        if let Some(enable_auctions) = args.enable_auctions {
+            self.auctions_enabled = enable_auctions;
+        }
+        if args.clear_global_debt_ceiling == Some(true) {
+            self.global_debt_ceiling = None;
+        }
+        if args.clear_principal_debt_ceiling == Some(true) {
+            self.principal_debt_ceiling = None;
+        }
+        if let Some(global_debt_ceiling) = args.global_debt_ceiling {
+            self.global_debt_ceiling = Some(TAL::from(global_debt_ceiling));
+        }
+        if let Some(principal_debt_ceiling) = args.principal_debt_ceiling {
+            self.principal_debt_ceiling = Some(TAL::from(principal_debt_ceiling));
+        }
+        if let Some(stability_fee_rate_e8s) = args.stability_fee_rate_e8s {
+            self.stability_fee_rate =
+                Ratio::from(Decimal::from_u64(stability_fee_rate_e8s).unwrap() / dec!(100_000_000));
+        }
+        if let Some(liquidation_penalty_e8s) = args.liquidation_penalty_e8s {
+            self.liquidation_penalty = Ratio::from(
+                Decimal::from_u64(liquidation_penalty_e8s).unwrap() / dec!(100_000_000),
+            );
+        }
+        if let Some(liquidation_reward_e8s) = args.liquidation_reward_e8s {
+            self.liquidation_reward_rate =
+                Ratio::from(Decimal::from_u64(liquidation_reward_e8s).unwrap() / dec!(100_000_000));
+        }
+        if let Some(withdrawal_cooldown_secs) = args.withdrawal_cooldown_secs {
+            self.withdrawal_cooldown_secs = withdrawal_cooldown_secs;
+        }
+        if let Some(withdrawal_window_secs) = args.withdrawal_window_secs {
+            self.withdrawal_window_secs = withdrawal_window_secs;
+        }
+        if let Some(lp_fee_share_e8s) = args.lp_fee_share_e8s {
+            self.lp_fee_share =
+                Ratio::from(Decimal::from_u64(lp_fee_share_e8s).unwrap() / dec!(100_000_000));
+        }
+        if let Some(oracle_xrc_principals) = args.oracle_xrc_principals {
+            self.oracle_xrc_principals = oracle_xrc_principals;
+        }
+        if let Some(fallback_oracle_principal) = args.fallback_oracle_principal {
+            self.fallback_oracle_principal = Some(fallback_oracle_principal);
+        }
+        if let Some(price_pushers) = args.price_pushers {
+            self.price_pushers = price_pushers.into_iter().collect();
+            let price_pushers = &self.price_pushers;
+            self.pushed_prices
+                .retain(|pusher, _price| price_pushers.contains(pusher));
+        }
+        if let Some(max_price_deviation_e8s) = args.max_price_deviation_e8s {
+            self.max_price_deviation = Ratio::from(
+                Decimal::from_u64(max_price_deviation_e8s).unwrap() / dec!(100_000_000),
+            );
+        }
+        if let Some(min_xrc_received_rates) = args.min_xrc_received_rates {
+            self.min_xrc_received_rates = min_xrc_received_rates;
+        }
+        if let Some(max_xrc_standard_deviation_e8s) = args.max_xrc_standard_deviation_e8s {
+            self.max_xrc_standard_deviation = Ratio::from(
+                Decimal::from_u64(max_xrc_standard_deviation_e8s).unwrap() / dec!(100_000_000),
+            );
+        }
+        if let Some(twap_window_secs) = args.twap_window_secs {
+            self.twap_window_secs = twap_window_secs;
+        }
+        if let Some(max_btc_price_change_e8s) = args.max_btc_price_change_e8s {
+            self.max_btc_price_change = Ratio::from(
+                Decimal::from_u64(max_btc_price_change_e8s).unwrap() / dec!(100_000_000),
+            );
+            if self.max_btc_price_change == Ratio::from(Decimal::ZERO) {
+                self.liquidations_frozen = false;
+            }
+        }
+        if let Some(borrow_price_kind) = args.borrow_price_kind {
+            self.borrow_price_kind = borrow_price_kind;
+        }
+        if let Some(liquidation_price_kind) = args.liquidation_price_kind {
+            self.liquidation_price_kind = liquidation_price_kind;
+        }
+    }
+
+    /// Whether a fetched BTC price should be recorded, so that the event log only grows
+    /// on material price changes and heartbeats.
+    pub fn should_record_btc_price(&self, rate: UsdBtc, timestamp: u64) -> bool {
+        match (self.last_btc_rate, self.last_btc_timestamp) {
+            (Some(last_rate), Some(last_timestamp)) => {
+                timestamp > last_timestamp
+                    && (timestamp - last_timestamp >= BTC_PRICE_HEARTBEAT_NANOS
+                        || (rate.0 - last_rate.0).abs()
+                            >= last_rate.0 * MIN_RECORDED_BTC_PRICE_CHANGE.0)
+            }
+            _ => true,
+        }
+    }
+
+    /// Sets the Bitcoin rate, unless a more recent one is known. Liquidations are frozen
+    /// until the next rate if it moved more than `max_btc_price_change` since the last one.
+    pub fn set_btc_rate(&mut self, rate: UsdBtc, timestamp: u64) {
+        if self.last_btc_timestamp.unwrap_or(0) >= timestamp {
+            return;
+        }
+        if let Some(last_btc_rate) = self.last_btc_rate {
+            let change = Ratio::from((rate.0 - last_btc_rate.0).abs() / last_btc_rate.0);
+            self.liquidations_frozen = self.max_btc_price_change > Ratio::from(Decimal::ZERO)
+                && change > self.max_btc_price_change;
+        }
+        self.last_btc_rate = Some(rate);
+        self.last_btc_timestamp = Some(timestamp);
+
+        self.btc_price_samples.push_back((timestamp, rate));
+        // Keep the last sample taken before the window, it holds at its start.
+        let window_start = timestamp.saturating_sub(self.twap_window_secs * SEC_NANOS);
+        while self.btc_price_samples.len() > 1 && self.btc_price_samples[1].0 <= window_start {
+            self.btc_price_samples.pop_front();
+        }
+    }
+
+    /// Average of the Bitcoin rate over the last `twap_window_secs`, each sample weighted
+    /// by how long it held until the next one.
+    pub fn btc_twap(&self) -> Option<UsdBtc> {
+        let (last_timestamp, last_rate) = *self.btc_price_samples.back()?;
+        let window_start = last_timestamp.saturating_sub(self.twap_window_secs * SEC_NANOS);
+        let mut weighted_sum = Decimal::ZERO;
+        let mut total_weight: u64 = 0;
+        for ((timestamp, rate), (next_timestamp, _)) in self
+            .btc_price_samples
+            .iter()
+            .zip(self.btc_price_samples.iter().skip(1))
+        {
+            let weight = next_timestamp.saturating_sub(max(*timestamp, window_start));
+            weighted_sum += rate.0 * Decimal::from(weight);
+            total_weight += weight;
+        }
+        if total_weight == 0 {
+            return Some(last_rate);
+        }
+        Some(UsdBtc::from(weighted_sum / Decimal::from(total_weight)))
+    }
+
+    pub fn should_accrue_stability_fee(&self, now: u64) -> bool {
+        if self.stability_fee_rate == Ratio::from(Decimal::ZERO) {
+            return false;
+        }
+        match self.last_stability_fee_accrual {
+            Some(last_accrual) => {
+                now.saturating_sub(last_accrual) >= STABILITY_FEE_ACCRUAL_INTERVAL_NANOS
+            }
+            None => true,
+        }
+    }
+
+    /// Compounds the stability fee since the last accrual into `stability_fee_index`. The
+    /// debt of a vault grows with the index and is brought up to date when the vault is
+    /// next touched, see [State::settle_stability_fee].
+    pub fn accrue_stability_fee(&mut self, timestamp: u64) {
+        let last_accrual = match self.last_stability_fee_accrual {
+            Some(last_accrual) => last_accrual,
+            None => {
+                self.last_stability_fee_accrual = Some(timestamp);
+                return;
+            }
+        };
+        if timestamp <= last_accrual {
+            return;
+        }
+        let elapsed_secs = (timestamp - last_accrual) / crate::SEC_NANOS;
+        // Keep the sub-second remainder for the next accrual.
+        self.last_stability_fee_accrual = Some(last_accrual + elapsed_secs * crate::SEC_NANOS);
+        if self.stability_fee_rate == Ratio::from(Decimal::ZERO) || elapsed_secs == 0 {
+            return;
+        }
+
+        let rate_per_sec = self.stability_fee_rate.0 / Decimal::from_u64(SECONDS_PER_YEAR).unwrap();
+        let growth = compound(dec!(1) + rate_per_sec, elapsed_secs);
+        self.stability_fee_index = Ratio::from(self.stability_fee_index.0 * growth);
+    }
+
+    /// Debt of a vault of `vault_id_to_vaults` including the stability fee accrued since
+    /// it was last settled.
+    fn accrued_debt(&self, vault: &Vault) -> TAL {
+        match self.vault_fee_indexes.get(&vault.vault_id) {
+            Some(vault_index) if *vault_index != self.stability_fee_index => {
+                vault.borrowed_tal_amount * Ratio::from(self.stability_fee_index.0 / vault_index.0)
+            }
+            _ => vault.borrowed_tal_amount,
+        }
+    }
+
+    /// The vault, its debt including the stability fee accrued since it was last settled.
+    pub fn get_vault(&self, vault_id: VaultId) -> Option<Vault> {
+        self.vault_id_to_vaults.get(&vault_id).map(|vault| Vault {
+            borrowed_tal_amount: self.accrued_debt(vault),
+            ..vault.clone()
+        })
+    }
+
+    /// All the vaults, their debt including the accrued stability fee.
+    pub fn vaults(&self) -> impl Iterator<Item = Vault> + '_ {
+        self.vault_id_to_vaults.values().map(|vault| Vault {
+            borrowed_tal_amount: self.accrued_debt(vault),
+            ..vault.clone()
+        })
+    }
+
+    /// Adds the stability fee accrued since the last settlement to the debt of the vault
+    /// and credits it to the developer in the matching liquidity pool. Called before
+    /// every change to the debt of a vault.
+    fn settle_stability_fee(&mut self, vault_id: VaultId) {
+        let (debt, stablecoin) = match self.vault_id_to_vaults.get(&vault_id) {
+            Some(vault) => (self.accrued_debt(vault), vault.stablecoin),
+            None => return,
+        };
+        self.vault_fee_indexes
+            .insert(vault_id, self.stability_fee_index);
+        let vault = self.vault_id_to_vaults.get_mut(&vault_id).unwrap();
+        let accrued = debt - vault.borrowed_tal_amount;
+        vault.borrowed_tal_amount = debt;
+        if accrued > 0 {
+            self.provide_liquidity(accrued, self.developer_principal, stablecoin);
+        }
+    }
+
+    pub fn add_stablecoin(&mut self, arg: AddStablecoinArg) {
+        let ledger_principal = arg.ledger_principal;
+        let config = StablecoinConfig::from(arg);
+        match self.stablecoins.entry(ledger_principal) {
+            Occupied(mut entry) => {
+                // Updating the parameters of a known stablecoin keeps its pool.
+                let current = entry.get_mut();
+                let peg_changed = current.peg_symbol != config.peg_symbol;
+                *current = StablecoinConfig {
+                    liquidity_pool: std::mem::take(&mut current.liquidity_pool),
+                    last_peg_rate: if peg_changed {
+                        config.last_peg_rate
+                    } else {
+                        current.last_peg_rate
+                    },
+                    last_peg_timestamp: if peg_changed {
+                        None
+                    } else {
+                        current.last_peg_timestamp
+                    },
+                    ..config
+                };
+            }
+            Vacant(entry) => {
+                entry.insert(config);
+            }
+        }
+    }
+
+    fn stablecoin_config(&self, ledger_principal: Principal) -> &StablecoinConfig {
+        self.stablecoins
+            .get(&ledger_principal)
+            .expect("bug: unknown stablecoin")
+    }
+
+    pub fn is_stablecoin_registered(&self, stablecoin: StablecoinType) -> bool {
+        match stablecoin {
+            StablecoinType::Tal => true,
+            StablecoinType::Icrc(ledger_principal) => {
+                self.stablecoins.contains_key(&ledger_principal)
+            }
+        }
+    }
+
+    pub fn get_stablecoin_ledger_principal(&self, stablecoin: StablecoinType) -> Principal {
+        match stablecoin {
+            StablecoinType::Tal => self.taler_ledger_principal,
+            StablecoinType::Icrc(ledger_principal) => ledger_principal,
+        }
+    }
+
+    /// USD price of one unit of the stablecoin peg asset.
+    pub fn get_peg_rate(&self, stablecoin: StablecoinType) -> Option<Ratio> {
+        match stablecoin {
+            StablecoinType::Tal => Some(Ratio::from(dec!(1))),
+            StablecoinType::Icrc(ledger_principal) => {
+                self.stablecoin_config(ledger_principal).last_peg_rate
+            }
+        }
+    }
+
+    /// Price of the collateral expressed in units of the stablecoin.
+    pub fn get_collateral_rate_in(
+        &self,
+        collateral_type: CollateralType,
+        stablecoin: StablecoinType,
+    ) -> Option<UsdBtc> {
+        self.get_collateral_rate_of_kind_in(collateral_type, stablecoin, PriceKind::Spot)
+    }
+
+    /// Price of the collateral in units of the stablecoin used to check borrows.
+    pub fn get_borrow_rate_in(
+        &self,
+        collateral_type: CollateralType,
+        stablecoin: StablecoinType,
+    ) -> Option<UsdBtc> {
+        self.get_collateral_rate_of_kind_in(collateral_type, stablecoin, self.borrow_price_kind)
+    }
+
+    /// Price of the collateral in units of the stablecoin used to liquidate vaults.
+    pub fn get_liquidation_rate_in(
+        &self,
+        collateral_type: CollateralType,
+        stablecoin: StablecoinType,
+    ) -> Option<UsdBtc> {
+        self.get_collateral_rate_of_kind_in(
+            collateral_type,
+            stablecoin,
+            self.liquidation_price_kind,
+        )
+    }
+
+    /// Only the Bitcoin rate has a TWAP, the other collaterals always use their spot rate.
+    fn get_collateral_rate_of_kind_in(
+        &self,
+        collateral_type: CollateralType,
+        stablecoin: StablecoinType,
+        kind: PriceKind,
+    ) -> Option<UsdBtc> {
+        let collateral_rate = match (collateral_type, kind) {
+            (CollateralType::CkBtc, PriceKind::Twap) if self.twap_window_secs > 0 => {
+                self.btc_twap().or(self.last_btc_rate)?
+            }
+            _ => self.get_collateral_rate(collateral_type)?,
+        };
+        let peg_rate = self.get_peg_rate(stablecoin)?;
+        Some(UsdBtc::from(collateral_rate.0 / peg_rate.0))
+    }
+
+    pub fn liquidity_pool_of(&self, stablecoin: StablecoinType) -> &StabilityPool {
+        match stablecoin {
+            StablecoinType::Tal => &self.liquidity_pool,
+            StablecoinType::Icrc(ledger_principal) => {
+                &self.stablecoin_config(ledger_principal).liquidity_pool
+            }
+        }
+    }
+
+    fn liquidity_pool_of_mut(&mut self, stablecoin: StablecoinType) -> &mut StabilityPool {
+        match stablecoin {
+            StablecoinType::Tal => &mut self.liquidity_pool,
+            StablecoinType::Icrc(ledger_principal) => {
+                &mut self
+                    .stablecoins
+                    .get_mut(&ledger_principal)
+                    .expect("bug: unknown stablecoin")
+                    .liquidity_pool
+            }
+        }
+    }
+
+    pub fn add_collateral_type(&mut self, arg: AddCollateralTypeArg) {
+        let ledger_principal = arg.ledger_principal;
+        let config = CollateralConfig::from(arg);
+        match self.collaterals.entry(ledger_principal) {
+            Occupied(mut entry) => {
+                // Updating the parameters of a known collateral keeps its price.
+                let current = entry.get_mut();
+                *current = CollateralConfig {
+                    last_rate: current.last_rate,
+                    last_timestamp: current.last_timestamp,
+                    ..config
+                };
+            }
+            Vacant(entry) => {
+                entry.insert(config);
+            }
+        }
+    }
+
+    fn collateral_config(&self, ledger_principal: Principal) -> &CollateralConfig {
+        self.collaterals
+            .get(&ledger_principal)
+            .expect("bug: unknown collateral type")
+    }
+
+    pub fn is_collateral_registered(&self, collateral_type: CollateralType) -> bool {
+        match collateral_type {
+            CollateralType::CkBtc => true,
+            CollateralType::Icrc(ledger_principal) => {
+                self.collaterals.contains_key(&ledger_principal)
+            }
+        }
+    }
+
+    pub fn get_collateral_ledger_principal(&self, collateral_type: CollateralType) -> Principal {
+        match collateral_type {
+            CollateralType::CkBtc => self.ckbtc_ledger_principal,
+            CollateralType::Icrc(ledger_principal) => ledger_principal,
+        }
+    }
+
+    pub fn get_collateral_ledger_fee(&self, collateral_type: CollateralType) -> CKBTC {
+        match collateral_type {
+            CollateralType::CkBtc => self.ckbtc_ledger_fee,
+            CollateralType::Icrc(ledger_principal) => {
+                self.collateral_config(ledger_principal).ledger_fee
+            }
+        }
+    }
+
+    /// Smallest amount of the collateral accepted as margin.
+    pub fn get_collateral_minimum_amount(&self, collateral_type: CollateralType) -> CKBTC {
+        match collateral_type {
+            CollateralType::CkBtc => MIN_CKBTC_AMOUNT,
+            CollateralType::Icrc(ledger_principal) => {
+                self.collateral_config(ledger_principal).minimum_amount
+            }
+        }
+    }
+
+    pub fn set_collateral_ledger_fee(&mut self, collateral_type: CollateralType, fee: CKBTC) {
+        match collateral_type {
+            CollateralType::CkBtc => self.ckbtc_ledger_fee = fee,
+            CollateralType::Icrc(ledger_principal) => {
+                if let Some(config) = self.collaterals.get_mut(&ledger_principal) {
+                    config.ledger_fee = fee;
+                }
+            }
+        }
+    }
+
+    pub fn get_collateral_rate(&self, collateral_type: CollateralType) -> Option<UsdBtc> {
+        match collateral_type {
+            CollateralType::CkBtc => self.last_btc_rate,
+            CollateralType::Icrc(ledger_principal) => {
+                self.collateral_config(ledger_principal).last_rate
+            }
+        }
+    }
+
+    /// Collateral ratio under which a vault gets liquidated outside of recovery mode.
+    pub fn get_collateral_minimum_ratio(&self, collateral_type: CollateralType) -> Ratio {
+        match collateral_type {
+            CollateralType::CkBtc => MINIMUM_COLLATERAL_RATIO,
+            CollateralType::Icrc(ledger_principal) => {
+                self.collateral_config(ledger_principal)
+                    .minimum_collateral_ratio
+            }
+        }
+    }
+
+    pub fn get_minimum_liquidation_collateral_ratio(
+        &self,
+        collateral_type: CollateralType,
+    ) -> Ratio {
+        let minimum_collateral_ratio = self.get_collateral_minimum_ratio(collateral_type);
+        match self.mode {
+            Mode::Recovery => minimum_collateral_ratio.max(RECOVERY_COLLATERAL_RATIO),
+            Mode::GeneralAvailability | Mode::ReadOnly => minimum_collateral_ratio,
+        }
+    }
+
+    pub fn get_collateral_debt_ceiling(&self, collateral_type: CollateralType) -> Option<TAL> {
+        match collateral_type {
+            CollateralType::CkBtc => None,
+            CollateralType::Icrc(ledger_principal) => {
+                Some(self.collateral_config(ledger_principal).debt_ceiling)
+            }
+        }
     }
 
     pub fn total_borrowed_tal_amount(&self) -> TAL {
+        self.total_borrowed_amount_of(StablecoinType::Tal)
+    }
+
+    pub fn total_borrowed_amount_of(&self, stablecoin: StablecoinType) -> TAL {
+        self.vault_id_to_vaults
+            .values()
+            .filter(|vault| vault.stablecoin == stablecoin)
+            .map(|vault| self.accrued_debt(vault))
+            .sum()
+    }
+
+    /// USD value of a debt in the stablecoin, None if its peg rate is unknown.
+    fn debt_value(&self, stablecoin: StablecoinType, debt: TAL) -> Option<TAL> {
+        self.get_peg_rate(stablecoin)
+            .map(|peg_rate| debt * peg_rate)
+    }
+
+    /// Debt of the vaults, of the open auctions and the bad debt, per stablecoin.
+    fn outstanding_debts(&self) -> impl Iterator<Item = (StablecoinType, TAL)> + '_ {
+        self.vault_id_to_vaults
+            .values()
+            .map(|vault| (vault.stablecoin, self.accrued_debt(vault)))
+            .chain(
+                self.auctions
+                    .values()
+                    .map(|auction| (auction.stablecoin, auction.debt)),
+            )
+            .chain(
+                self.bad_debt
+                    .iter()
+                    .map(|(stablecoin, debt)| (*stablecoin, *debt)),
+            )
+    }
+
+    /// Stablecoin with outstanding debt whose peg rate is not known yet, the USD value
+    /// of the debt cannot be computed until it is fetched.
+    pub fn stablecoin_without_peg_rate(&self) -> Option<StablecoinType> {
+        self.outstanding_debts()
+            .find(|(stablecoin, debt)| *debt > 0 && self.get_peg_rate(*stablecoin).is_none())
+            .map(|(stablecoin, _debt)| stablecoin)
+    }
+
+    /// Checks that borrowing `amount` on the vault keeps the debt under the global,
+    /// owner and collateral debt ceilings. Fails while the value of some debt is unknown.
+    pub fn check_debt_ceilings(&self, vault: &Vault, amount: TAL) -> Result<(), ProtocolError> {
+        let amount_value = amount * self.get_peg_rate(vault.stablecoin).expect("no peg rate");
+
+        let has_ceiling = self.global_debt_ceiling.is_some()
+            || self.principal_debt_ceiling.is_some()
+            || self
+                .get_collateral_debt_ceiling(vault.collateral_type)
+                .is_some();
+        if has_ceiling {
+            if let Some(stablecoin) = self.stablecoin_without_peg_rate() {
+                return Err(ProtocolError::TemporarilyUnavailable(format!(
+                    "the peg rate of {stablecoin} is unknown, cannot check the debt ceilings"
+                )));
+            }
+        }
+
+        if let Some(debt_ceiling) = self.global_debt_ceiling {
+            let total_debt_value = self.total_debt_value();
+            if total_debt_value + amount_value > debt_ceiling {
+                return Err(ProtocolError::GenericError(format!(
+                    "failed to borrow from vault, global debt ceiling reached: {debt_ceiling}, already borrowed: {total_debt_value}"
+                )));
+            }
+        }
+
+        self.check_principal_debt_ceiling(vault.owner, amount_value)?;
+
+        if let Some(debt_ceiling) = self.get_collateral_debt_ceiling(vault.collateral_type) {
+            let borrowed_on_collateral = self.total_debt_value_on(vault.collateral_type);
+            if borrowed_on_collateral + amount_value > debt_ceiling {
+                return Err(ProtocolError::GenericError(format!(
+                    "failed to borrow from vault, debt ceiling of {} reached: {debt_ceiling}, already borrowed: {borrowed_on_collateral}",
+                    vault.collateral_type
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the owner can take on debt worth `amount_value` under the principal
+    /// debt ceiling.
+    fn check_principal_debt_ceiling(
+        &self,
+        owner: Principal,
+        amount_value: TAL,
+    ) -> Result<(), ProtocolError> {
+        if let Some(debt_ceiling) = self.principal_debt_ceiling {
+            let owner_debt_value = self.total_debt_value_of(owner);
+            if owner_debt_value + amount_value > debt_ceiling {
+                return Err(ProtocolError::GenericError(format!(
+                    "debt ceiling of {owner} reached: {debt_ceiling}, already borrowed: {owner_debt_value}"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the debt of the vault fits under the principal debt ceiling of
+    /// `new_owner`. Fails while the value of some debt is unknown.
+    pub fn check_transfer_debt_ceiling(
+        &self,
+        vault: &Vault,
+        new_owner: Principal,
+    ) -> Result<(), ProtocolError> {
+        if self.principal_debt_ceiling.is_none() {
+            return Ok(());
+        }
+        if let Some(stablecoin) = self.stablecoin_without_peg_rate() {
+            return Err(ProtocolError::TemporarilyUnavailable(format!(
+                "the peg rate of {stablecoin} is unknown, cannot check the debt ceilings"
+            )));
+        }
+        let debt_value = self
+            .debt_value(vault.stablecoin, vault.borrowed_tal_amount)
+            .unwrap_or(TAL::from(0));
+        self.check_principal_debt_ceiling(new_owner, debt_value)
+    }
+
+    /// USD value of the debt of all the vaults, of the open auctions and of the bad debt,
+    /// leaving out the debt whose peg rate is unknown, see [State::stablecoin_without_peg_rate].
+    pub fn total_debt_value(&self) -> TAL {
+        self.outstanding_debts()
+            .filter_map(|(stablecoin, debt)| self.debt_value(stablecoin, debt))
+            .sum()
+    }
+
+    /// USD value of the debt of the vaults owned by the principal.
+    pub fn total_debt_value_of(&self, owner: Principal) -> TAL {
+        self.principal_to_vault_ids
+            .get(&owner)
+            .into_iter()
+            .flatten()
+            .map(|vault_id| &self.vault_id_to_vaults[vault_id])
+            .filter_map(|vault| self.debt_value(vault.stablecoin, self.accrued_debt(vault)))
+            .sum()
+    }
+
+    /// USD value of the debt of the vaults backed by the collateral.
+    pub fn total_debt_value_on(&self, collateral_type: CollateralType) -> TAL {
         self.vault_id_to_vaults
             .values()
-            .map(|vault| vault.borrowed_tal_amount)
+            .filter(|vault| vault.collateral_type == collateral_type)
+            .filter_map(|vault| self.debt_value(vault.stablecoin, self.accrued_debt(vault)))
             .sum()
     }
 
     pub fn total_ckbtc_margin_amount(&self) -> CKBTC {
+        self.total_margin_amount(CollateralType::CkBtc)
+    }
+
+    pub fn total_margin_amount(&self, collateral_type: CollateralType) -> CKBTC {
         self.vault_id_to_vaults
             .values()
+            .filter(|vault| vault.collateral_type == collateral_type)
             .map(|vault| vault.ckbtc_margin_amount)
             .sum()
     }
 
+    /// Collateral of the open auctions.
+    pub fn total_auctioned_amount(&self, collateral_type: CollateralType) -> CKBTC {
+        self.auctions
+            .values()
+            .filter(|auction| auction.collateral_type == collateral_type)
+            .map(|auction| auction.collateral_amount)
+            .sum()
+    }
+
+    /// Value of all the margins and of the collateral of the open auctions, collaterals
+    /// without a known price are not counted.
+    pub fn total_collateral_value(&self, btc_rate: UsdBtc) -> TAL {
+        let ckbtc_value = (self.total_ckbtc_margin_amount()
+            + self.total_auctioned_amount(CollateralType::CkBtc))
+            * btc_rate;
+        self.collaterals
+            .iter()
+            .filter_map(|(ledger_principal, config)| {
+                let collateral_type = CollateralType::Icrc(*ledger_principal);
+                config.last_rate.map(|rate| {
+                    (self.total_margin_amount(collateral_type)
+                        + self.total_auctioned_amount(collateral_type))
+                        * rate
+                })
+            })
+            .fold(ckbtc_value, |acc, value| acc + value)
+    }
+
     pub fn compute_total_collateral_ratio(&self, btc_rate: UsdBtc) -> Ratio {
-        if self.total_borrowed_tal_amount() == 0 {
+        let total_debt_value = self.total_debt_value();
+        if total_debt_value == 0 {
             return Ratio::from(Decimal::MAX);
         }
-        (self.total_ckbtc_margin_amount() * btc_rate) / self.total_borrowed_tal_amount()
+        self.total_collateral_value(btc_rate) / total_debt_value
     }
 
     pub fn get_redemption_fee(&self, redeemed_amount: TAL) -> Ratio {
@@ -244,15 +1124,30 @@ impl State {
         )
     }
 
-    pub fn get_borrowing_fee(&self) -> Ratio {
+    pub fn get_borrowing_fee(&self, stablecoin: StablecoinType) -> Ratio {
+        let fee = match stablecoin {
+            StablecoinType::Tal => self.fee,
+            StablecoinType::Icrc(ledger_principal) => {
+                self.stablecoin_config(ledger_principal).borrowing_fee
+            }
+        };
         match self.mode {
             Mode::Recovery => Ratio::from(Decimal::ZERO),
-            Mode::GeneralAvailability => self.fee,
-            Mode::ReadOnly => self.fee,
+            Mode::GeneralAvailability => fee,
+            Mode::ReadOnly => fee,
         }
     }
 
     pub fn update_total_collateral_ratio_and_mode(&mut self, btc_rate: UsdBtc) {
+        if let Some(stablecoin) = self.stablecoin_without_peg_rate() {
+            // Leaving out that debt would overstate the total collateral ratio.
+            log!(
+                crate::DEBUG,
+                "[update_total_collateral_ratio_and_mode] keeping mode {}, the peg rate of {stablecoin} is unknown",
+                self.mode
+            );
+            return;
+        }
         let previous_mode = self.mode;
         let new_total_collateral_ratio = self.compute_total_collateral_ratio(btc_rate);
         self.total_collateral_ratio = new_total_collateral_ratio;
@@ -278,6 +1173,8 @@ impl State {
     pub fn open_vault(&mut self, vault: Vault) {
         let vault_id = vault.vault_id;
         self.vault_id_to_vaults.insert(vault_id, vault.clone());
+        self.vault_fee_indexes
+            .insert(vault_id, self.stability_fee_index);
         match self.principal_to_vault_ids.get_mut(&vault.owner) {
             Some(vault_ids) => {
                 vault_ids.insert(vault_id);
@@ -288,35 +1185,324 @@ impl State {
                 self.principal_to_vault_ids.insert(vault.owner, vault_ids);
             }
         }
+        self.reindex_vault(vault_id);
     }
 
-    pub fn close_vault(&mut self, vault_id: u64) {
+    /// Moves the vault to its place in the redemption index, or takes it out if it is
+    /// gone or has no TAL debt. The index holds the debt of the vaults divided by their
+    /// `vault_fee_indexes`, which the stability fee grows alike, so accruals keep the
+    /// order of the vaults and only scale their debt by `stability_fee_index`.
+    fn reindex_vault(&mut self, vault_id: VaultId) {
+        if let Some((collateral_type, key, debt)) = self.redemption_index_entries.remove(&vault_id)
+        {
+            self.redemption_index
+                .remove(&(collateral_type, key, vault_id));
+            if let Some(redeemable_debt) = self.redeemable_debt.get_mut(&collateral_type) {
+                *redeemable_debt -= debt;
+            }
+        }
+        let vault = match self.vault_id_to_vaults.get(&vault_id) {
+            Some(vault)
+                if vault.stablecoin == StablecoinType::Tal && vault.borrowed_tal_amount > 0 =>
+            {
+                vault
+            }
+            _ => return,
+        };
+        let vault_index = self
+            .vault_fee_indexes
+            .get(&vault_id)
+            .copied()
+            .unwrap_or(self.stability_fee_index);
+        let key = Ratio::from(
+            Decimal::from_u64(vault.ckbtc_margin_amount.to_u64()).unwrap() * vault_index.0
+                / Decimal::from_u64(vault.borrowed_tal_amount.to_u64()).unwrap(),
+        );
+        let (collateral_type, debt) = (
+            vault.collateral_type,
+            vault.borrowed_tal_amount / vault_index,
+        );
+        self.redemption_index
+            .insert((collateral_type, key, vault_id));
+        self.redemption_index_entries
+            .insert(vault_id, (collateral_type, key, debt));
+        *self
+            .redeemable_debt
+            .entry(collateral_type)
+            .or_insert(TAL::from(0)) += debt;
+    }
+
+    /// Closes a vault and queues the transfer of its margin to `to`, the owner if not set.
+    /// A margin withdrawal still pending for the vault is kept, the margin is then queued
+    /// as a collateral transfer.
+    pub fn close_vault(&mut self, vault_id: u64, to: Option<Account>) {
         if let Some(vault) = self.vault_id_to_vaults.remove(&vault_id) {
+            self.vault_operators.remove(&vault_id);
+            self.vault_fee_indexes.remove(&vault_id);
             let owner = vault.owner;
-            self.pending_margin_transfers.insert(
-                vault_id,
-                PendingMarginTransfer {
-                    owner,
-                    margin: vault.ckbtc_margin_amount,
-                },
-            );
+            let transfer = PendingMarginTransfer {
+                to: to.unwrap_or(Account::from(owner)),
+                margin: vault.ckbtc_margin_amount,
+                collateral_type: vault.collateral_type,
+            };
+            if let Some(withdrawal) = self.pending_margin_transfers.insert(vault_id, transfer) {
+                self.pending_margin_transfers.insert(vault_id, withdrawal);
+                self.push_collateral_transfer(transfer);
+            }
             if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&owner) {
                 vault_ids.remove(&vault_id);
             } else {
                 ic_cdk::trap("BUG: tried to close vault with no owner");
             }
+            self.reindex_vault(vault_id);
         } else {
             ic_cdk::trap("BUG: tried to close unknown vault");
         }
     }
 
-    pub fn borrow_from_vault(&mut self, vault_id: u64, borrowed_amount: TAL) {
+    /// Takes an unhealthy vault out of the vaults and puts its collateral up for auction.
+    pub fn start_auction(&mut self, vault_id: u64, oracle_price: UsdBtc, start_time: u64) {
+        self.settle_stability_fee(vault_id);
+        let vault = match self.vault_id_to_vaults.remove(&vault_id) {
+            Some(vault) => vault,
+            None => ic_cdk::trap("auctioning unknown vault"),
+        };
+        self.vault_operators.remove(&vault_id);
+        self.vault_fee_indexes.remove(&vault_id);
+        if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&vault.owner) {
+            vault_ids.remove(&vault_id);
+        }
+        self.reindex_vault(vault_id);
+        self.auctions.insert(
+            vault_id,
+            Auction {
+                vault_id,
+                owner: vault.owner,
+                collateral_type: vault.collateral_type,
+                stablecoin: vault.stablecoin,
+                collateral_amount: vault.ckbtc_margin_amount,
+                debt: vault.borrowed_tal_amount,
+                oracle_price,
+                start_time,
+                restarts: 0,
+            },
+        );
+    }
+
+    pub fn reset_auction(&mut self, vault_id: u64, oracle_price: UsdBtc, start_time: u64) {
+        match self.auctions.get_mut(&vault_id) {
+            Some(auction) => {
+                auction.oracle_price = oracle_price;
+                auction.start_time = start_time;
+                auction.restarts += 1;
+            }
+            None => ic_cdk::trap("resetting unknown auction"),
+        }
+    }
+
+    /// Closes an auction that ran out of restarts: its debt is booked as bad debt and its
+    /// collateral goes to the developer, to be sold to cover it.
+    pub fn close_auction(&mut self, vault_id: u64) {
+        let auction = match self.auctions.remove(&vault_id) {
+            Some(auction) => auction,
+            None => ic_cdk::trap("closing unknown auction"),
+        };
+        self.book_bad_debt(&auction);
+        if auction.collateral_amount > self.get_collateral_ledger_fee(auction.collateral_type) {
+            self.push_collateral_transfer(PendingMarginTransfer {
+                to: Account::from(self.developer_principal),
+                margin: auction.collateral_amount,
+                collateral_type: auction.collateral_type,
+            });
+        }
+    }
+
+    /// Books the debt left in a closed auction as bad debt: the stablecoin it was minted
+    /// as stays in circulation without any collateral backing it.
+    fn book_bad_debt(&mut self, auction: &Auction) {
+        if auction.debt == 0 {
+            return;
+        }
+        log!(
+            crate::INFO,
+            "[book_bad_debt] auction of vault {} closed leaving {} {} of debt uncovered",
+            auction.vault_id,
+            auction.debt,
+            auction.stablecoin
+        );
+        *self
+            .bad_debt
+            .entry(auction.stablecoin)
+            .or_insert(TAL::from(0)) += auction.debt;
+    }
+
+    /// Settles a bid: the stablecoin paid covers debt and the collateral bought is queued
+    /// for transfer. Once the debt is covered the remaining collateral goes back to the owner.
+    pub fn auction_bid(
+        &mut self,
+        vault_id: u64,
+        bidder: Principal,
+        stablecoin_amount: TAL,
+        collateral_amount: CKBTC,
+        to: Option<Account>,
+    ) {
+        let auction = match self.auctions.get_mut(&vault_id) {
+            Some(auction) => auction,
+            None => ic_cdk::trap("bidding on unknown auction"),
+        };
+        assert!(stablecoin_amount <= auction.debt);
+        assert!(collateral_amount <= auction.collateral_amount);
+        auction.debt -= stablecoin_amount;
+        auction.collateral_amount -= collateral_amount;
+        let auction = auction.clone();
+        self.push_collateral_transfer(PendingMarginTransfer {
+            to: to.unwrap_or(Account::from(bidder)),
+            margin: collateral_amount,
+            collateral_type: auction.collateral_type,
+        });
+
+        if auction.debt == 0 || auction.collateral_amount == 0 {
+            self.auctions.remove(&vault_id);
+            self.book_bad_debt(&auction);
+            if auction.collateral_amount > self.get_collateral_ledger_fee(auction.collateral_type) {
+                self.push_collateral_transfer(PendingMarginTransfer {
+                    to: Account::from(auction.owner),
+                    margin: auction.collateral_amount,
+                    collateral_type: auction.collateral_type,
+                });
+            }
+        }
+    }
+
+    pub fn push_collateral_transfer(&mut self, transfer: PendingMarginTransfer) {
+        let transfer_id = self.next_collateral_transfer_id;
+        self.next_collateral_transfer_id += 1;
+        self.pending_collateral_transfers
+            .insert(transfer_id, transfer);
+    }
+
+    pub fn push_stablecoin_transfer(&mut self, transfer: PendingStablecoinTransfer) {
+        let transfer_id = self.next_stablecoin_transfer_id;
+        self.next_stablecoin_transfer_id += 1;
+        self.pending_stablecoin_transfers
+            .insert(transfer_id, transfer);
+    }
+
+    /// Moves the margin and debt of the source vault into the target vault.
+    pub fn merge_vaults(&mut self, target_vault_id: u64, source_vault_id: u64) {
+        self.settle_stability_fee(target_vault_id);
+        self.settle_stability_fee(source_vault_id);
+        let source = match self.vault_id_to_vaults.remove(&source_vault_id) {
+            Some(source) => source,
+            None => ic_cdk::trap("merging unknown vault"),
+        };
+        self.vault_operators.remove(&source_vault_id);
+        self.vault_fee_indexes.remove(&source_vault_id);
+        if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&source.owner) {
+            vault_ids.remove(&source_vault_id);
+        }
+        match self.vault_id_to_vaults.get_mut(&target_vault_id) {
+            Some(target) => {
+                assert_eq!(target.collateral_type, source.collateral_type);
+                assert_eq!(target.stablecoin, source.stablecoin);
+                target.ckbtc_margin_amount += source.ckbtc_margin_amount;
+                target.borrowed_tal_amount += source.borrowed_tal_amount;
+            }
+            None => ic_cdk::trap("merging into unknown vault"),
+        }
+        self.reindex_vault(source_vault_id);
+        self.reindex_vault(target_vault_id);
+    }
+
+    /// Moves part of the margin and debt of a vault into a new vault with the same owner.
+    pub fn split_vault(&mut self, vault_id: u64, new_vault_id: u64, margin: CKBTC, debt: TAL) {
+        self.settle_stability_fee(vault_id);
+        let new_vault = match self.vault_id_to_vaults.get_mut(&vault_id) {
+            Some(vault) => {
+                assert!(margin <= vault.ckbtc_margin_amount);
+                assert!(debt <= vault.borrowed_tal_amount);
+                vault.ckbtc_margin_amount -= margin;
+                vault.borrowed_tal_amount -= debt;
+                Vault {
+                    owner: vault.owner,
+                    borrowed_tal_amount: debt,
+                    ckbtc_margin_amount: margin,
+                    vault_id: new_vault_id,
+                    collateral_type: vault.collateral_type,
+                    stablecoin: vault.stablecoin,
+                }
+            }
+            None => ic_cdk::trap("splitting unknown vault"),
+        };
+        self.reindex_vault(vault_id);
+        self.open_vault(new_vault);
+    }
+
+    pub fn transfer_vault(&mut self, vault_id: u64, new_owner: Principal) {
+        let vault = match self.vault_id_to_vaults.get_mut(&vault_id) {
+            Some(vault) => vault,
+            None => ic_cdk::trap("transferring unknown vault"),
+        };
+        let previous_owner = std::mem::replace(&mut vault.owner, new_owner);
+        // Operators were granted by the previous owner.
+        self.vault_operators.remove(&vault_id);
+        match self.principal_to_vault_ids.get_mut(&previous_owner) {
+            Some(vault_ids) => {
+                vault_ids.remove(&vault_id);
+            }
+            None => ic_cdk::trap("BUG: tried to transfer vault with no owner"),
+        }
+        self.principal_to_vault_ids
+            .entry(new_owner)
+            .or_default()
+            .insert(vault_id);
+    }
+
+    /// Grants permissions on a vault to an operator, or revokes them if `permissions` is not set.
+    pub fn set_vault_operator(
+        &mut self,
+        vault_id: u64,
+        operator: Principal,
+        permissions: Option<OperatorPermissions>,
+    ) {
+        match permissions {
+            Some(permissions) => {
+                self.vault_operators
+                    .entry(vault_id)
+                    .or_default()
+                    .insert(operator, permissions);
+            }
+            None => {
+                if let Some(operators) = self.vault_operators.get_mut(&vault_id) {
+                    operators.remove(&operator);
+                    if operators.is_empty() {
+                        self.vault_operators.remove(&vault_id);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get_operator_permissions(
+        &self,
+        vault_id: u64,
+        operator: Principal,
+    ) -> Option<OperatorPermissions> {
+        self.vault_operators
+            .get(&vault_id)
+            .and_then(|operators| operators.get(&operator))
+            .copied()
+    }
+
+    pub fn borrow_from_vault(&mut self, vault_id: u64, borrowed_amount: TAL) {
+        self.settle_stability_fee(vault_id);
         match self.vault_id_to_vaults.get_mut(&vault_id) {
             Some(vault) => {
                 vault.borrowed_tal_amount += borrowed_amount;
             }
             None => ic_cdk::trap("borrowing from unkown vault"),
         }
+        self.reindex_vault(vault_id);
     }
 
     pub fn add_margin_to_vault(&mut self, vault_id: u64, add_margin: CKBTC) {
@@ -326,9 +1512,41 @@ impl State {
             }
             None => ic_cdk::trap("adding margin to unkown vault"),
         }
+        self.reindex_vault(vault_id);
+    }
+
+    /// Takes margin out of a vault and queues its transfer, the vault must not have
+    /// another margin transfer pending.
+    pub fn withdraw_margin_from_vault(
+        &mut self,
+        vault_id: u64,
+        margin_withdrawn: CKBTC,
+        to: Option<Account>,
+    ) {
+        match self.vault_id_to_vaults.get_mut(&vault_id) {
+            Some(vault) => {
+                assert!(margin_withdrawn <= vault.ckbtc_margin_amount);
+                vault.ckbtc_margin_amount -= margin_withdrawn;
+                let transfer = PendingMarginTransfer {
+                    to: to.unwrap_or(Account::from(vault.owner)),
+                    margin: margin_withdrawn,
+                    collateral_type: vault.collateral_type,
+                };
+                if self
+                    .pending_margin_transfers
+                    .insert(vault_id, transfer)
+                    .is_some()
+                {
+                    ic_cdk::trap("BUG: margin transfer already pending for vault");
+                }
+            }
+            None => ic_cdk::trap("withdrawing margin from unkown vault"),
+        }
+        self.reindex_vault(vault_id);
     }
 
     pub fn repay_to_vault(&mut self, vault_id: u64, repayed_amount: TAL) {
+        self.settle_stability_fee(vault_id);
         match self.vault_id_to_vaults.get_mut(&vault_id) {
             Some(vault) => {
                 assert!(repayed_amount <= vault.borrowed_tal_amount);
@@ -336,144 +1554,628 @@ impl State {
             }
             None => ic_cdk::trap("repaying to unkown vault"),
         }
+        self.reindex_vault(vault_id);
     }
 
-    pub fn provide_liquidity(&mut self, amount: TAL, caller: Principal) {
+    pub fn provide_liquidity(
+        &mut self,
+        amount: TAL,
+        caller: Principal,
+        stablecoin: StablecoinType,
+    ) {
         if amount == 0 {
             return;
         }
-        self.liquidity_pool
-            .entry(caller)
-            .and_modify(|curr| *curr += amount)
-            .or_insert(amount);
+        let gains = self
+            .liquidity_pool_of_mut(stablecoin)
+            .deposit(caller, amount);
+        self.credit_liquidity_returns(caller, stablecoin, gains);
+        self.compound_liquidity_returns(caller);
     }
 
-    pub fn withdraw_liquidity(&mut self, amount: TAL, caller: Principal) {
-        match self.liquidity_pool.entry(caller) {
-            Occupied(mut entry) => {
-                assert!(*entry.get() >= amount);
-                *entry.get_mut() -= amount;
-                if *entry.get() == 0 {
-                    entry.remove_entry();
+    pub fn withdraw_liquidity(
+        &mut self,
+        amount: TAL,
+        caller: Principal,
+        stablecoin: StablecoinType,
+    ) {
+        let gains = self
+            .liquidity_pool_of_mut(stablecoin)
+            .withdraw(caller, amount);
+        self.credit_liquidity_returns(caller, stablecoin, gains);
+        self.compound_liquidity_returns(caller);
+        let remaining_deposit = self.get_provided_liquidity(caller, stablecoin);
+        if let Occupied(mut entry) = self.withdrawal_requests.entry((caller, stablecoin)) {
+            let request = entry.get_mut();
+            request.amount = request.amount.saturating_sub(amount).min(remaining_deposit);
+            if request.amount == 0 {
+                entry.remove_entry();
+            }
+        }
+    }
+
+    pub fn request_withdrawal(
+        &mut self,
+        caller: Principal,
+        amount: TAL,
+        stablecoin: StablecoinType,
+        timestamp: u64,
+    ) {
+        self.withdrawal_requests.insert(
+            (caller, stablecoin),
+            WithdrawalRequest {
+                amount,
+                requested_at: timestamp,
+            },
+        );
+    }
+
+    /// Checks that the provider can withdraw `amount` at `now`. With a cooldown, the
+    /// amount must have been requested and the request can only be used between the
+    /// end of the cooldown and the end of the withdrawal window.
+    pub fn check_withdrawal(
+        &self,
+        provider: Principal,
+        stablecoin: StablecoinType,
+        amount: TAL,
+        now: u64,
+    ) -> Result<(), ProtocolError> {
+        if self.withdrawal_cooldown_secs == 0 {
+            return Ok(());
+        }
+        let request = match self.withdrawal_requests.get(&(provider, stablecoin)) {
+            Some(request) => request,
+            None => {
+                return Err(ProtocolError::GenericError(
+                    "call request_withdrawal before withdrawing liquidity".to_string(),
+                ))
+            }
+        };
+        let unlock_time = request.requested_at + self.withdrawal_cooldown_secs * SEC_NANOS;
+        if amount > request.amount {
+            return Err(ProtocolError::GenericError(format!(
+                "cannot withdraw: {amount}, requested: {}",
+                request.amount
+            )));
+        }
+        if now < unlock_time {
+            return Err(ProtocolError::GenericError(format!(
+                "withdrawal requested at {} is still cooling down",
+                request.requested_at
+            )));
+        }
+        if now >= unlock_time + self.withdrawal_window_secs * SEC_NANOS {
+            return Err(ProtocolError::GenericError(format!(
+                "withdrawal requested at {} has lapsed, call request_withdrawal again",
+                request.requested_at
+            )));
+        }
+        Ok(())
+    }
+
+    /// Amount of the withdrawal request of the provider still in the pool, it shrinks
+    /// with the liquidations absorbed by the deposit.
+    pub fn get_pending_withdrawal(
+        &self,
+        provider: Principal,
+        stablecoin: StablecoinType,
+    ) -> Option<WithdrawalRequest> {
+        self.withdrawal_requests
+            .get(&(provider, stablecoin))
+            .map(|request| WithdrawalRequest {
+                amount: request
+                    .amount
+                    .min(self.get_provided_liquidity(provider, stablecoin)),
+                requested_at: request.requested_at,
+            })
+    }
+
+    fn credit_liquidity_returns(
+        &mut self,
+        provider: Principal,
+        stablecoin: StablecoinType,
+        gains: PoolGains,
+    ) {
+        for (collateral_type, gain) in gains.collateral {
+            *self
+                .liquidity_returns
+                .entry(provider)
+                .or_default()
+                .entry(collateral_type)
+                .or_insert(CKBTC::from(0)) += gain;
+        }
+        if gains.fees > 0 {
+            *self
+                .fee_returns
+                .entry(provider)
+                .or_default()
+                .entry(stablecoin)
+                .or_insert(TAL::from(0)) += gains.fees;
+        }
+    }
+
+    /// Pays `lp_fee_share` of a borrowing or redemption fee to the providers of the
+    /// liquidity pool of the stablecoin, and the rest to the developer.
+    pub fn collect_fee(&mut self, fee_amount: TAL, stablecoin: StablecoinType) {
+        let lp_fee = if self.liquidity_pool_of(stablecoin).total_deposits() == 0 {
+            TAL::from(0)
+        } else {
+            (fee_amount * self.lp_fee_share).min(fee_amount)
+        };
+        if lp_fee > 0 {
+            self.liquidity_pool_of_mut(stablecoin)
+                .distribute_fee(lp_fee);
+        }
+        self.provide_liquidity(fee_amount - lp_fee, self.developer_principal, stablecoin);
+    }
+
+    pub(crate) fn stablecoin_types(&self) -> Vec<StablecoinType> {
+        std::iter::once(StablecoinType::Tal)
+            .chain(self.stablecoins.keys().map(|p| StablecoinType::Icrc(*p)))
+            .collect()
+    }
+
+    /// Realizes the returns of the provider pending in every liquidity pool.
+    fn settle_liquidity_returns(&mut self, provider: Principal) {
+        for stablecoin in self.stablecoin_types() {
+            let gains = self.liquidity_pool_of_mut(stablecoin).settle(provider);
+            self.credit_liquidity_returns(provider, stablecoin, gains);
+        }
+    }
+
+    /// Realized and pending liquidity returns of every provider.
+    pub fn all_liquidity_returns(&self) -> BTreeMap<Principal, BTreeMap<CollateralType, CKBTC>> {
+        let mut returns = self.liquidity_returns.clone();
+        for stablecoin in self.stablecoin_types() {
+            let pool = self.liquidity_pool_of(stablecoin);
+            for (provider, _) in pool.deposits() {
+                for (collateral_type, gain) in pool.collateral_gains_of(provider) {
+                    *returns
+                        .entry(provider)
+                        .or_default()
+                        .entry(collateral_type)
+                        .or_insert(CKBTC::from(0)) += gain;
                 }
             }
-            Vacant(_) => ic_cdk::trap("cannot remove liquidity from unknow principal"),
         }
+        returns
     }
 
-    pub fn claim_liquidity_returns(&mut self, amount: CKBTC, caller: Principal) {
+    pub fn claim_liquidity_returns(
+        &mut self,
+        amount: CKBTC,
+        caller: Principal,
+        collateral_type: CollateralType,
+    ) {
+        self.settle_liquidity_returns(caller);
         match self.liquidity_returns.entry(caller) {
             Occupied(mut entry) => {
-                assert!(*entry.get() >= amount);
-                *entry.get_mut() -= amount;
-                if *entry.get() == 0 {
+                match entry.get_mut().entry(collateral_type) {
+                    Occupied(mut returns) => {
+                        // Legacy claims can exceed the replayed returns by rounding dust.
+                        assert!(*returns.get() + CKBTC::from(MAX_ROUNDING_DUST) >= amount);
+                        *returns.get_mut() = returns.get().saturating_sub(amount);
+                        if *returns.get() == 0 {
+                            returns.remove_entry();
+                        }
+                    }
+                    Vacant(_) => ic_cdk::trap("cannot claim returns of unknown collateral"),
+                }
+                if entry.get().is_empty() {
                     entry.remove_entry();
                 }
             }
             Vacant(_) => ic_cdk::trap("cannot claim returns from unknow principal"),
         }
+        self.compound_liquidity_returns(caller);
+    }
+
+    pub fn set_compounding_preference(
+        &mut self,
+        provider: Principal,
+        preference: CompoundingPreference,
+    ) {
+        if preference == CompoundingPreference::Claim {
+            self.compounding_preferences.remove(&provider);
+        } else {
+            self.compounding_preferences.insert(provider, preference);
+        }
+    }
+
+    /// Adds the realized returns of a provider compounding into a vault to its margin.
+    /// Returns are realized when the provider deposits, withdraws or claims, so that
+    /// liquidations do not go through every compounding provider.
+    fn compound_liquidity_returns(&mut self, provider: Principal) {
+        let vault_id = match self.compounding_preferences.get(&provider) {
+            Some(CompoundingPreference::AddToVault { vault_id }) => *vault_id,
+            _ => return,
+        };
+        let collateral_type = match self.vault_id_to_vaults.get(&vault_id) {
+            Some(vault) if vault.owner == provider => vault.collateral_type,
+            _ => return,
+        };
+        let returns = match self.liquidity_returns.get_mut(&provider) {
+            Some(returns) => returns.remove(&collateral_type).unwrap_or(CKBTC::from(0)),
+            None => return,
+        };
+        if self.liquidity_returns[&provider].is_empty() {
+            self.liquidity_returns.remove(&provider);
+        }
+        if returns == 0 {
+            return;
+        }
+        log!(
+            crate::DEBUG,
+            "[compound_liquidity_returns] adding {returns} {collateral_type} of {provider} to vault {vault_id}"
+        );
+        self.add_margin_to_vault(vault_id, returns);
+    }
+
+    /// Fills a redemption with the returns of the providers compounding into liquidity,
+    /// returns the amount left to redeem on vaults.
+    fn redeem_on_compounding_returns(
+        &mut self,
+        tal_amount: TAL,
+        current_btc_rate: UsdBtc,
+        collateral_type: CollateralType,
+    ) -> TAL {
+        let providers: Vec<Principal> = self
+            .compounding_preferences
+            .iter()
+            .filter(|(_, preference)| **preference == CompoundingPreference::ProvideLiquidity)
+            .map(|(provider, _)| *provider)
+            .collect();
+        let mut tal_amount_to_convert = tal_amount;
+        for provider in providers {
+            if tal_amount_to_convert == 0 {
+                break;
+            }
+            let returns = self.get_liquidity_returns_of(provider, collateral_type);
+            let wanted_margin: CKBTC = tal_amount_to_convert / current_btc_rate;
+            let sold_margin = returns.min(wanted_margin);
+            if sold_margin == 0 {
+                continue;
+            }
+            let paid_amount = if sold_margin == wanted_margin {
+                tal_amount_to_convert
+            } else {
+                sold_margin * current_btc_rate
+            };
+            self.claim_liquidity_returns(sold_margin, provider, collateral_type);
+            self.provide_liquidity(paid_amount, provider, StablecoinType::Tal);
+            tal_amount_to_convert -= paid_amount;
+        }
+        tal_amount_to_convert
+    }
+
+    pub fn claim_fee_returns(
+        &mut self,
+        amount: TAL,
+        caller: Principal,
+        stablecoin: StablecoinType,
+    ) {
+        self.settle_liquidity_returns(caller);
+        match self.fee_returns.entry(caller) {
+            Occupied(mut entry) => {
+                match entry.get_mut().entry(stablecoin) {
+                    Occupied(mut returns) => {
+                        assert!(*returns.get() >= amount);
+                        *returns.get_mut() -= amount;
+                        if *returns.get() == 0 {
+                            returns.remove_entry();
+                        }
+                    }
+                    Vacant(_) => ic_cdk::trap("cannot claim fee returns of unknown stablecoin"),
+                }
+                if entry.get().is_empty() {
+                    entry.remove_entry();
+                }
+            }
+            Vacant(_) => ic_cdk::trap("cannot claim fee returns from unknow principal"),
+        }
+        self.compound_liquidity_returns(caller);
+    }
+
+    /// Realized and pending fee returns of the provider in the stablecoin.
+    pub fn get_fee_returns_of(&self, principal: Principal, stablecoin: StablecoinType) -> TAL {
+        self.fee_returns
+            .get(&principal)
+            .and_then(|returns| returns.get(&stablecoin))
+            .cloned()
+            .unwrap_or(TAL::from(0))
+            + self.liquidity_pool_of(stablecoin).fee_gains_of(principal)
+    }
+
+    pub fn total_fee_returns(&self, stablecoin: StablecoinType) -> TAL {
+        let pool = self.liquidity_pool_of(stablecoin);
+        let realized: TAL = self
+            .fee_returns
+            .values()
+            .filter_map(|returns| returns.get(&stablecoin).cloned())
+            .sum();
+        let pending: TAL = pool
+            .deposits()
+            .map(|(provider, _)| pool.fee_gains_of(provider))
+            .sum();
+        realized + pending
+    }
+
+    pub fn get_liquidity_returns_of(
+        &self,
+        principal: Principal,
+        collateral_type: CollateralType,
+    ) -> CKBTC {
+        let pending: CKBTC = self
+            .stablecoin_types()
+            .into_iter()
+            .filter_map(|stablecoin| {
+                self.liquidity_pool_of(stablecoin)
+                    .collateral_gains_of(principal)
+                    .get(&collateral_type)
+                    .cloned()
+            })
+            .sum();
+        self.liquidity_returns
+            .get(&principal)
+            .and_then(|returns| returns.get(&collateral_type))
+            .cloned()
+            .unwrap_or(0.into())
+            + pending
+    }
+
+    pub fn total_provided_liquidity_amount(&self, stablecoin: StablecoinType) -> TAL {
+        self.liquidity_pool_of(stablecoin).total_deposits()
+    }
+
+    pub fn total_available_returns(&self, collateral_type: CollateralType) -> CKBTC {
+        self.all_liquidity_returns()
+            .values()
+            .filter_map(|returns| returns.get(&collateral_type).cloned())
+            .sum()
     }
 
-    pub fn get_liquidity_returns_of(&self, principal: Principal) -> CKBTC {
-        *self.liquidity_returns.get(&principal).unwrap_or(&0.into())
+    pub fn get_provided_liquidity(&self, principal: Principal, stablecoin: StablecoinType) -> TAL {
+        self.liquidity_pool_of(stablecoin).deposit_of(principal)
     }
 
-    pub fn total_provided_liquidity_amount(&self) -> TAL {
-        self.liquidity_pool.values().cloned().sum()
+    /// Whether liquidating the vault only seizes the value of its debt at the minimum
+    /// collateral ratio, without penalty: in recovery mode, for vaults still above that
+    /// ratio at `collateral_rate`, the price of their collateral in their stablecoin.
+    fn is_partial_liquidation(&self, vault: &Vault, mode: Mode, collateral_rate: UsdBtc) -> bool {
+        mode == Mode::Recovery
+            && compute_collateral_ratio(vault, collateral_rate)
+                > self.get_collateral_minimum_ratio(vault.collateral_type)
     }
 
-    pub fn total_available_returns(&self) -> CKBTC {
-        self.liquidity_returns.values().cloned().sum()
+    /// Margin seized on top of the value of the debt when liquidating a vault, capped by
+    /// the margin of the vault. None for partial liquidations, which do not apply it.
+    pub fn compute_liquidation_penalty(
+        &self,
+        vault_id: u64,
+        mode: Mode,
+        collateral_rate: UsdBtc,
+    ) -> Option<CKBTC> {
+        let vault = self.get_vault(vault_id).expect("bug: vault not found");
+        if self.is_partial_liquidation(&vault, mode, collateral_rate) {
+            return None;
+        }
+        let debt_margin =
+            (vault.borrowed_tal_amount / collateral_rate).min(vault.ckbtc_margin_amount);
+        Some((debt_margin * self.liquidation_penalty).min(vault.ckbtc_margin_amount - debt_margin))
     }
 
-    pub fn get_provided_liquidity(&self, principal: Principal) -> TAL {
-        *self.liquidity_pool.get(&principal).unwrap_or(&TAL::from(0))
+    /// Splits the margin of a vault between the liquidity pool, the keeper and the owner.
+    /// The debt of the vault must include the accrued stability fee, see [State::get_vault].
+    /// Partial liquidations seize the value of the debt at the minimum collateral ratio,
+    /// the others the value of the debt plus the `penalty`, or the whole margin for
+    /// liquidations recorded before penalties existed.
+    pub fn compute_liquidation_split(
+        &self,
+        vault: &Vault,
+        mode: Mode,
+        collateral_rate: UsdBtc,
+        has_keeper: bool,
+        penalty: Option<CKBTC>,
+    ) -> LiquidationSplit {
+        let seized_margin = if self.is_partial_liquidation(vault, mode, collateral_rate) {
+            (vault.borrowed_tal_amount * self.get_collateral_minimum_ratio(vault.collateral_type))
+                / collateral_rate
+        } else {
+            match penalty {
+                Some(penalty) => {
+                    let debt_margin = (vault.borrowed_tal_amount / collateral_rate)
+                        .min(vault.ckbtc_margin_amount);
+                    (debt_margin + penalty).min(vault.ckbtc_margin_amount)
+                }
+                None => vault.ckbtc_margin_amount,
+            }
+        };
+        let reward = seized_margin * self.liquidation_reward_rate;
+        let keeper_reward =
+            if has_keeper && reward > self.get_collateral_ledger_fee(vault.collateral_type) {
+                reward
+            } else {
+                CKBTC::from(0)
+            };
+        LiquidationSplit {
+            seized_margin,
+            keeper_reward,
+            remaining_margin: vault.ckbtc_margin_amount.saturating_sub(seized_margin),
+        }
     }
 
-    pub fn liquidate_vault(&mut self, vault_id: u64, mode: Mode, btc_rate: UsdBtc) {
+    /// Liquidates a vault to the liquidity pool as split by
+    /// [State::compute_liquidation_split], the rest of the margin stays in the vault.
+    /// When the liquidation is triggered by a keeper, the keeper is paid
+    /// `liquidation_reward_rate` of the seized margin.
+    pub fn liquidate_vault(
+        &mut self,
+        vault_id: u64,
+        mode: Mode,
+        collateral_rate: UsdBtc,
+        keeper: Option<Account>,
+        penalty: Option<CKBTC>,
+    ) {
+        self.settle_stability_fee(vault_id);
         let vault = self
             .vault_id_to_vaults
             .get(&vault_id)
             .cloned()
             .expect("bug: vault not found");
-        assert!(self.total_provided_liquidity_amount() >= vault.borrowed_tal_amount);
-        let vault_collateral_ratio = compute_collateral_ratio(&vault, btc_rate);
-        let entries = if mode == Mode::Recovery && vault_collateral_ratio > MINIMUM_COLLATERAL_RATIO
-        {
-            let partial_margin = (vault.borrowed_tal_amount * MINIMUM_COLLATERAL_RATIO) / btc_rate;
+        assert!(
+            self.total_provided_liquidity_amount(vault.stablecoin) >= vault.borrowed_tal_amount
+        );
+        let split = self.compute_liquidation_split(
+            &vault,
+            mode,
+            collateral_rate,
+            keeper.is_some(),
+            penalty,
+        );
+        if self.is_partial_liquidation(&vault, mode, collateral_rate) {
             assert!(
-                partial_margin <= vault.ckbtc_margin_amount,
-                "partial margin: {partial_margin}, vault margin: {}",
+                split.seized_margin <= vault.ckbtc_margin_amount,
+                "partial margin: {}, vault margin: {}",
+                split.seized_margin,
                 vault.ckbtc_margin_amount
             );
             match self.vault_id_to_vaults.get_mut(&vault_id) {
                 Some(vault) => {
                     vault.borrowed_tal_amount = 0.into();
-                    assert!(vault.ckbtc_margin_amount >= partial_margin);
-                    vault.ckbtc_margin_amount -= partial_margin;
+                    vault.ckbtc_margin_amount -= split.seized_margin;
                 }
                 None => ic_cdk::trap("liquidating unkown vault"),
             }
             log!(
                 crate::DEBUG,
-                "[liquidate_vault] Do not liquidate totally as CR still above 110%",
+                "[liquidate_vault] Do not liquidate totally as CR still above {}",
+                self.get_collateral_minimum_ratio(vault.collateral_type)
             );
-            distribute_across_lps(
-                &self.liquidity_pool,
-                vault.borrowed_tal_amount,
-                partial_margin,
-            )
-        } else {
-            if let Some(vault) = self.vault_id_to_vaults.remove(&vault_id) {
-                if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&vault.owner) {
-                    vault_ids.remove(&vault_id);
+        } else if split.remaining_margin > 0 {
+            match self.vault_id_to_vaults.get_mut(&vault_id) {
+                Some(vault) => {
+                    vault.borrowed_tal_amount = 0.into();
+                    vault.ckbtc_margin_amount -= split.seized_margin;
                 }
+                None => ic_cdk::trap("liquidating unkown vault"),
             }
-            distribute_across_lps(
-                &self.liquidity_pool,
-                vault.borrowed_tal_amount,
-                vault.ckbtc_margin_amount,
-            )
-        };
-        assert!(!entries.is_empty());
-        for entry in entries {
             log!(
                 crate::DEBUG,
-                "[liquidate_vault] debiting {} TAL to {} for {} ckBTC",
-                entry.tal_to_debit,
-                entry.owner,
-                entry.ckbtc_reward
+                "[liquidate_vault] leaving {} {} to the owner of vault {vault_id}",
+                split.remaining_margin,
+                vault.collateral_type
             );
-            match self.liquidity_pool.entry(entry.owner) {
-                Occupied(mut lp_entry) => {
-                    assert!(
-                        *lp_entry.get() >= entry.tal_to_debit,
-                        "entry contains {} cannot substract {}",
-                        *lp_entry.get(),
-                        entry.tal_to_debit
-                    );
-                    *lp_entry.get_mut() -= entry.tal_to_debit;
-                    if *lp_entry.get() == 0 {
-                        lp_entry.remove();
-                    }
-                }
-                Vacant(_) => {
-                    ic_cdk::trap("bug: principal not found in liquidity_pool");
-                }
+        } else if let Some(vault) = self.vault_id_to_vaults.remove(&vault_id) {
+            self.vault_operators.remove(&vault_id);
+            self.vault_fee_indexes.remove(&vault_id);
+            if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&vault.owner) {
+                vault_ids.remove(&vault_id);
             }
-            self.liquidity_returns
-                .entry(entry.owner)
-                .and_modify(|v| *v += entry.ckbtc_reward)
-                .or_insert(entry.ckbtc_reward);
         }
+        if let Some(keeper) = keeper {
+            self.pay_liquidation_reward(&vault, split.keeper_reward, keeper);
+        }
+        let liquidated_margin = split.seized_margin - split.keeper_reward;
+        self.reindex_vault(vault_id);
+        log!(
+            crate::DEBUG,
+            "[liquidate_vault] debiting {} {} from the liquidity pool for {} {}",
+            vault.borrowed_tal_amount,
+            vault.stablecoin,
+            liquidated_margin,
+            vault.collateral_type
+        );
+        self.offset_liquidity_pool(
+            vault.stablecoin,
+            vault.borrowed_tal_amount,
+            vault.collateral_type,
+            liquidated_margin,
+        );
+    }
+
+    /// Burns `debt` out of the liquidity pool of `stablecoin` for `collateral`, the
+    /// returns of the deposits wiped out by the liquidation are credited.
+    fn offset_liquidity_pool(
+        &mut self,
+        stablecoin: StablecoinType,
+        debt: TAL,
+        collateral_type: CollateralType,
+        collateral: CKBTC,
+    ) {
+        let wiped_out =
+            self.liquidity_pool_of_mut(stablecoin)
+                .offset(debt, collateral_type, collateral);
+        for (provider, gains) in wiped_out {
+            self.credit_liquidity_returns(provider, stablecoin, gains);
+            self.compound_liquidity_returns(provider);
+            self.withdrawal_requests.remove(&(provider, stablecoin));
+        }
+    }
+
+    /// Checks that the keeper reward is less than the whole liquidated margin and at most
+    /// the liquidation penalty, so that the reward comes out of the penalty.
+    pub fn check_liquidation_reward(&self) -> Result<(), String> {
+        if self.liquidation_reward_rate >= Ratio::from(dec!(1)) {
+            return Err(format!(
+                "liquidation reward of {} must be below 1",
+                self.liquidation_reward_rate
+            ));
+        }
+        if self.liquidation_reward_rate > self.liquidation_penalty {
+            return Err(format!(
+                "liquidation reward of {} is above the liquidation penalty of {}",
+                self.liquidation_reward_rate, self.liquidation_penalty
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queues the keeper's share of the liquidated margin.
+    fn pay_liquidation_reward(&mut self, vault: &Vault, reward: CKBTC, keeper: Account) {
+        if reward == 0 {
+            return;
+        }
+        log!(
+            crate::DEBUG,
+            "[liquidate_vault] paying {reward} {} to keeper {keeper}",
+            vault.collateral_type
+        );
+        self.push_collateral_transfer(PendingMarginTransfer {
+            to: keeper,
+            margin: reward,
+            collateral_type: vault.collateral_type,
+        });
     }
 
     pub fn redistribute_vault(&mut self, vault_id: u64) {
-        let vault = self
+        let (collateral_type, stablecoin) = match self.vault_id_to_vaults.get(&vault_id) {
+            Some(vault) => (vault.collateral_type, vault.stablecoin),
+            None => panic!("bug: vault not found"),
+        };
+        // Debt and margin can only be absorbed by vaults backed by the same
+        // collateral and borrowing the same stablecoin.
+        let same_collateral_vault_ids: Vec<VaultId> = self
             .vault_id_to_vaults
-            .get(&vault_id)
-            .expect("bug: vault not found");
-        let entries = distribute_accross_vaults(&self.vault_id_to_vaults, vault.clone());
+            .values()
+            .filter(|other| {
+                other.collateral_type == collateral_type && other.stablecoin == stablecoin
+            })
+            .map(|other| other.vault_id)
+            .collect();
+        for other_vault_id in &same_collateral_vault_ids {
+            self.settle_stability_fee(*other_vault_id);
+        }
+        let vault = self.vault_id_to_vaults[&vault_id].clone();
+        let same_collateral_vaults: BTreeMap<u64, Vault> = same_collateral_vault_ids
+            .into_iter()
+            .map(|other_vault_id| {
+                (
+                    other_vault_id,
+                    self.vault_id_to_vaults[&other_vault_id].clone(),
+                )
+            })
+            .collect();
+        let entries = distribute_accross_vaults(&same_collateral_vaults, vault);
         for entry in entries {
             match self.vault_id_to_vaults.entry(entry.vault_id) {
                 Occupied(mut vault_entry) => {
@@ -482,31 +2184,105 @@ impl State {
                 }
                 Vacant(_) => panic!("bug: vault not found"),
             }
+            self.reindex_vault(entry.vault_id);
         }
         if let Some(vault) = self.vault_id_to_vaults.remove(&vault_id) {
+            self.vault_operators.remove(&vault_id);
+            self.vault_fee_indexes.remove(&vault_id);
             let owner = vault.owner;
             if let Some(vault_ids) = self.principal_to_vault_ids.get_mut(&owner) {
                 vault_ids.remove(&vault_id);
             }
         }
+        self.reindex_vault(vault_id);
     }
 
-    pub fn redeem_on_vaults(&mut self, tal_amount: TAL, current_btc_rate: UsdBtc) {
-        let mut tal_amount_to_convert = tal_amount;
-        let mut vaults: BTreeSet<(Ratio, VaultId)> = BTreeSet::new();
+    /// Amount of a redemption of `tal_amount` that can be filled from the returns offered
+    /// by compounding providers and at most [MAX_REDEEMED_VAULTS] vaults. Fails when
+    /// there is not enough debt to redeem.
+    pub fn redeemable_amount(
+        &self,
+        tal_amount: TAL,
+        current_btc_rate: UsdBtc,
+        collateral_type: CollateralType,
+    ) -> Result<TAL, ProtocolError> {
+        let offered_returns = self.offered_returns(current_btc_rate, collateral_type);
+        let redeemable_debt = self
+            .redeemable_debt
+            .get(&collateral_type)
+            .cloned()
+            .unwrap_or(TAL::from(0))
+            * self.stability_fee_index;
+        if tal_amount > offered_returns + redeemable_debt {
+            return Err(ProtocolError::GenericError(format!(
+                "cannot redeem {tal_amount}, redeemable: {}",
+                offered_returns + redeemable_debt
+            )));
+        }
+        Ok(self.fillable_amount(tal_amount, current_btc_rate, collateral_type))
+    }
 
-        for vault in self.vault_id_to_vaults.values() {
-            vaults.insert((
-                crate::compute_collateral_ratio(vault, current_btc_rate),
-                vault.vault_id,
-            ));
+    /// Value of the returns compounding providers offer to redemptions.
+    fn offered_returns(&self, current_btc_rate: UsdBtc, collateral_type: CollateralType) -> TAL {
+        self.compounding_preferences
+            .iter()
+            .filter(|(_, preference)| **preference == CompoundingPreference::ProvideLiquidity)
+            .map(|(provider, _)| {
+                self.get_liquidity_returns_of(*provider, collateral_type) * current_btc_rate
+            })
+            .sum()
+    }
+
+    /// Part of a redemption of `tal_amount` that the returns offered by compounding
+    /// providers and at most [MAX_REDEEMED_VAULTS] vaults can fill.
+    pub fn fillable_amount(
+        &self,
+        tal_amount: TAL,
+        current_btc_rate: UsdBtc,
+        collateral_type: CollateralType,
+    ) -> TAL {
+        let mut fillable_amount = self.offered_returns(current_btc_rate, collateral_type);
+        for (_, _, vault_id) in self
+            .redemption_index
+            .range((collateral_type, Ratio::from(Decimal::ZERO), 0)..)
+            .take_while(|(other, _, _)| *other == collateral_type)
+            .take(MAX_REDEEMED_VAULTS)
+        {
+            if fillable_amount >= tal_amount {
+                break;
+            }
+            fillable_amount += self.redemption_index_entries[vault_id].2 * self.stability_fee_index;
         }
+        fillable_amount.min(tal_amount)
+    }
 
-        let vault_ids: Vec<VaultId> = vaults.iter().map(|(_cr, vault_id)| *vault_id).collect();
-        let mut index: usize = 0;
+    /// Takes up to `tal_amount` of debt and its value in collateral from at most
+    /// `max_vaults` vaults with the lowest collateral ratio, after the returns offered
+    /// by compounding providers. Returns the amount redeemed.
+    pub fn redeem_on_vaults(
+        &mut self,
+        tal_amount: TAL,
+        current_btc_rate: UsdBtc,
+        collateral_type: CollateralType,
+        max_vaults: usize,
+    ) -> TAL {
+        let mut tal_amount_to_convert =
+            self.redeem_on_compounding_returns(tal_amount, current_btc_rate, collateral_type);
 
-        while tal_amount_to_convert > 0 && index < vault_ids.len() {
-            let vault = self.vault_id_to_vaults.get(&vault_ids[index]).unwrap();
+        for _ in 0..max_vaults {
+            if tal_amount_to_convert == 0 {
+                break;
+            }
+            let vault_id = match self
+                .redemption_index
+                .range((collateral_type, Ratio::from(Decimal::ZERO), 0)..)
+                .next()
+            {
+                Some((other, _, vault_id)) if *other == collateral_type => *vault_id,
+                _ => break,
+            };
+            self.settle_stability_fee(vault_id);
+            let vault = self.vault_id_to_vaults.get(&vault_id).unwrap();
 
             if vault.borrowed_tal_amount >= tal_amount_to_convert {
                 // We can convert everything on this vault
@@ -514,9 +2290,9 @@ impl State {
                 self.deduct_amount_from_vault(
                     redeemable_ckbtc_amount,
                     tal_amount_to_convert,
-                    vault_ids[index],
+                    vault_id,
                 );
-                break;
+                tal_amount_to_convert = TAL::from(0);
             } else {
                 // Convert what we can on this vault
                 let redeemable_tal_amount = vault.borrowed_tal_amount;
@@ -524,13 +2300,18 @@ impl State {
                 self.deduct_amount_from_vault(
                     redeemable_ckbtc_amount,
                     redeemable_tal_amount,
-                    vault_ids[index],
+                    vault_id,
                 );
                 tal_amount_to_convert -= redeemable_tal_amount;
-                index += 1;
             }
         }
-        debug_assert!(tal_amount_to_convert == 0);
+        if tal_amount_to_convert > 0 {
+            log!(
+                crate::INFO,
+                "[redeem_on_vaults] {tal_amount_to_convert} of the redemption left without debt to redeem"
+            );
+        }
+        tal_amount - tal_amount_to_convert
     }
 
     fn deduct_amount_from_vault(
@@ -548,6 +2329,7 @@ impl State {
             }
             None => ic_cdk::trap("cannot deduct from unknown vault"),
         }
+        self.reindex_vault(vault_id);
     }
 
     /// Checks whether the internal state of the core canister matches the other state
@@ -589,6 +2371,58 @@ impl State {
             other.principal_to_vault_ids,
             "principal_to_vault_ids does not match"
         );
+        ensure_eq!(
+            self.vault_operators,
+            other.vault_operators,
+            "vault_operators does not match"
+        );
+        ensure_eq!(
+            (self.auctions_enabled, &self.auctions, &self.bad_debt),
+            (other.auctions_enabled, &other.auctions, &other.bad_debt),
+            "auctions does not match"
+        );
+        ensure_eq!(
+            (
+                &self.pending_collateral_transfers,
+                self.next_collateral_transfer_id
+            ),
+            (
+                &other.pending_collateral_transfers,
+                other.next_collateral_transfer_id
+            ),
+            "pending_collateral_transfers does not match"
+        );
+        ensure_eq!(
+            (
+                &self.pending_stablecoin_transfers,
+                self.next_stablecoin_transfer_id
+            ),
+            (
+                &other.pending_stablecoin_transfers,
+                other.next_stablecoin_transfer_id
+            ),
+            "pending_stablecoin_transfers does not match"
+        );
+        ensure_eq!(
+            (self.global_debt_ceiling, self.principal_debt_ceiling),
+            (other.global_debt_ceiling, other.principal_debt_ceiling),
+            "debt ceilings do not match"
+        );
+        ensure_eq!(
+            (self.stability_fee_rate, self.last_stability_fee_accrual),
+            (other.stability_fee_rate, other.last_stability_fee_accrual),
+            "stability fee does not match"
+        );
+        ensure_eq!(
+            (self.stability_fee_index, &self.vault_fee_indexes),
+            (other.stability_fee_index, &other.vault_fee_indexes),
+            "stability fee indexes do not match"
+        );
+        ensure_eq!(
+            (self.liquidation_reward_rate, self.liquidation_penalty),
+            (other.liquidation_reward_rate, other.liquidation_penalty),
+            "liquidation rates do not match"
+        );
         ensure_eq!(
             self.liquidity_pool,
             other.liquidity_pool,
@@ -599,11 +2433,87 @@ impl State {
             other.liquidity_returns,
             "liquidity_returns does not match"
         );
+        ensure_eq!(
+            (self.lp_fee_share, &self.fee_returns),
+            (other.lp_fee_share, &other.fee_returns),
+            "fee_returns does not match"
+        );
+        ensure_eq!(
+            self.compounding_preferences,
+            other.compounding_preferences,
+            "compounding_preferences does not match"
+        );
+        ensure_eq!(
+            self.redemption_index,
+            other.redemption_index,
+            "redemption_index does not match"
+        );
+        ensure_eq!(
+            self.redemption_index_entries,
+            other.redemption_index_entries,
+            "redemption_index_entries does not match"
+        );
+        ensure_eq!(
+            self.redeemable_debt,
+            other.redeemable_debt,
+            "redeemable_debt does not match"
+        );
+        ensure_eq!(
+            (
+                self.withdrawal_cooldown_secs,
+                self.withdrawal_window_secs,
+                &self.withdrawal_requests
+            ),
+            (
+                other.withdrawal_cooldown_secs,
+                other.withdrawal_window_secs,
+                &other.withdrawal_requests
+            ),
+            "withdrawal_requests does not match"
+        );
         ensure_eq!(
             self.xrc_principal,
             other.xrc_principal,
             "xrc_principal does not match"
         );
+        ensure_eq!(
+            (
+                &self.oracle_xrc_principals,
+                self.fallback_oracle_principal,
+                &self.price_pushers,
+                self.max_price_deviation,
+                self.min_xrc_received_rates,
+                self.max_xrc_standard_deviation
+            ),
+            (
+                &other.oracle_xrc_principals,
+                other.fallback_oracle_principal,
+                &other.price_pushers,
+                other.max_price_deviation,
+                other.min_xrc_received_rates,
+                other.max_xrc_standard_deviation
+            ),
+            "price sources do not match"
+        );
+        ensure_eq!(
+            (
+                &self.btc_price_samples,
+                self.twap_window_secs,
+                self.max_btc_price_change,
+                self.liquidations_frozen,
+                self.borrow_price_kind,
+                self.liquidation_price_kind
+            ),
+            (
+                &other.btc_price_samples,
+                other.twap_window_secs,
+                other.max_btc_price_change,
+                other.liquidations_frozen,
+                other.borrow_price_kind,
+                other.liquidation_price_kind
+            ),
+            "btc price circuit breaker does not match"
+        );
         ensure_eq!(
             self.taler_ledger_principal,
             other.taler_ledger_principal,
@@ -619,6 +2529,46 @@ impl State {
             other.pending_redemption_transfer,
             "pending_redemption_transfer does not match"
         );
+        ensure_eq!(
+            self.collaterals
+                .iter()
+                .map(|(ledger_principal, config)| (
+                    *ledger_principal,
+                    config.minimum_collateral_ratio,
+                    config.debt_ceiling
+                ))
+                .collect::<Vec<_>>(),
+            other
+                .collaterals
+                .iter()
+                .map(|(ledger_principal, config)| (
+                    *ledger_principal,
+                    config.minimum_collateral_ratio,
+                    config.debt_ceiling
+                ))
+                .collect::<Vec<_>>(),
+            "collaterals does not match"
+        );
+        ensure_eq!(
+            self.stablecoins
+                .iter()
+                .map(|(ledger_principal, config)| (
+                    *ledger_principal,
+                    config.borrowing_fee,
+                    &config.liquidity_pool
+                ))
+                .collect::<Vec<_>>(),
+            other
+                .stablecoins
+                .iter()
+                .map(|(ledger_principal, config)| (
+                    *ledger_principal,
+                    config.borrowing_fee,
+                    &config.liquidity_pool
+                ))
+                .collect::<Vec<_>>(),
+            "stablecoins does not match"
+        );
 
         Ok(())
     }
@@ -651,6 +2601,24 @@ impl State {
     }
 }
 
+fn check_rate_not_too_old(last_timestamp: Option<u64>, asset: &str) -> Result<(), ProtocolError> {
+    let current_time = ic_cdk::api::time();
+    match last_timestamp {
+        Some(last_timestamp)
+            if current_time.saturating_sub(last_timestamp) <= MAX_BTC_PRICE_AGE_NANOS =>
+        {
+            Ok(())
+        }
+        Some(_) => Err(ProtocolError::TemporarilyUnavailable(format!(
+            "Last known {asset} price too old"
+        ))),
+        None => Err(ProtocolError::TemporarilyUnavailable(format!(
+            "No {asset} price fetched"
+        ))),
+    }
+}
+
+#[cfg(test)]
 #[derive(Debug)]
 pub(crate) struct DistributeEntry {
     pub owner: Principal,
@@ -658,8 +2626,10 @@ pub(crate) struct DistributeEntry {
     pub tal_to_debit: TAL,
 }
 
-/// Liquidate a vault by liquidity providers.
+/// Liquidate a vault by liquidity providers, the per-provider reference the
+/// stability pool accumulators are checked against.
 /// Hypothesis: sum(provided_liquidity) >= vault.tal
+#[cfg(test)]
 pub(crate) fn distribute_across_lps(
     provided_liquidity: &BTreeMap<Principal, TAL>,
     borrowed_tal_amount: TAL,
@@ -761,6 +2731,19 @@ pub(crate) fn distribute_accross_vaults(
     result
 }
 
+/// Raises `base` to the power `exponent` by squaring.
+fn compound(base: Decimal, exponent: u64) -> Decimal {
+    let (mut result, mut base, mut exponent) = (Decimal::ONE, base, exponent);
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result *= base;
+        }
+        base *= base;
+        exponent >>= 1;
+    }
+    result
+}
+
 fn compute_redemption_fee(
     elapsed_hours: u64,
     redeemed_amount: TAL,
@@ -830,6 +2813,37 @@ mod tests {
     use crate::numeric::{CKBTC, TAL};
     use std::collections::BTreeMap;
 
+    fn test_init_arg() -> InitArg {
+        InitArg {
+            fee_e8s: 0,
+            ckbtc_ledger_principal: Principal::anonymous(),
+            xrc_principal: Principal::anonymous(),
+            taler_ledger_principal: Principal::anonymous(),
+            developer_principal: Principal::anonymous(),
+            global_debt_ceiling: None,
+            principal_debt_ceiling: None,
+            stablecoins: None,
+        }
+    }
+
+    fn test_state() -> State {
+        State::from(test_init_arg())
+    }
+
+    /// Opens a vault borrowing TAL against ckBTC under the next vault id.
+    fn open_test_vault(state: &mut State, owner: Principal, margin: u64, debt: u64) -> VaultId {
+        let vault_id = state.increment_vault_id();
+        state.open_vault(Vault {
+            owner,
+            vault_id,
+            ckbtc_margin_amount: CKBTC::from(margin),
+            borrowed_tal_amount: TAL::from(debt),
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
+        });
+        vault_id
+    }
+
     #[test]
     fn test_distribute_across_on_lp() {
         // Define input data
@@ -859,12 +2873,16 @@ mod tests {
             vault_id: 1,
             ckbtc_margin_amount: CKBTC::from(500_000),
             borrowed_tal_amount: TAL::from(300_000),
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
         };
         let vault2 = Vault {
             owner: Principal::anonymous(),
             vault_id: 2,
             ckbtc_margin_amount: CKBTC::from(300_000),
             borrowed_tal_amount: TAL::from(200_000),
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
         };
         vaults.insert(1, vault1);
         vaults.insert(2, vault2);
@@ -874,6 +2892,8 @@ mod tests {
             vault_id: 3,
             ckbtc_margin_amount: CKBTC::from(700_000),
             borrowed_tal_amount: TAL::from(400_000),
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
         };
 
         // Call the function
@@ -891,6 +2911,1285 @@ mod tests {
         assert_eq!(distribute_entry2.tal_share_amount, TAL::from(150_000)); // Example calculated tal_share_amount
     }
 
+    #[test]
+    fn should_only_redistribute_across_same_collateral() {
+        let ledger = Principal::from_slice(&[1]);
+        let mut state = test_state();
+        state.add_collateral_type(AddCollateralTypeArg {
+            ledger_principal: ledger,
+            xrc_symbol: "ICP".to_string(),
+            decimals: 8,
+            minimum_collateral_ratio_e8s: 150_000_000,
+            debt_ceiling: 1_000_000,
+            ledger_fee: 10_000,
+            minimum_amount: None,
+        });
+        assert_eq!(
+            state.get_collateral_minimum_amount(CollateralType::Icrc(ledger)),
+            CKBTC::from(100_000)
+        );
+        for (vault_id, collateral_type) in [
+            (0, CollateralType::CkBtc),
+            (1, CollateralType::Icrc(ledger)),
+            (2, CollateralType::Icrc(ledger)),
+        ] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                ckbtc_margin_amount: CKBTC::from(500_000),
+                borrowed_tal_amount: TAL::from(300_000),
+                collateral_type,
+                stablecoin: StablecoinType::Tal,
+            });
+        }
+
+        state.redistribute_vault(2);
+
+        assert_eq!(
+            state.vault_id_to_vaults[&0].ckbtc_margin_amount,
+            CKBTC::from(500_000)
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&1].ckbtc_margin_amount,
+            CKBTC::from(1_000_000)
+        );
+        assert_eq!(
+            state.total_debt_value_on(CollateralType::Icrc(ledger)),
+            TAL::from(600_000)
+        );
+        assert_eq!(
+            state.get_collateral_minimum_ratio(CollateralType::Icrc(ledger)),
+            Ratio::from(dec!(1.5))
+        );
+    }
+
+    #[test]
+    fn should_fail_closed_on_unknown_peg_rates() {
+        let eur_ledger = Principal::from_slice(&[2]);
+        let eur = StablecoinType::Icrc(eur_ledger);
+        let mut state = State::from(InitArg {
+            global_debt_ceiling: Some(1_000_000_000),
+            stablecoins: Some(vec![AddStablecoinArg {
+                ledger_principal: eur_ledger,
+                symbol: "EURT".to_string(),
+                peg_symbol: "EUR".to_string(),
+                borrowing_fee_e8s: 1_000_000,
+            }]),
+            ..test_init_arg()
+        });
+        for (vault_id, stablecoin) in [(0, StablecoinType::Tal), (1, eur)] {
+            state.open_vault(Vault {
+                owner: Principal::anonymous(),
+                vault_id,
+                ckbtc_margin_amount: CKBTC::from(1_000_000),
+                borrowed_tal_amount: TAL::from(0),
+                collateral_type: CollateralType::CkBtc,
+                stablecoin,
+            });
+        }
+        let tal_vault = state.vault_id_to_vaults[&0].clone();
+        assert_eq!(state.stablecoin_without_peg_rate(), None);
+        assert!(state
+            .check_debt_ceilings(&tal_vault, TAL::from(100_000))
+            .is_ok());
+
+        state.borrow_from_vault(1, TAL::from(100_000));
+        assert_eq!(state.stablecoin_without_peg_rate(), Some(eur));
+        assert!(state
+            .check_debt_ceilings(&tal_vault, TAL::from(100_000))
+            .is_err());
+        state.mode = Mode::Recovery;
+        state.update_total_collateral_ratio_and_mode(UsdBtc::from(dec!(30_000)));
+        assert_eq!(state.mode, Mode::Recovery);
+
+        state
+            .stablecoins
+            .get_mut(&eur_ledger)
+            .unwrap()
+            .last_peg_rate = Some(Ratio::from(dec!(1.1)));
+        assert!(state
+            .check_debt_ceilings(&tal_vault, TAL::from(100_000))
+            .is_ok());
+        state.update_total_collateral_ratio_and_mode(UsdBtc::from(dec!(30_000)));
+        assert_eq!(state.mode, Mode::GeneralAvailability);
+    }
+
+    #[test]
+    fn should_keep_a_liquidity_pool_per_stablecoin() {
+        let eur_ledger = Principal::from_slice(&[2]);
+        let eur = StablecoinType::Icrc(eur_ledger);
+        let mut state = State::from(InitArg {
+            stablecoins: Some(vec![AddStablecoinArg {
+                ledger_principal: eur_ledger,
+                symbol: "EURT".to_string(),
+                peg_symbol: "EUR".to_string(),
+                borrowing_fee_e8s: 1_000_000,
+            }]),
+            ..test_init_arg()
+        });
+        state.last_btc_rate = Some(UsdBtc::from(dec!(30_000)));
+        assert_eq!(
+            state.get_collateral_rate_in(CollateralType::CkBtc, eur),
+            None
+        );
+
+        state
+            .stablecoins
+            .get_mut(&eur_ledger)
+            .unwrap()
+            .last_peg_rate = Some(Ratio::from(dec!(1.2)));
+        assert_eq!(
+            state.get_collateral_rate_in(CollateralType::CkBtc, eur),
+            Some(UsdBtc::from(dec!(25_000)))
+        );
+        assert_eq!(state.get_borrowing_fee(eur), Ratio::from(dec!(0.01)));
+
+        state.provide_liquidity(TAL::from(1_000), Principal::anonymous(), eur);
+        assert_eq!(
+            state.total_provided_liquidity_amount(StablecoinType::Tal),
+            TAL::from(0)
+        );
+        assert_eq!(state.total_provided_liquidity_amount(eur), TAL::from(1_000));
+    }
+
+    #[test]
+    fn should_queue_withdrawn_margin() {
+        let owner = Principal::from_slice(&[3]);
+        let mut state = test_state();
+        open_test_vault(&mut state, owner, 500_000, 300_000);
+
+        state.withdraw_margin_from_vault(0, CKBTC::from(200_000), None);
+
+        assert_eq!(
+            state.vault_id_to_vaults[&0].ckbtc_margin_amount,
+            CKBTC::from(300_000)
+        );
+        assert_eq!(
+            state.pending_margin_transfers[&0],
+            PendingMarginTransfer {
+                to: Account::from(owner),
+                margin: CKBTC::from(200_000),
+                collateral_type: CollateralType::CkBtc,
+            }
+        );
+
+        // Closing the vault keeps the pending withdrawal.
+        state.repay_to_vault(0, TAL::from(300_000));
+        state.close_vault(0, None);
+        assert_eq!(
+            state.pending_margin_transfers[&0].margin,
+            CKBTC::from(200_000)
+        );
+        assert_eq!(
+            state.pending_collateral_transfers[&0],
+            PendingMarginTransfer {
+                to: Account::from(owner),
+                margin: CKBTC::from(300_000),
+                collateral_type: CollateralType::CkBtc,
+            }
+        );
+    }
+
+    #[test]
+    fn should_transfer_vault_to_new_owner() {
+        let (owner, new_owner) = (Principal::from_slice(&[3]), Principal::from_slice(&[4]));
+        let mut state = test_state();
+        open_test_vault(&mut state, owner, 500_000, 300_000);
+
+        state.transfer_vault(0, new_owner);
+
+        assert_eq!(state.vault_id_to_vaults[&0].owner, new_owner);
+        assert!(state.principal_to_vault_ids[&owner].is_empty());
+        assert_eq!(
+            state.principal_to_vault_ids[&new_owner],
+            BTreeSet::from([0])
+        );
+    }
+
+    #[test]
+    fn should_clear_operators_on_vault_transfer() {
+        let (owner, operator) = (Principal::from_slice(&[3]), Principal::from_slice(&[4]));
+        let mut state = test_state();
+        open_test_vault(&mut state, owner, 500_000, 0);
+        let permissions = OperatorPermissions {
+            add_margin: true,
+            repay: true,
+            max_borrowed_amount: Some(100_000),
+        };
+
+        state.set_vault_operator(0, operator, Some(permissions));
+        assert_eq!(
+            state.get_operator_permissions(0, operator),
+            Some(permissions)
+        );
+        assert_eq!(state.get_operator_permissions(0, owner), None);
+
+        state.transfer_vault(0, Principal::from_slice(&[5]));
+        assert_eq!(state.get_operator_permissions(0, operator), None);
+        assert!(state.vault_operators.is_empty());
+    }
+
+    #[test]
+    fn should_mint_operator_borrows_to_owner() {
+        use crate::vault::borrow_recipient;
+
+        let (owner, operator) = (Principal::from_slice(&[3]), Principal::from_slice(&[4]));
+        let vault = Vault {
+            owner,
+            vault_id: 0,
+            ckbtc_margin_amount: CKBTC::from(500_000),
+            borrowed_tal_amount: TAL::from(0),
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
+        };
+        let owner_subaccount = Account {
+            owner,
+            subaccount: Some([1; 32]),
+        };
+
+        assert!(borrow_recipient(&vault, operator, Some(Account::from(operator))).is_err());
+        assert_eq!(
+            borrow_recipient(&vault, operator, None).ok(),
+            Some(Account::from(owner))
+        );
+        assert_eq!(
+            borrow_recipient(&vault, operator, Some(owner_subaccount)).ok(),
+            Some(owner_subaccount)
+        );
+        assert_eq!(
+            borrow_recipient(&vault, owner, Some(Account::from(operator))).ok(),
+            Some(Account::from(operator))
+        );
+    }
+
+    #[test]
+    fn should_merge_and_split_vaults() {
+        let owner = Principal::from_slice(&[3]);
+        let mut state = test_state();
+        for _ in 0..2 {
+            open_test_vault(&mut state, owner, 500_000, 300_000);
+        }
+
+        state.merge_vaults(0, 1);
+        assert!(!state.vault_id_to_vaults.contains_key(&1));
+        assert_eq!(
+            state.vault_id_to_vaults[&0].ckbtc_margin_amount,
+            CKBTC::from(1_000_000)
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&0].borrowed_tal_amount,
+            TAL::from(600_000)
+        );
+
+        state.split_vault(0, 2, CKBTC::from(400_000), TAL::from(100_000));
+        assert_eq!(
+            state.vault_id_to_vaults[&0].ckbtc_margin_amount,
+            CKBTC::from(600_000)
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&2].borrowed_tal_amount,
+            TAL::from(100_000)
+        );
+        assert_eq!(state.principal_to_vault_ids[&owner], BTreeSet::from([0, 2]));
+    }
+
+    #[test]
+    fn should_check_liquidation_reward() {
+        let mut state = test_state();
+        state.upgrade(UpgradeArg {
+            liquidation_penalty_e8s: Some(5_000_000),
+            liquidation_reward_e8s: Some(1_000_000),
+            ..Default::default()
+        });
+        assert!(state.check_liquidation_reward().is_ok());
+
+        state.upgrade(UpgradeArg {
+            liquidation_reward_e8s: Some(6_000_000),
+            ..Default::default()
+        });
+        assert!(state.check_liquidation_reward().is_err());
+
+        state.upgrade(UpgradeArg {
+            liquidation_penalty_e8s: Some(200_000_000),
+            liquidation_reward_e8s: Some(100_000_000),
+            ..Default::default()
+        });
+        assert!(state.check_liquidation_reward().is_err());
+    }
+
+    #[test]
+    fn should_pay_keeper_out_of_liquidated_margin() {
+        let owner = Principal::from_slice(&[3]);
+        let provider = Principal::from_slice(&[4]);
+        let keeper = Principal::from_slice(&[5]);
+        let mut state = test_state();
+        state.upgrade(UpgradeArg {
+            liquidation_reward_e8s: Some(1_000_000),
+            ..Default::default()
+        });
+        state.provide_liquidity(TAL::from(1_000_000), provider, StablecoinType::Tal);
+        open_test_vault(&mut state, owner, 1_000_000, 500_000);
+
+        assert_eq!(
+            state.compute_liquidation_split(
+                &state.vault_id_to_vaults[&0],
+                Mode::GeneralAvailability,
+                UsdBtc::from(dec!(0.5)),
+                true,
+                Some(CKBTC::from(0)),
+            ),
+            LiquidationSplit {
+                seized_margin: CKBTC::from(1_000_000),
+                keeper_reward: CKBTC::from(10_000),
+                remaining_margin: CKBTC::from(0),
+            }
+        );
+        state.liquidate_vault(
+            0,
+            Mode::GeneralAvailability,
+            UsdBtc::from(dec!(0.5)),
+            Some(Account::from(keeper)),
+            Some(CKBTC::from(0)),
+        );
+
+        assert!(state.vault_id_to_vaults.is_empty());
+        assert_eq!(
+            state.pending_collateral_transfers[&0].to,
+            Account::from(keeper)
+        );
+        assert_eq!(
+            state.pending_collateral_transfers[&0].margin,
+            CKBTC::from(10_000)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(provider, CollateralType::CkBtc),
+            CKBTC::from(990_000)
+        );
+        assert_eq!(
+            state.get_provided_liquidity(provider, StablecoinType::Tal),
+            TAL::from(500_000)
+        );
+    }
+
+    #[test]
+    fn should_leave_margin_above_penalty_in_vault() {
+        let owner = Principal::from_slice(&[3]);
+        let provider = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.provide_liquidity(TAL::from(1_000_000), provider, StablecoinType::Tal);
+        open_test_vault(&mut state, owner, 1_000_000, 400_000);
+
+        let collateral_rate = UsdBtc::from(dec!(0.5));
+        let penalty =
+            state.compute_liquidation_penalty(0, Mode::GeneralAvailability, collateral_rate);
+        assert_eq!(penalty, Some(CKBTC::from(80_000)));
+        state.liquidate_vault(0, Mode::GeneralAvailability, collateral_rate, None, penalty);
+
+        assert_eq!(
+            state.vault_id_to_vaults[&0].ckbtc_margin_amount,
+            CKBTC::from(120_000)
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&0].borrowed_tal_amount,
+            TAL::from(0)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(provider, CollateralType::CkBtc),
+            CKBTC::from(880_000)
+        );
+    }
+
+    #[test]
+    fn should_compound_liquidity_returns() {
+        use crate::liquidity_pool::CompoundingPreference;
+
+        let alice = Principal::from_slice(&[3]);
+        let bob = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.provide_liquidity(TAL::from(500_000), alice, StablecoinType::Tal);
+        state.provide_liquidity(TAL::from(500_000), bob, StablecoinType::Tal);
+        for (owner, borrowed_tal_amount) in [(Principal::anonymous(), 400_000), (alice, 100_000)] {
+            open_test_vault(&mut state, owner, 1_000_000, borrowed_tal_amount);
+        }
+        state.set_compounding_preference(alice, CompoundingPreference::AddToVault { vault_id: 1 });
+        state.set_compounding_preference(bob, CompoundingPreference::ProvideLiquidity);
+
+        let collateral_rate = UsdBtc::from(dec!(0.5));
+        state.liquidate_vault(0, Mode::GeneralAvailability, collateral_rate, None, None);
+        // Returns are only added to the vault once alice touches her deposit.
+        assert_eq!(
+            state.vault_id_to_vaults[&1].ckbtc_margin_amount,
+            CKBTC::from(1_000_000)
+        );
+        state.withdraw_liquidity(TAL::from(100_000), alice, StablecoinType::Tal);
+        assert_eq!(
+            state.vault_id_to_vaults[&1].ckbtc_margin_amount,
+            CKBTC::from(1_500_000)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(alice, CollateralType::CkBtc),
+            CKBTC::from(0)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(bob, CollateralType::CkBtc),
+            CKBTC::from(500_000)
+        );
+
+        // Redemptions are filled with the returns of bob before touching the vaults.
+        state.redeem_on_vaults(
+            TAL::from(100_000),
+            collateral_rate,
+            CollateralType::CkBtc,
+            MAX_REDEEMED_VAULTS,
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&1].borrowed_tal_amount,
+            TAL::from(100_000)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(bob, CollateralType::CkBtc),
+            CKBTC::from(300_000)
+        );
+        assert_eq!(
+            state.get_provided_liquidity(bob, StablecoinType::Tal),
+            TAL::from(400_000)
+        );
+    }
+
+    #[test]
+    fn should_expose_requested_withdrawals_to_liquidations() {
+        let provider = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.provide_liquidity(TAL::from(1_000_000), provider, StablecoinType::Tal);
+        state.request_withdrawal(provider, TAL::from(800_000), StablecoinType::Tal, 42);
+        open_test_vault(&mut state, Principal::anonymous(), 1_000_000, 400_000);
+        state.liquidate_vault(
+            0,
+            Mode::GeneralAvailability,
+            UsdBtc::from(dec!(0.5)),
+            None,
+            None,
+        );
+
+        assert_eq!(
+            state.get_pending_withdrawal(provider, StablecoinType::Tal),
+            Some(WithdrawalRequest {
+                amount: TAL::from(600_000),
+                requested_at: 42,
+            })
+        );
+        state.withdraw_liquidity(TAL::from(500_000), provider, StablecoinType::Tal);
+        assert_eq!(
+            state.get_pending_withdrawal(provider, StablecoinType::Tal),
+            Some(WithdrawalRequest {
+                amount: TAL::from(100_000),
+                requested_at: 42,
+            })
+        );
+        state.withdraw_liquidity(TAL::from(100_000), provider, StablecoinType::Tal);
+        assert_eq!(
+            state.get_pending_withdrawal(provider, StablecoinType::Tal),
+            None
+        );
+    }
+
+    #[test]
+    fn should_lapse_withdrawal_requests_after_the_window() {
+        let provider = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.withdrawal_cooldown_secs = 60;
+        state.withdrawal_window_secs = 120;
+        state.provide_liquidity(TAL::from(1_000_000), provider, StablecoinType::Tal);
+        assert!(state
+            .check_withdrawal(provider, StablecoinType::Tal, TAL::from(500_000), 0)
+            .is_err());
+
+        state.request_withdrawal(provider, TAL::from(500_000), StablecoinType::Tal, 0);
+        let unlock_time = 60 * SEC_NANOS;
+        let expiry_time = 180 * SEC_NANOS;
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(500_000),
+                unlock_time - 1
+            )
+            .is_err());
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(600_000),
+                unlock_time
+            )
+            .is_err());
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(500_000),
+                unlock_time
+            )
+            .is_ok());
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(500_000),
+                expiry_time
+            )
+            .is_err());
+
+        // Withdrawals consume the request.
+        state.withdraw_liquidity(TAL::from(300_000), provider, StablecoinType::Tal);
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(300_000),
+                unlock_time
+            )
+            .is_err());
+        assert!(state
+            .check_withdrawal(
+                provider,
+                StablecoinType::Tal,
+                TAL::from(200_000),
+                unlock_time
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn should_refund_funding_subaccount_when_borrow_fails() {
+        use crate::event::{replay, Event};
+
+        let owner = Principal::from_slice(&[3]);
+        let funding_account = Account {
+            owner,
+            subaccount: Some([7; 32]),
+        };
+        // Events recorded by open_vault_and_borrow when the mint fails.
+        let events = vec![
+            Event::Init(test_init_arg()),
+            Event::OpenVault {
+                vault: Vault {
+                    owner,
+                    vault_id: 0,
+                    ckbtc_margin_amount: CKBTC::from(1_000_000),
+                    borrowed_tal_amount: TAL::from(0),
+                    collateral_type: CollateralType::CkBtc,
+                    stablecoin: StablecoinType::Tal,
+                },
+                block_index: 1,
+                from_subaccount: funding_account.subaccount,
+            },
+            Event::CloseVault {
+                vault_id: 0,
+                block_index: None,
+                to: Some(funding_account),
+            },
+        ];
+        let state = replay(events.into_iter()).expect("failed to replay events");
+
+        assert!(state.vault_id_to_vaults.is_empty());
+        assert_eq!(
+            state.pending_margin_transfers.get(&0),
+            Some(&PendingMarginTransfer {
+                to: funding_account,
+                margin: CKBTC::from(1_000_000),
+                collateral_type: CollateralType::CkBtc,
+            })
+        );
+    }
+
+    #[test]
+    fn should_queue_failed_claims_on_replay() {
+        use crate::event::{replay, Event};
+
+        let provider = Principal::from_slice(&[4]);
+        let events = vec![
+            Event::Init(test_init_arg()),
+            Event::ProvideLiquidity {
+                amount: TAL::from(1_000_000),
+                block_index: 0,
+                caller: provider,
+                stablecoin: StablecoinType::Tal,
+                from_subaccount: None,
+            },
+            Event::OpenVault {
+                vault: Vault {
+                    owner: Principal::anonymous(),
+                    vault_id: 0,
+                    ckbtc_margin_amount: CKBTC::from(1_000_000),
+                    borrowed_tal_amount: TAL::from(400_000),
+                    collateral_type: CollateralType::CkBtc,
+                    stablecoin: StablecoinType::Tal,
+                },
+                block_index: 1,
+                from_subaccount: None,
+            },
+            Event::LiquidateVault {
+                vault_id: 0,
+                mode: Mode::GeneralAvailability,
+                btc_rate: UsdBtc::from(dec!(0.5)),
+                keeper: None,
+                penalty: None,
+                seized_margin: None,
+                keeper_reward: None,
+                remaining_margin: None,
+            },
+            Event::ClaimLiquidityReturns {
+                amount: CKBTC::from(400_000),
+                block_index: None,
+                caller: provider,
+                collateral_type: CollateralType::CkBtc,
+                to: None,
+            },
+        ];
+        let state = replay(events.into_iter()).expect("failed to replay events");
+
+        assert_eq!(
+            state.get_liquidity_returns_of(provider, CollateralType::CkBtc),
+            CKBTC::from(600_000)
+        );
+        assert_eq!(
+            state
+                .pending_collateral_transfers
+                .values()
+                .collect::<Vec<_>>(),
+            vec![&PendingMarginTransfer {
+                to: Account::from(provider),
+                margin: CKBTC::from(400_000),
+                collateral_type: CollateralType::CkBtc,
+            }]
+        );
+    }
+
+    #[test]
+    fn should_queue_failed_refunds_on_replay() {
+        use crate::event::{replay, Event};
+
+        let caller = Account::from(Principal::from_slice(&[4]));
+        let events = vec![
+            Event::Init(test_init_arg()),
+            Event::RefundStablecoin {
+                to: caller,
+                amount: TAL::from(100_000),
+                stablecoin: StablecoinType::Tal,
+                block_index: Some(0),
+            },
+            Event::RefundStablecoin {
+                to: caller,
+                amount: TAL::from(200_000),
+                stablecoin: StablecoinType::Tal,
+                block_index: None,
+            },
+            Event::RefundStablecoin {
+                to: caller,
+                amount: TAL::from(300_000),
+                stablecoin: StablecoinType::Tal,
+                block_index: None,
+            },
+            Event::StablecoinTransfered {
+                transfer_id: 0,
+                block_index: 1,
+            },
+        ];
+        let state = replay(events.into_iter()).expect("failed to replay events");
+
+        assert_eq!(
+            state
+                .pending_stablecoin_transfers
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![(
+                &1,
+                &PendingStablecoinTransfer {
+                    to: caller,
+                    amount: TAL::from(300_000),
+                    stablecoin: StablecoinType::Tal,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn should_replay_legacy_events_with_uneven_splits() {
+        use crate::event::{replay, Event};
+
+        let alice = Principal::from_slice(&[3]);
+        let bob = Principal::from_slice(&[4]);
+        let carol = Principal::from_slice(&[5]);
+        let mut events = vec![Event::Init(test_init_arg())];
+        for provider in [alice, bob, carol] {
+            events.push(Event::ProvideLiquidity {
+                amount: TAL::from(100_000),
+                block_index: 0,
+                caller: provider,
+                stablecoin: StablecoinType::Tal,
+                from_subaccount: None,
+            });
+        }
+        // The legacy accounting debited 33_334 from alice and 33_333 from the others,
+        // and credited alice with 33_334 of the margin. The pool rounds every share down.
+        events.extend([
+            Event::OpenVault {
+                vault: Vault {
+                    owner: Principal::anonymous(),
+                    vault_id: 0,
+                    ckbtc_margin_amount: CKBTC::from(100_000),
+                    borrowed_tal_amount: TAL::from(100_000),
+                    collateral_type: CollateralType::CkBtc,
+                    stablecoin: StablecoinType::Tal,
+                },
+                block_index: 1,
+                from_subaccount: None,
+            },
+            Event::LiquidateVault {
+                vault_id: 0,
+                mode: Mode::GeneralAvailability,
+                btc_rate: UsdBtc::from(dec!(0.5)),
+                keeper: None,
+                penalty: None,
+                seized_margin: None,
+                keeper_reward: None,
+                remaining_margin: None,
+            },
+            Event::WithdrawLiquidity {
+                amount: TAL::from(66_667),
+                block_index: 2,
+                caller: bob,
+                stablecoin: StablecoinType::Tal,
+                to: None,
+            },
+            Event::ClaimLiquidityReturns {
+                amount: CKBTC::from(33_334),
+                block_index: Some(3),
+                caller: alice,
+                collateral_type: CollateralType::CkBtc,
+                to: None,
+            },
+        ]);
+        let state = replay(events.into_iter()).expect("failed to replay events");
+
+        assert_eq!(
+            state.get_provided_liquidity(bob, StablecoinType::Tal),
+            TAL::from(0)
+        );
+        assert_eq!(
+            state.get_provided_liquidity(carol, StablecoinType::Tal),
+            TAL::from(66_666)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(alice, CollateralType::CkBtc),
+            CKBTC::from(0)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(carol, CollateralType::CkBtc),
+            CKBTC::from(33_333)
+        );
+    }
+
+    #[test]
+    fn should_prune_deposits_wiped_out_by_liquidation() {
+        let alice = Principal::from_slice(&[3]);
+        let bob = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.provide_liquidity(TAL::from(300_000), alice, StablecoinType::Tal);
+        state.provide_liquidity(TAL::from(100_000), bob, StablecoinType::Tal);
+        state.request_withdrawal(alice, TAL::from(100_000), StablecoinType::Tal, 42);
+
+        state.offset_liquidity_pool(
+            StablecoinType::Tal,
+            TAL::from(400_000),
+            CollateralType::CkBtc,
+            CKBTC::from(800_000),
+        );
+        assert_eq!(state.liquidity_pool.deposits().count(), 0);
+        assert_eq!(
+            state.get_pending_withdrawal(alice, StablecoinType::Tal),
+            None
+        );
+        assert_eq!(
+            state.liquidity_returns[&alice][&CollateralType::CkBtc],
+            CKBTC::from(600_000)
+        );
+        assert_eq!(
+            state.liquidity_returns[&bob][&CollateralType::CkBtc],
+            CKBTC::from(200_000)
+        );
+    }
+
+    #[test]
+    fn should_redeem_on_riskiest_vaults_first() {
+        use crate::MAX_REDEEMED_VAULTS;
+
+        let mut state = test_state();
+        let rate = UsdBtc::from(dec!(1));
+        for vault_id in 0..(MAX_REDEEMED_VAULTS as u64 + 1) {
+            open_test_vault(
+                &mut state,
+                Principal::anonymous(),
+                2_000_000 + vault_id * 1_000,
+                0,
+            );
+            state.borrow_from_vault(vault_id, TAL::from(1_000_000));
+        }
+        // The last vault becomes the riskiest one.
+        let last_vault_id = MAX_REDEEMED_VAULTS as u64;
+        state.withdraw_margin_from_vault(last_vault_id, CKBTC::from(900_000), None);
+        assert_eq!(
+            state.redemption_index.iter().next().map(|(_, _, id)| *id),
+            Some(last_vault_id)
+        );
+
+        let total_debt = TAL::from(1_000_000 * (MAX_REDEEMED_VAULTS as u64 + 1));
+        assert!(state
+            .redeemable_amount(total_debt + TAL::from(1), rate, CollateralType::CkBtc)
+            .is_err());
+        assert_eq!(
+            state
+                .redeemable_amount(total_debt, rate, CollateralType::CkBtc)
+                .ok(),
+            Some(TAL::from(1_000_000 * MAX_REDEEMED_VAULTS as u64))
+        );
+
+        assert_eq!(
+            state.redeem_on_vaults(
+                TAL::from(1_500_000),
+                rate,
+                CollateralType::CkBtc,
+                MAX_REDEEMED_VAULTS
+            ),
+            TAL::from(1_500_000)
+        );
+        assert!(!state.redemption_index_entries.contains_key(&last_vault_id));
+        assert_eq!(
+            state.vault_id_to_vaults[&0].borrowed_tal_amount,
+            TAL::from(500_000)
+        );
+        assert_eq!(
+            state.redemption_index.iter().next().map(|(_, _, id)| *id),
+            Some(1)
+        );
+        assert_eq!(
+            state.redeemable_debt[&CollateralType::CkBtc],
+            total_debt - TAL::from(1_500_000)
+        );
+
+        // Vaults past the bound are left untouched.
+        assert_eq!(
+            state.redeem_on_vaults(total_debt, rate, CollateralType::CkBtc, 2),
+            TAL::from(2_000_000)
+        );
+        assert_eq!(
+            state.vault_id_to_vaults[&3].borrowed_tal_amount,
+            TAL::from(1_000_000)
+        );
+    }
+
+    #[test]
+    fn should_tell_whether_the_btc_price_is_fresh() {
+        let mut state = test_state();
+        assert!(!state.is_price_fresh(0));
+        state.set_btc_rate(UsdBtc::from(dec!(30_000)), SEC_NANOS);
+        assert!(state.is_price_fresh(SEC_NANOS));
+        assert!(state.is_price_fresh(SEC_NANOS + MAX_BTC_PRICE_AGE_NANOS));
+        assert!(!state.is_price_fresh(SEC_NANOS + MAX_BTC_PRICE_AGE_NANOS + 1));
+    }
+
+    #[test]
+    fn should_record_btc_price_on_change_or_heartbeat() {
+        let mut state = test_state();
+        let minute = 60 * SEC_NANOS;
+        assert!(state.should_record_btc_price(UsdBtc::from(dec!(30_000)), minute));
+        state.set_btc_rate(UsdBtc::from(dec!(30_000)), minute);
+
+        assert!(!state.should_record_btc_price(UsdBtc::from(dec!(30_000)), minute));
+        assert!(!state.should_record_btc_price(UsdBtc::from(dec!(30_100)), 2 * minute));
+        assert!(state.should_record_btc_price(UsdBtc::from(dec!(30_150)), 2 * minute));
+        assert!(state.should_record_btc_price(UsdBtc::from(dec!(29_850)), 2 * minute));
+        assert!(!state.should_record_btc_price(UsdBtc::from(dec!(30_000)), 5 * minute));
+        assert!(state.should_record_btc_price(UsdBtc::from(dec!(30_000)), 6 * minute));
+    }
+
+    #[test]
+    fn should_freeze_liquidations_on_price_jumps() {
+        let mut state = test_state();
+        state.twap_window_secs = 180;
+        state.max_btc_price_change = Ratio::from(dec!(0.1));
+        let minute = 60 * SEC_NANOS;
+
+        state.set_btc_rate(UsdBtc::from(dec!(30_000)), minute);
+        state.set_btc_rate(UsdBtc::from(dec!(30_000)), 2 * minute);
+        state.set_btc_rate(UsdBtc::from(dec!(31_000)), 3 * minute);
+        assert!(!state.liquidations_frozen);
+        // A single bad sample freezes liquidations without moving the TWAP.
+        state.set_btc_rate(UsdBtc::from(dec!(15_000)), 4 * minute);
+        assert!(state.liquidations_frozen);
+        assert_eq!(
+            state.btc_twap(),
+            Some(UsdBtc::from(dec!(30_333.333333333333333333333333)))
+        );
+        assert_eq!(
+            state.get_borrow_rate_in(CollateralType::CkBtc, StablecoinType::Tal),
+            Some(UsdBtc::from(dec!(15_000)))
+        );
+        assert_eq!(
+            state.get_liquidation_rate_in(CollateralType::CkBtc, StablecoinType::Tal),
+            state.btc_twap()
+        );
+        state.set_btc_rate(UsdBtc::from(dec!(16_000)), 5 * minute);
+        assert!(!state.liquidations_frozen);
+        // Samples older than the window are dropped, except the one holding at its start.
+        assert_eq!(state.btc_price_samples.len(), 4);
+        assert_eq!(
+            state.btc_twap(),
+            Some(UsdBtc::from(dec!(25_333.333333333333333333333333)))
+        );
+    }
+
+    #[test]
+    fn should_pick_liquidation_action() {
+        use crate::{liquidation_action, partition_vaults, LiquidationAction};
+
+        let provider = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        state.last_btc_rate = Some(UsdBtc::from(dec!(1)));
+        state.provide_liquidity(TAL::from(500_000), provider, StablecoinType::Tal);
+        for borrowed_tal_amount in [400_000, 950_000, 100_000] {
+            open_test_vault(
+                &mut state,
+                Principal::from_slice(&[3]),
+                1_000_000,
+                borrowed_tal_amount,
+            );
+        }
+        state.update_total_collateral_ratio_and_mode(UsdBtc::from(dec!(0.5)));
+        state.last_btc_rate = Some(UsdBtc::from(dec!(0.5)));
+
+        let (unhealthy_vaults, healthy_vaults) = partition_vaults(&state);
+        assert_eq!(healthy_vaults.len(), 1);
+        let actions: Vec<LiquidationAction> = unhealthy_vaults
+            .iter()
+            .map(|(vault, _)| liquidation_action(&state, vault, &healthy_vaults))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![
+                LiquidationAction::Liquidate,
+                LiquidationAction::Redistribute
+            ]
+        );
+    }
+
+    #[test]
+    fn should_realize_pool_returns_on_claim() {
+        let alice = Principal::from_slice(&[3]);
+        let bob = Principal::from_slice(&[4]);
+        let mut pool = StabilityPool::default();
+        pool.deposit(alice, TAL::from(300_000));
+        pool.deposit(bob, TAL::from(100_000));
+
+        pool.offset(
+            TAL::from(200_000),
+            CollateralType::CkBtc,
+            CKBTC::from(400_000),
+        );
+        assert_eq!(pool.deposit_of(alice), TAL::from(150_000));
+        assert_eq!(
+            pool.collateral_gains_of(bob)[&CollateralType::CkBtc],
+            CKBTC::from(100_000)
+        );
+
+        let mut state = test_state();
+        state.liquidity_pool = pool;
+        state.claim_liquidity_returns(CKBTC::from(100_000), alice, CollateralType::CkBtc);
+        assert_eq!(
+            state.get_liquidity_returns_of(alice, CollateralType::CkBtc),
+            CKBTC::from(200_000)
+        );
+        assert_eq!(
+            state.total_available_returns(CollateralType::CkBtc),
+            CKBTC::from(300_000)
+        );
+
+        // A liquidation absorbing the whole pool starts a new epoch.
+        state.offset_liquidity_pool(
+            StablecoinType::Tal,
+            TAL::from(200_000),
+            CollateralType::CkBtc,
+            CKBTC::from(200_000),
+        );
+        assert_eq!(
+            state.get_provided_liquidity(alice, StablecoinType::Tal),
+            TAL::from(0)
+        );
+        assert_eq!(
+            state.get_liquidity_returns_of(bob, CollateralType::CkBtc),
+            CKBTC::from(150_000)
+        );
+        state.provide_liquidity(TAL::from(100_000), bob, StablecoinType::Tal);
+        assert_eq!(
+            state.get_provided_liquidity(bob, StablecoinType::Tal),
+            TAL::from(100_000)
+        );
+        assert_eq!(
+            state.liquidity_returns[&bob][&CollateralType::CkBtc],
+            CKBTC::from(150_000)
+        );
+    }
+
+    #[test]
+    fn should_split_fees_with_liquidity_providers() {
+        let alice = Principal::from_slice(&[3]);
+        let bob = Principal::from_slice(&[4]);
+        let developer = Principal::from_slice(&[5]);
+        let mut state = State::from(InitArg {
+            developer_principal: developer,
+            ..test_init_arg()
+        });
+        state.lp_fee_share = Ratio::from(dec!(0.25));
+
+        // Without providers the whole fee goes to the developer.
+        state.collect_fee(TAL::from(40_000), StablecoinType::Tal);
+        assert_eq!(
+            state.get_provided_liquidity(developer, StablecoinType::Tal),
+            TAL::from(40_000)
+        );
+
+        state.provide_liquidity(TAL::from(300_000), alice, StablecoinType::Tal);
+        state.provide_liquidity(TAL::from(60_000), bob, StablecoinType::Tal);
+        state.collect_fee(TAL::from(40_000), StablecoinType::Tal);
+        assert_eq!(
+            state.get_provided_liquidity(developer, StablecoinType::Tal),
+            TAL::from(70_000)
+        );
+        assert_eq!(
+            state.get_fee_returns_of(alice, StablecoinType::Tal),
+            TAL::from(7_500)
+        );
+        assert_eq!(
+            state.get_fee_returns_of(bob, StablecoinType::Tal),
+            TAL::from(1_500)
+        );
+        assert_eq!(
+            state.total_fee_returns(StablecoinType::Tal),
+            TAL::from(10_000)
+        );
+
+        // Fees are realized when the deposit changes and survive its withdrawal.
+        state.withdraw_liquidity(TAL::from(60_000), bob, StablecoinType::Tal);
+        assert_eq!(
+            state.fee_returns[&bob][&StablecoinType::Tal],
+            TAL::from(1_500)
+        );
+        state.claim_fee_returns(TAL::from(7_500), alice, StablecoinType::Tal);
+        assert_eq!(
+            state.get_fee_returns_of(alice, StablecoinType::Tal),
+            TAL::from(0)
+        );
+        assert!(!state.fee_returns.contains_key(&alice));
+    }
+
+    #[test]
+    fn should_return_auction_surplus_to_owner() {
+        let owner = Principal::from_slice(&[3]);
+        let bidder = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        open_test_vault(&mut state, owner, 1_000_000, 300_000);
+
+        state.start_auction(0, UsdBtc::from(dec!(0.5)), 0);
+        assert!(state.vault_id_to_vaults.is_empty());
+        assert!(state.principal_to_vault_ids[&owner].is_empty());
+
+        state.auction_bid(0, bidder, TAL::from(100_000), CKBTC::from(200_000), None);
+        assert_eq!(state.auctions[&0].debt, TAL::from(200_000));
+        assert_eq!(state.pending_collateral_transfers.len(), 1);
+
+        state.auction_bid(0, bidder, TAL::from(200_000), CKBTC::from(400_000), None);
+        assert!(state.auctions.is_empty());
+        assert_eq!(
+            state.pending_collateral_transfers[&1].to,
+            Account::from(bidder)
+        );
+        assert_eq!(
+            state.pending_collateral_transfers[&2].to,
+            Account::from(owner)
+        );
+        assert_eq!(
+            state.pending_collateral_transfers[&2].margin,
+            CKBTC::from(400_000)
+        );
+    }
+
+    #[test]
+    fn should_book_auction_bad_debt() {
+        let owner = Principal::from_slice(&[3]);
+        let bidder = Principal::from_slice(&[4]);
+        let mut state = test_state();
+        for _ in 0..2 {
+            open_test_vault(&mut state, owner, 1_000_000, 300_000);
+        }
+
+        state.start_auction(0, UsdBtc::from(dec!(0.2)), 0);
+        state.start_auction(1, UsdBtc::from(dec!(0.2)), 0);
+        assert_eq!(state.total_debt_value(), TAL::from(600_000));
+        assert_eq!(
+            state.total_collateral_value(UsdBtc::from(dec!(0.2))),
+            TAL::from(400_000)
+        );
+
+        state.auction_bid(0, bidder, TAL::from(200_000), CKBTC::from(1_000_000), None);
+        assert!(!state.auctions.contains_key(&0));
+        assert_eq!(state.bad_debt[&StablecoinType::Tal], TAL::from(100_000));
+        assert_eq!(state.total_debt_value(), TAL::from(400_000));
+
+        for restart in 0..crate::auction::MAX_AUCTION_RESTARTS {
+            state.reset_auction(1, UsdBtc::from(dec!(0.2)), restart);
+        }
+        assert_eq!(
+            state.auctions[&1].restarts,
+            crate::auction::MAX_AUCTION_RESTARTS
+        );
+        state.close_auction(1);
+        assert!(state.auctions.is_empty());
+        assert_eq!(state.bad_debt[&StablecoinType::Tal], TAL::from(400_000));
+        assert_eq!(state.total_debt_value(), TAL::from(400_000));
+        assert_eq!(
+            state.pending_collateral_transfers[&1].to,
+            Account::from(state.developer_principal)
+        );
+        assert_eq!(
+            state.pending_collateral_transfers[&1].margin,
+            CKBTC::from(1_000_000)
+        );
+    }
+
+    #[test]
+    fn should_accrue_stability_fee() {
+        use crate::SEC_NANOS;
+
+        let developer = Principal::from_slice(&[3]);
+        let mut state = State::from(InitArg {
+            developer_principal: developer,
+            ..test_init_arg()
+        });
+        open_test_vault(
+            &mut state,
+            Principal::anonymous(),
+            500_000_000,
+            1_000_000_000,
+        );
+        state.upgrade(UpgradeArg {
+            stability_fee_rate_e8s: Some(5_000_000),
+            ..Default::default()
+        });
+
+        assert!(state.should_accrue_stability_fee(0));
+        state.accrue_stability_fee(0);
+        assert!(!state.should_accrue_stability_fee(60 * SEC_NANOS));
+        state.accrue_stability_fee(SECONDS_PER_YEAR * SEC_NANOS);
+
+        // Compounded every second, 5% a year grows to e^0.05.
+        let borrowed_amount = state.get_vault(0).unwrap().borrowed_tal_amount;
+        assert!(borrowed_amount > TAL::from(1_051_271_000));
+        assert!(borrowed_amount < TAL::from(1_051_272_000));
+        assert_eq!(state.total_borrowed_tal_amount(), borrowed_amount);
+        assert_eq!(
+            state
+                .redeemable_amount(
+                    borrowed_amount,
+                    UsdBtc::from(dec!(20_000)),
+                    CollateralType::CkBtc
+                )
+                .ok(),
+            Some(borrowed_amount)
+        );
+
+        // The accrued fee is added to the debt and paid to the developer once the vault
+        // is touched.
+        assert_eq!(
+            state.vault_id_to_vaults[&0].borrowed_tal_amount,
+            TAL::from(1_000_000_000)
+        );
+        state.repay_to_vault(0, TAL::from(1_000_000_000));
+        assert_eq!(
+            state.vault_id_to_vaults[&0].borrowed_tal_amount,
+            borrowed_amount - TAL::from(1_000_000_000)
+        );
+        assert_eq!(
+            state.get_provided_liquidity(developer, StablecoinType::Tal),
+            borrowed_amount - TAL::from(1_000_000_000)
+        );
+        assert_eq!(state.get_vault(0).unwrap(), state.vault_id_to_vaults[&0]);
+    }
+
+    #[test]
+    fn should_compute_debt_value_per_principal() {
+        let (alice, bob) = (Principal::from_slice(&[3]), Principal::from_slice(&[4]));
+        let mut state = State::from(InitArg {
+            global_debt_ceiling: Some(1_000_000),
+            ..test_init_arg()
+        });
+        for owner in [alice, alice, bob] {
+            open_test_vault(&mut state, owner, 500_000, 100_000);
+        }
+        state.upgrade(UpgradeArg {
+            principal_debt_ceiling: Some(500_000),
+            ..Default::default()
+        });
+
+        assert_eq!(state.total_debt_value_of(alice), TAL::from(200_000));
+        assert_eq!(state.total_debt_value_of(bob), TAL::from(100_000));
+        assert_eq!(state.global_debt_ceiling, Some(TAL::from(1_000_000)));
+        assert_eq!(state.principal_debt_ceiling, Some(TAL::from(500_000)));
+
+        let alice_vault = state.vault_id_to_vaults[&0].clone();
+        let bob_vault = state.vault_id_to_vaults[&2].clone();
+        assert!(state
+            .check_debt_ceilings(&alice_vault, TAL::from(300_000))
+            .is_ok());
+        assert!(state
+            .check_debt_ceilings(&alice_vault, TAL::from(300_001))
+            .is_err());
+        assert!(state
+            .check_debt_ceilings(&bob_vault, TAL::from(600_000))
+            .is_err());
+
+        // Transfers cannot take the new owner over its ceiling either.
+        assert!(state.check_transfer_debt_ceiling(&alice_vault, bob).is_ok());
+        state.upgrade(UpgradeArg {
+            principal_debt_ceiling: Some(150_000),
+            ..Default::default()
+        });
+        assert!(state
+            .check_transfer_debt_ceiling(&alice_vault, bob)
+            .is_err());
+
+        state.upgrade(UpgradeArg {
+            clear_principal_debt_ceiling: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(state.principal_debt_ceiling, None);
+        assert!(state
+            .check_debt_ceilings(&alice_vault, TAL::from(300_001))
+            .is_ok());
+        assert!(state
+            .check_debt_ceilings(&alice_vault, TAL::from(700_001))
+            .is_err());
+
+        state.upgrade(UpgradeArg {
+            clear_global_debt_ceiling: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(state.global_debt_ceiling, None);
+        assert!(state
+            .check_debt_ceilings(&alice_vault, TAL::from(700_001))
+            .is_ok());
+    }
+
     #[test]
     fn should_compute_redemption_fee() {
         use crate::E8S;
@@ -1,16 +1,20 @@
 use crate::event::Event;
+use crate::numeric::UsdBtc;
 use ic_stable_structures::{
     log::{Log as StableLog, NoSuchEntry},
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl,
+    DefaultMemoryImpl, Memory,
 };
 use std::cell::RefCell;
 
 const LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(0);
 const LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(1);
+const PRICE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const PRICE_DATA_MEMORY_ID: MemoryId = MemoryId::new(3);
 
 type VMem = VirtualMemory<DefaultMemoryImpl>;
 type EventLog = StableLog<Vec<u8>, VMem, VMem>;
+type PriceLog<M> = StableLog<Vec<u8>, M, M>;
 
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
@@ -28,6 +32,16 @@ thread_local! {
               )
         );
 
+    /// The BTC prices accepted by the protocol, ordered by timestamp.
+    static PRICES: RefCell<PriceLog<VMem>> = MEMORY_MANAGER
+        .with(|m|
+              RefCell::new(
+                  StableLog::init(
+                      m.borrow().get(PRICE_INDEX_MEMORY_ID),
+                      m.borrow().get(PRICE_DATA_MEMORY_ID)
+                  ).expect("failed to initialize stable price log")
+              )
+        );
 }
 
 pub struct EventIterator {
@@ -95,3 +109,194 @@ pub fn record_event(event: &Event) {
             .expect("failed to append an entry to the event log")
     });
 }
+
+/// Appends a BTC price to the price history, timestamps must be increasing.
+pub fn record_price(timestamp: u64, rate: UsdBtc) {
+    PRICES.with(|prices| append_price(&prices.borrow(), timestamp, rate));
+}
+
+/// Returns the current number of prices in the price history.
+pub fn count_prices() -> u64 {
+    PRICES.with(|prices| prices.borrow().len())
+}
+
+/// Records the price of every `btc_price_update` event of the log when the price
+/// history is empty, so that it covers the prices accepted before it was introduced.
+pub fn backfill_price_history() {
+    if count_prices() > 0 {
+        return;
+    }
+    let mut last_timestamp = None;
+    for event in events() {
+        if let Event::BtcPriceUpdate {
+            rate, timestamp, ..
+        } = event
+        {
+            if last_timestamp < Some(timestamp) {
+                record_price(timestamp, rate);
+                last_timestamp = Some(timestamp);
+            }
+        }
+    }
+}
+
+/// Returns at most `max_len` BTC prices recorded between `from` and `to` included,
+/// keeping the first price of every `resolution` nanoseconds when it is not zero.
+pub fn price_history(from: u64, to: u64, resolution: u64, max_len: usize) -> Vec<(u64, UsdBtc)> {
+    PRICES.with(|prices| prices_between(&prices.borrow(), from, to, resolution, max_len))
+}
+
+fn append_price<M: Memory>(prices: &PriceLog<M>, timestamp: u64, rate: UsdBtc) {
+    let mut bytes = timestamp.to_be_bytes().to_vec();
+    bytes.extend_from_slice(&rate.serialize());
+    prices
+        .append(&bytes)
+        .expect("failed to append an entry to the price log");
+}
+
+fn read_price<M: Memory>(
+    prices: &PriceLog<M>,
+    pos: u64,
+    buf: &mut Vec<u8>,
+) -> Option<(u64, UsdBtc)> {
+    prices.read_entry(pos, buf).ok()?;
+    let timestamp = u64::from_be_bytes(buf[0..8].try_into().unwrap());
+    let rate = UsdBtc::deserialize(buf[8..24].try_into().unwrap());
+    Some((timestamp, rate))
+}
+
+/// Position of the first price recorded at or after `timestamp`, starting from `start`.
+fn first_price_from<M: Memory>(
+    prices: &PriceLog<M>,
+    start: u64,
+    timestamp: u64,
+    buf: &mut Vec<u8>,
+) -> u64 {
+    let (mut low, mut high) = (start, prices.len());
+    while low < high {
+        let middle = low + (high - low) / 2;
+        match read_price(prices, middle, buf) {
+            Some((price_timestamp, _rate)) if price_timestamp < timestamp => low = middle + 1,
+            _ => high = middle,
+        }
+    }
+    low
+}
+
+fn prices_between<M: Memory>(
+    prices: &PriceLog<M>,
+    from: u64,
+    to: u64,
+    resolution: u64,
+    max_len: usize,
+) -> Vec<(u64, UsdBtc)> {
+    let mut buf = vec![];
+    let mut history = vec![];
+    let mut pos = first_price_from(prices, 0, from, &mut buf);
+    while history.len() < max_len {
+        let (timestamp, rate) = match read_price(prices, pos, &mut buf) {
+            Some(price) if price.0 <= to => price,
+            _ => break,
+        };
+        history.push((timestamp, rate));
+        pos = match timestamp.checked_div(resolution) {
+            Some(bucket) => {
+                let next_bucket = bucket.saturating_add(1).saturating_mul(resolution);
+                first_price_from(prices, pos + 1, next_bucket, &mut buf)
+            }
+            None => pos + 1,
+        };
+    }
+    history
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use rust_decimal::Decimal;
+
+    fn price_log(timestamps: &[u64]) -> PriceLog<VectorMemory> {
+        let prices = StableLog::new(VectorMemory::default(), VectorMemory::default());
+        for timestamp in timestamps {
+            append_price(&prices, *timestamp, UsdBtc::from(Decimal::from(*timestamp)));
+        }
+        prices
+    }
+
+    fn timestamps(history: Vec<(u64, UsdBtc)>) -> Vec<u64> {
+        history
+            .into_iter()
+            .map(|(timestamp, _rate)| timestamp)
+            .collect()
+    }
+
+    #[test]
+    fn should_return_every_price_without_resolution() {
+        let prices = price_log(&[10, 20, 30, 40]);
+        assert_eq!(
+            prices_between(&prices, 0, u64::MAX, 0, usize::MAX),
+            vec![
+                (10, UsdBtc::from(Decimal::from(10))),
+                (20, UsdBtc::from(Decimal::from(20))),
+                (30, UsdBtc::from(Decimal::from(30))),
+                (40, UsdBtc::from(Decimal::from(40))),
+            ]
+        );
+        assert_eq!(
+            timestamps(prices_between(&prices, 20, 30, 0, usize::MAX)),
+            vec![20, 30]
+        );
+    }
+
+    #[test]
+    fn should_return_nothing_when_from_is_after_to() {
+        let prices = price_log(&[10, 20, 30]);
+        assert!(prices_between(&prices, 30, 10, 0, usize::MAX).is_empty());
+        assert!(prices_between(&prices, 31, u64::MAX, 0, usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn should_keep_the_first_price_of_every_bucket() {
+        let prices = price_log(&[0, 5, 9, 10, 19, 35, 40]);
+        assert_eq!(
+            timestamps(prices_between(&prices, 0, u64::MAX, 10, usize::MAX)),
+            vec![0, 10, 35, 40]
+        );
+        assert_eq!(
+            timestamps(prices_between(&prices, 5, 39, 10, usize::MAX)),
+            vec![5, 10, 35]
+        );
+    }
+
+    #[test]
+    fn should_backfill_prices_from_events() {
+        for timestamp in [10, 20, 20, 30] {
+            record_event(&Event::BtcPriceUpdate {
+                rate: UsdBtc::from(Decimal::from(timestamp)),
+                timestamp,
+                sources: vec![],
+            });
+        }
+        backfill_price_history();
+        backfill_price_history();
+        assert_eq!(
+            timestamps(price_history(0, u64::MAX, 0, usize::MAX)),
+            vec![10, 20, 30]
+        );
+    }
+
+    #[test]
+    fn should_truncate_to_max_len() {
+        let prices = price_log(&[10, 20, 30, 40]);
+        assert_eq!(
+            timestamps(prices_between(&prices, 0, u64::MAX, 0, 2)),
+            vec![10, 20]
+        );
+        assert_eq!(
+            timestamps(prices_between(&prices, 0, u64::MAX, 20, 1)),
+            vec![10]
+        );
+        assert!(prices_between(&prices, 0, u64::MAX, 0, 0).is_empty());
+    }
+}
@@ -1,13 +1,21 @@
+use crate::collateral::CollateralType;
+use crate::liquidity_pool::StabilityPool;
+use crate::numeric::{Ratio, UsdBtc};
+use crate::oracle::{PriceSample, PriceSource, DEFAULT_MAX_PRICE_DEVIATION};
+use crate::stablecoin::StablecoinType;
 use crate::Vault;
 use crate::{CKBTC, TAL};
 use candid::Principal;
 use ic_base_types::PrincipalId;
+use ic_xrc_types::{Asset, AssetClass, ExchangeRate, ExchangeRateMetadata};
 use proptest::prop_assert;
 use proptest::proptest;
 use proptest::{
     collection::{btree_map, vec as pvec},
     prelude::{any, Strategy},
 };
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use std::collections::BTreeMap;
 
 #[cfg(test)]
@@ -20,6 +28,8 @@ fn arb_vault() -> impl Strategy<Value = Vault> {
             borrowed_tal_amount: TAL::from(borrowed_tal),
             ckbtc_margin_amount: CKBTC::from(ckbtc_margin.max(1_000_000)),
             vault_id: 0,
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
         }
     })
 }
@@ -52,6 +62,8 @@ fn vault_vec_to_map(vaults_vec: Vec<Vault>) -> BTreeMap<u64, Vault> {
                 borrowed_tal_amount: vault.borrowed_tal_amount,
                 ckbtc_margin_amount: vault.ckbtc_margin_amount,
                 vault_id: counter,
+                collateral_type: vault.collateral_type,
+                stablecoin: vault.stablecoin,
             },
         );
     }
@@ -73,6 +85,8 @@ proptest! {
             borrowed_tal_amount: TAL::from(target_borrowed_tal),
             ckbtc_margin_amount: CKBTC::from(ckbtc_margin),
             vault_id: vaults.last_key_value().unwrap().1.vault_id + 1,
+            collateral_type: CollateralType::CkBtc,
+            stablecoin: StablecoinType::Tal,
         };
 
         // Ensure that the sum of ckbtc_margin in vaults is greater or equal to target_vault's ckbtc_margin
@@ -108,4 +122,116 @@ proptest! {
         assert_eq!(tal_debited.to_u64(), borrowed_tal);
         assert_eq!(ckbtc_distributed.to_u64(), vault_ckbtc_margin);
     }
+
+    #[test]
+    fn proptest_stability_pool_matches_per_provider_liquidations(
+        provided_liquidity_map in btree_map(arb_principal(), arb_usd_amount(), 1..10),
+        liquidations in pvec((0..100_u64, arb_btc_amount()), 1..6),
+    ) {
+        let mut provided_liquidity = provided_liquidity_map.clone();
+        let mut returns: BTreeMap<Principal, CKBTC> = BTreeMap::new();
+        let mut pool = StabilityPool::default();
+        for (provider, amount) in &provided_liquidity_map {
+            pool.deposit(*provider, *amount);
+        }
+
+        for (debt_percent, ckbtc_margin) in &liquidations {
+            let total_provided_liquidity: TAL = provided_liquidity.values().cloned().sum();
+            prop_assert!(pool.total_deposits() == total_provided_liquidity);
+            let debt = total_provided_liquidity * Ratio::from(Decimal::from(*debt_percent) / dec!(100));
+
+            let result = crate::state::distribute_across_lps(&provided_liquidity, debt, CKBTC::from(*ckbtc_margin));
+            for entry in result {
+                *provided_liquidity.get_mut(&entry.owner).unwrap() -= entry.tal_to_debit;
+                *returns.entry(entry.owner).or_insert(CKBTC::from(0)) += entry.ckbtc_reward;
+            }
+            pool.offset(debt, CollateralType::CkBtc, CKBTC::from(*ckbtc_margin));
+        }
+
+        // Each liquidation rounds every entry and hands the remainder to the first one.
+        let tolerance = liquidations.len() as u64 * (provided_liquidity_map.len() as u64 + 2);
+        for (provider, expected_deposit) in provided_liquidity {
+            let deposit = pool.deposit_of(provider).to_u64();
+            prop_assert!(
+                deposit.abs_diff(expected_deposit.to_u64()) <= tolerance,
+                "deposit of {provider}: {deposit}, expected: {expected_deposit}"
+            );
+            let gain = pool
+                .collateral_gains_of(provider)
+                .get(&CollateralType::CkBtc)
+                .map(|gain| gain.to_u64())
+                .unwrap_or(0);
+            let expected_gain = returns.get(&provider).map(|r| r.to_u64()).unwrap_or(0);
+            prop_assert!(
+                gain.abs_diff(expected_gain) <= tolerance,
+                "gain of {provider}: {gain}, expected: {expected_gain}"
+            );
+        }
+    }
+
+    #[test]
+    fn proptest_aggregate_price_ignores_outliers(
+        btc_rate in 10_000..200_000_u64,
+        honest_offsets in pvec(-100..100_i64, 1..8),
+        outlier_factors in pvec(2..10_u64, 0..8),
+    ) {
+        // Honest sources are within 1% of the rate, outliers are a minority at least twice it.
+        let outlier_factors: Vec<u64> = outlier_factors.into_iter().take(honest_offsets.len() - 1).collect();
+        let base = Decimal::from(btc_rate);
+        let mut samples = vec![];
+        for (i, offset) in honest_offsets.iter().enumerate() {
+            samples.push(PriceSample {
+                source: PriceSource::Xrc(PrincipalId::new_user_test_id(i as u64).0),
+                rate: UsdBtc::from(base + base * Decimal::from(*offset) / dec!(10_000)),
+                timestamp: i as u64,
+            });
+        }
+        for (i, factor) in outlier_factors.iter().enumerate() {
+            samples.push(PriceSample {
+                source: PriceSource::Pushed(PrincipalId::new_user_test_id(i as u64).0),
+                rate: UsdBtc::from(base * Decimal::from(*factor)),
+                timestamp: 0,
+            });
+        }
+
+        let price = crate::oracle::aggregate(&samples, DEFAULT_MAX_PRICE_DEVIATION).unwrap();
+        prop_assert!(price.sources.len() == honest_offsets.len());
+        prop_assert!(price.sources.iter().all(|source| matches!(source, PriceSource::Xrc(_))));
+        prop_assert!(price.rate >= UsdBtc::from(base * dec!(0.99)));
+        prop_assert!(price.rate <= UsdBtc::from(base * dec!(1.01)));
+        // Rejected samples do not date the price.
+        prop_assert!(price.timestamp == honest_offsets.len() as u64 - 1);
+    }
+
+    #[test]
+    fn proptest_xrc_confidence_thresholds(
+        rate in 1..100_000_000_000_u64,
+        standard_deviation in any::<u32>(),
+        received_rates in 0..20_usize,
+        min_received_rates in 0..20_u64,
+        max_standard_deviation_e8s in 0..100_000_000_u64,
+    ) {
+        let exchange_rate = ExchangeRate {
+            base_asset: Asset { symbol: "BTC".to_string(), class: AssetClass::Cryptocurrency },
+            quote_asset: Asset { symbol: "USD".to_string(), class: AssetClass::FiatCurrency },
+            timestamp: 0,
+            rate,
+            metadata: ExchangeRateMetadata {
+                decimals: 9,
+                base_asset_num_queried_sources: 20,
+                base_asset_num_received_rates: received_rates,
+                quote_asset_num_queried_sources: 20,
+                quote_asset_num_received_rates: 20,
+                standard_deviation: standard_deviation as u64,
+                forex_timestamp: None,
+            },
+        };
+        let max_standard_deviation = Ratio::from(Decimal::from(max_standard_deviation_e8s) / dec!(100_000_000));
+
+        let result = crate::oracle::check_xrc_confidence(&exchange_rate, min_received_rates, max_standard_deviation);
+        let enough_rates = received_rates as u64 >= min_received_rates;
+        let low_deviation = max_standard_deviation_e8s == 0
+            || Decimal::from(standard_deviation) / Decimal::from(rate) <= max_standard_deviation.0;
+        prop_assert!(result.is_ok() == (enough_rates && low_deviation));
+    }
 }
@@ -252,6 +252,9 @@ impl EllipticSetup {
             ckbtc_ledger_principal: ckbtc_ledger_id.into(),
             fee_e8s: 0,
             developer_principal: Principal::anonymous(),
+            global_debt_ceiling: None,
+            principal_debt_ceiling: None,
+            stablecoins: None,
         };
 
         let protocol_id = install_core_canister(&env, protocol_wasm(), init_args);
@@ -278,6 +281,9 @@ impl EllipticSetup {
             ckbtc_ledger_principal: self.ckbtc_ledger_id.into(),
             fee_e8s,
             developer_principal: Principal::anonymous(),
+            global_debt_ceiling: None,
+            principal_debt_ceiling: None,
+            stablecoins: None,
         };
 
         self.env
@@ -1288,7 +1294,11 @@ fn vault_id_persist_accross_upgrade() {
         elliptic.env.upgrade_canister(
             elliptic.protocol_id,
             protocol_wasm(),
-            Encode!(&ProtocolArg::Upgrade(UpgradeArg { mode: None })).unwrap(),
+            Encode!(&ProtocolArg::Upgrade(UpgradeArg {
+                mode: None,
+                ..Default::default()
+            }))
+            .unwrap(),
         ),
         Ok(_)
     );
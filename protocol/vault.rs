@@ -1,17 +1,26 @@
+use crate::collateral::CollateralType;
 use crate::event::{
-    record_add_margin_to_vault, record_borrow_from_vault, record_open_vault,
-    record_redemption_on_vaults, record_repayed_to_vault,
+    record_add_margin_to_vault, record_borrow_from_vault, record_liquidate_vault,
+    record_merge_vaults, record_open_vault, record_redemption_on_vaults, record_repayed_to_vault,
+    record_set_vault_operator, record_split_vault, record_transfer_vault,
+    record_withdraw_margin_from_vault,
 };
 use crate::guard::GuardPrincipal;
 use crate::logs::{DEBUG, INFO};
-use crate::management::{mint_tal, transfer_ckbtc_from, transfer_tal_from};
-use crate::numeric::{CKBTC, TAL};
+use crate::management::{mint_stablecoin, transfer_collateral_from, transfer_stablecoin_from};
+use crate::numeric::{Ratio, UsdBtc, CKBTC, TAL};
+use crate::stablecoin::StablecoinType;
+use crate::state::State;
 use crate::{
-    mutate_state, read_state, ProtocolError, SuccessWithFee, MIN_CKBTC_AMOUNT, MIN_TAL_AMOUNT,
+    mutate_state, read_state, ProtocolError, RedemptionSuccess, SuccessWithFee, MIN_TAL_AMOUNT,
 };
 use candid::{CandidType, Deserialize, Principal};
 use ic_canister_log::log;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
 use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
+use rust_decimal::prelude::FromPrimitive;
+use rust_decimal::Decimal;
+use rust_decimal_macros::dec;
 use serde::Serialize;
 use std::time::Duration;
 
@@ -21,18 +30,59 @@ pub struct OpenVaultSuccess {
     pub block_index: u64,
 }
 
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct OpenVaultAndBorrowSuccess {
+    pub vault_id: u64,
+    /// Block index of the margin transfer.
+    pub block_index: u64,
+    /// Block index of the borrowed stablecoin mint.
+    pub borrow_block_index: u64,
+    pub fee_amount_paid: u64,
+}
+
 #[derive(CandidType, Deserialize)]
 pub struct VaultArg {
     pub vault_id: u64,
     pub amount: u64,
 }
 
+/// Permissions granted by a vault owner to another principal.
+#[derive(CandidType, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OperatorPermissions {
+    pub add_margin: bool,
+    pub repay: bool,
+    /// Debt up to which the operator may borrow from the vault, borrowing is not allowed if not set.
+    pub max_borrowed_amount: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct VaultOperator {
+    pub operator: Principal,
+    pub permissions: OperatorPermissions,
+}
+
+/// Operations that can be delegated to a vault operator.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VaultOperation {
+    AddMargin,
+    Repay,
+    Borrow(TAL),
+    /// Withdrawing margin, transferring, closing or managing operators: owner only.
+    Manage,
+}
+
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, PartialOrd, Ord)]
 pub struct Vault {
     pub owner: Principal,
     pub borrowed_tal_amount: TAL,
     pub ckbtc_margin_amount: CKBTC,
     pub vault_id: u64,
+    /// Token backing the vault, the margin amount is expressed in its base units.
+    #[serde(default)]
+    pub collateral_type: CollateralType,
+    /// Stablecoin borrowed by the vault, the borrowed amount is expressed in its units.
+    #[serde(default)]
+    pub stablecoin: StablecoinType,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Debug)]
@@ -41,9 +91,113 @@ pub struct CandidVault {
     pub borrowed_tal_amount: u64,
     pub ckbtc_margin_amount: u64,
     pub vault_id: u64,
+    pub collateral_type: CollateralType,
+    pub stablecoin: StablecoinType,
+}
+
+impl From<Vault> for CandidVault {
+    fn from(vault: Vault) -> Self {
+        Self {
+            owner: vault.owner,
+            borrowed_tal_amount: vault.borrowed_tal_amount.to_u64(),
+            ckbtc_margin_amount: vault.ckbtc_margin_amount.to_u64(),
+            vault_id: vault.vault_id,
+            collateral_type: vault.collateral_type,
+            stablecoin: vault.stablecoin,
+        }
+    }
+}
+
+/// Account receiving the stablecoin borrowed from a vault. Operators can only borrow
+/// to the vault owner, so they cannot take the stablecoin minted against its margin.
+pub(crate) fn borrow_recipient(
+    vault: &Vault,
+    caller: Principal,
+    to: Option<Account>,
+) -> Result<Account, ProtocolError> {
+    match to {
+        Some(to) if caller != vault.owner && to.owner != vault.owner => {
+            Err(ProtocolError::CallerNotOwner)
+        }
+        Some(to) => Ok(to),
+        None => Ok(Account::from(vault.owner)),
+    }
+}
+
+/// Checks that the caller is allowed to perform the operation on the vault.
+/// Operators also hold the owner's guard so that they cannot act concurrently
+/// with the owner.
+fn check_vault_access(
+    vault: &Vault,
+    caller: Principal,
+    operation: VaultOperation,
+) -> Result<Option<GuardPrincipal>, ProtocolError> {
+    if caller == vault.owner {
+        return Ok(None);
+    }
+    let permissions = match read_state(|s| s.get_operator_permissions(vault.vault_id, caller)) {
+        Some(permissions) => permissions,
+        None => return Err(ProtocolError::CallerNotOwner),
+    };
+    let allowed = match operation {
+        VaultOperation::AddMargin => permissions.add_margin,
+        VaultOperation::Repay => permissions.repay,
+        VaultOperation::Borrow(amount) => match permissions.max_borrowed_amount {
+            Some(max_borrowed_amount) => {
+                vault.borrowed_tal_amount + amount <= TAL::from(max_borrowed_amount)
+            }
+            None => false,
+        },
+        VaultOperation::Manage => false,
+    };
+    if !allowed {
+        return Err(ProtocolError::CallerNotOwner);
+    }
+    Ok(Some(GuardPrincipal::new(vault.owner)?))
+}
+
+/// Checks that a vault with debt is above the minimum collateral ratio.
+fn check_minimum_collateral_ratio(vault: &Vault) -> Result<(), ProtocolError> {
+    if vault.borrowed_tal_amount == 0 {
+        return Ok(());
+    }
+    read_state(|s| s.check_collateral_price_not_too_old(vault.collateral_type))?;
+    read_state(|s| s.check_peg_price_not_too_old(vault.stablecoin))?;
+    let (collateral_rate, minimum_collateral_ratio) = read_state(|s| {
+        (
+            s.get_borrow_rate_in(vault.collateral_type, vault.stablecoin)
+                .expect("no collateral rate"),
+            s.get_minimum_liquidation_collateral_ratio(vault.collateral_type),
+        )
+    });
+    let collateral_ratio = crate::compute_collateral_ratio(vault, collateral_rate);
+    if collateral_ratio < minimum_collateral_ratio {
+        return Err(ProtocolError::GenericError(format!(
+            "collateral ratio of vault {} would be {} below the minimum of {}",
+            vault.vault_id,
+            collateral_ratio.to_f64(),
+            minimum_collateral_ratio.to_f64()
+        )));
+    }
+    Ok(())
 }
 
-pub async fn redeem_ckbtc(_tal_amount: u64) -> Result<SuccessWithFee, ProtocolError> {
+/// Redeems TAL for collateral taken from the TAL vaults of `collateral_type` with the
+/// lowest collateral ratio. Only the part of `_tal_amount` that
+/// [MAX_REDEEMED_VAULTS](crate::MAX_REDEEMED_VAULTS) vaults can fill is taken from the
+/// caller, and the part they can no longer fill once the TAL is transferred is refunded.
+/// The redemption is rejected when the fee is above `max_fee_percentage` or the ckBTC
+/// sent is below `min_ckbtc_out`. Both are checked before the TAL is taken, and checked
+/// again afterwards against the latest fee and price, in which case the TAL is refunded
+/// and a failed refund is queued for retry.
+pub async fn redeem_ckbtc(
+    _tal_amount: u64,
+    collateral_type: CollateralType,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+    max_fee_percentage: Option<f64>,
+    min_ckbtc_out: Option<u64>,
+) -> Result<RedemptionSuccess, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
@@ -55,32 +209,121 @@ pub async fn redeem_ckbtc(_tal_amount: u64) -> Result<SuccessWithFee, ProtocolEr
         });
     }
 
-    let current_btc_rate = read_state(|s| s.last_btc_rate.expect("no btc rate entry"));
+    let max_fee = match max_fee_percentage {
+        Some(max_fee_percentage) if max_fee_percentage.is_finite() && max_fee_percentage >= 0.0 => {
+            Ratio::from(Decimal::from_f64(max_fee_percentage).unwrap() / dec!(100))
+        }
+        Some(max_fee_percentage) => {
+            return Err(ProtocolError::GenericError(format!(
+                "invalid max fee percentage: {max_fee_percentage}"
+            )))
+        }
+        None => Ratio::from(Decimal::ONE),
+    };
+    let min_ckbtc_out = CKBTC::from(min_ckbtc_out.unwrap_or(0));
+
+    if !read_state(|s| s.is_collateral_registered(collateral_type)) {
+        return Err(ProtocolError::GenericError(format!(
+            "unknown collateral type: {collateral_type}"
+        )));
+    }
+    let collateral_rate =
+        read_state(|s| s.get_collateral_rate_in(collateral_type, StablecoinType::Tal)).ok_or_else(
+            || ProtocolError::TemporarilyUnavailable(format!("no price for {collateral_type}")),
+        )?;
+    let tal_amount =
+        read_state(|s| s.redeemable_amount(tal_amount, collateral_rate, collateral_type))?;
+
+    let base_fee = read_state(|s| s.get_redemption_fee(tal_amount));
+    if base_fee > max_fee {
+        return Err(ProtocolError::RedemptionFeeTooHigh {
+            fee_percentage: base_fee.to_f64() * 100.0,
+            max_fee_percentage: max_fee.to_f64() * 100.0,
+        });
+    }
+    let ckbtc_amount = read_state(|s| {
+        redeemed_ckbtc_amount(
+            s,
+            tal_amount,
+            tal_amount * base_fee,
+            collateral_rate,
+            collateral_type,
+        )
+    });
+    if ckbtc_amount < min_ckbtc_out {
+        return Err(ProtocolError::RedeemedAmountTooLow {
+            ckbtc_amount: ckbtc_amount.to_u64(),
+            min_ckbtc_out: min_ckbtc_out.to_u64(),
+        });
+    }
 
-    match transfer_tal_from(tal_amount, caller).await {
+    match transfer_stablecoin_from(tal_amount, caller, from_subaccount, StablecoinType::Tal).await {
         Ok(block_index) => {
-            let fee_amount = mutate_state(|s| {
-                let base_fee = s.get_redemption_fee(tal_amount);
+            let redemption = mutate_state(|s| {
+                // Vaults may have been repaid and the price or the fee may have moved
+                // during the transfer.
+                let collateral_rate = s
+                    .get_collateral_rate_in(collateral_type, StablecoinType::Tal)
+                    .expect("no collateral rate");
+                let filled_amount = s.fillable_amount(tal_amount, collateral_rate, collateral_type);
+                if filled_amount == 0 {
+                    return Err(ProtocolError::GenericError(
+                        "no debt left to redeem".to_string(),
+                    ));
+                }
+                let base_fee = s.get_redemption_fee(filled_amount);
+                if base_fee > max_fee {
+                    return Err(ProtocolError::RedemptionFeeTooHigh {
+                        fee_percentage: base_fee.to_f64() * 100.0,
+                        max_fee_percentage: max_fee.to_f64() * 100.0,
+                    });
+                }
+                let fee_amount = filled_amount * base_fee;
+                let ckbtc_amount = redeemed_ckbtc_amount(
+                    s,
+                    filled_amount,
+                    fee_amount,
+                    collateral_rate,
+                    collateral_type,
+                );
+                if ckbtc_amount < min_ckbtc_out {
+                    return Err(ProtocolError::RedeemedAmountTooLow {
+                        ckbtc_amount: ckbtc_amount.to_u64(),
+                        min_ckbtc_out: min_ckbtc_out.to_u64(),
+                    });
+                }
                 s.current_base_rate = base_fee;
                 s.last_redemption_time = ic_cdk::api::time();
-                let fee_amount = tal_amount * base_fee;
 
                 record_redemption_on_vaults(
                     s,
                     caller,
-                    tal_amount - fee_amount,
+                    filled_amount - fee_amount,
                     fee_amount,
-                    current_btc_rate,
+                    collateral_rate,
+                    collateral_type,
+                    from_subaccount,
+                    to,
                     block_index,
                 );
-                fee_amount
+                Ok((filled_amount, fee_amount, ckbtc_amount))
             });
+            let (filled_amount, fee_amount, ckbtc_amount) = match redemption {
+                Ok(redemption) => redemption,
+                Err(error) => {
+                    refund_redemption(tal_amount, caller, from_subaccount).await;
+                    return Err(error);
+                }
+            };
+            refund_redemption(tal_amount - filled_amount, caller, from_subaccount).await;
             ic_cdk_timers::set_timer(std::time::Duration::from_secs(0), || {
                 ic_cdk::spawn(crate::process_pending_transfer())
             });
-            Ok(SuccessWithFee {
+            Ok(RedemptionSuccess {
                 block_index,
                 fee_amount_paid: fee_amount.to_u64(),
+                tal_amount_redeemed: filled_amount.to_u64(),
+                ckbtc_amount: ckbtc_amount.to_u64(),
             })
         }
         Err(transfer_from_error) => Err(ProtocolError::TransferFromError(
@@ -90,19 +333,109 @@ pub async fn redeem_ckbtc(_tal_amount: u64) -> Result<SuccessWithFee, ProtocolEr
     }
 }
 
-pub async fn open_vault(ckbtc_margin: u64) -> Result<OpenVaultSuccess, ProtocolError> {
-    let caller = ic_cdk::api::caller();
-    let _guard_principal = GuardPrincipal::new(caller)?;
+/// Mints back the TAL of a redemption that could not be filled, failed refunds are
+/// queued for retry.
+async fn refund_redemption(amount: TAL, caller: Principal, from_subaccount: Option<Subaccount>) {
+    let to = Account {
+        owner: caller,
+        subaccount: from_subaccount,
+    };
+    crate::refund_stablecoin(amount, to, StablecoinType::Tal).await;
+}
 
-    let ckbtc_margin_amount = ckbtc_margin.into();
+/// Collateral received for redeeming `tal_amount`, after the redemption fee and the
+/// ledger fee.
+fn redeemed_ckbtc_amount(
+    state: &State,
+    tal_amount: TAL,
+    fee_amount: TAL,
+    collateral_rate: UsdBtc,
+    collateral_type: CollateralType,
+) -> CKBTC {
+    let margin: CKBTC = (tal_amount - fee_amount) / collateral_rate;
+    margin.saturating_sub(state.get_collateral_ledger_fee(collateral_type))
+}
 
-    if ckbtc_margin_amount < MIN_CKBTC_AMOUNT {
+/// Fails when `amount` is below the smallest margin accepted for the collateral.
+fn check_margin_amount(
+    amount: CKBTC,
+    collateral_type: CollateralType,
+) -> Result<(), ProtocolError> {
+    let minimum_amount = read_state(|s| s.get_collateral_minimum_amount(collateral_type));
+    if amount < minimum_amount {
         return Err(ProtocolError::AmountTooLow {
-            minimum_amount: MIN_CKBTC_AMOUNT.to_u64(),
+            minimum_amount: minimum_amount.to_u64(),
         });
     }
+    Ok(())
+}
+
+fn check_open_vault(
+    ckbtc_margin_amount: CKBTC,
+    collateral_type: CollateralType,
+    stablecoin: StablecoinType,
+) -> Result<(), ProtocolError> {
+    if !read_state(|s| s.is_collateral_registered(collateral_type)) {
+        return Err(ProtocolError::GenericError(format!(
+            "unknown collateral type: {collateral_type}"
+        )));
+    }
+
+    check_margin_amount(ckbtc_margin_amount, collateral_type)?;
+
+    if !read_state(|s| s.is_stablecoin_registered(stablecoin)) {
+        return Err(ProtocolError::GenericError(format!(
+            "unknown stablecoin: {stablecoin}"
+        )));
+    }
+    Ok(())
+}
+
+/// Checks that borrowing `amount` keeps the vault above the minimum collateral ratio
+/// and under the global, owner and collateral debt ceilings, returns the borrowing fee.
+fn check_borrow(vault: &Vault, amount: TAL) -> Result<TAL, ProtocolError> {
+    read_state(|s| s.check_collateral_price_not_too_old(vault.collateral_type))?;
+    read_state(|s| s.check_peg_price_not_too_old(vault.stablecoin))?;
+    let last_btc_rate = read_state(|s| {
+        s.get_borrow_rate_in(vault.collateral_type, vault.stablecoin)
+            .expect("no collateral rate")
+    });
+
+    let max_borrowable_amount = vault.ckbtc_margin_amount * last_btc_rate
+        / read_state(|s| s.get_minimum_liquidation_collateral_ratio(vault.collateral_type));
+
+    if vault.borrowed_tal_amount + amount > max_borrowable_amount {
+        return Err(ProtocolError::GenericError(format!("failed to borrow from vault, max borrowable amount: {max_borrowable_amount}, already borrowed: {}, asked to borrow {amount} \n last_btc_rate: {last_btc_rate}", vault.borrowed_tal_amount)));
+    }
+
+    read_state(|s| s.check_debt_ceilings(vault, amount))?;
+
+    Ok(read_state(|s| {
+        amount * s.get_borrowing_fee(vault.stablecoin)
+    }))
+}
 
-    match transfer_ckbtc_from(ckbtc_margin_amount, caller).await {
+pub async fn open_vault(
+    ckbtc_margin: u64,
+    collateral_type: CollateralType,
+    stablecoin: StablecoinType,
+    from_subaccount: Option<Subaccount>,
+) -> Result<OpenVaultSuccess, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let ckbtc_margin_amount = ckbtc_margin.into();
+
+    check_open_vault(ckbtc_margin_amount, collateral_type, stablecoin)?;
+
+    match transfer_collateral_from(
+        ckbtc_margin_amount,
+        caller,
+        from_subaccount,
+        collateral_type,
+    )
+    .await
+    {
         Ok(block_index) => {
             let vault_id = mutate_state(|s| {
                 let vault_id = s.increment_vault_id();
@@ -113,12 +446,18 @@ pub async fn open_vault(ckbtc_margin: u64) -> Result<OpenVaultSuccess, ProtocolE
                         borrowed_tal_amount: 0.into(),
                         ckbtc_margin_amount,
                         vault_id,
+                        collateral_type,
+                        stablecoin,
                     },
+                    from_subaccount,
                     block_index,
                 );
                 vault_id
             });
-            log!(INFO, "[open_vault] opened vault with id: {vault_id}");
+            log!(
+                INFO,
+                "[open_vault] opened vault with id: {vault_id} backed by {collateral_type} borrowing {stablecoin}"
+            );
             Ok(OpenVaultSuccess {
                 vault_id,
                 block_index,
@@ -131,7 +470,7 @@ pub async fn open_vault(ckbtc_margin: u64) -> Result<OpenVaultSuccess, ProtocolE
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.ckbtc_ledger_fee = CKBTC::from(expected_fee);
+                    s.set_collateral_ledger_fee(collateral_type, CKBTC::from(expected_fee));
                 });
             };
             Err(ProtocolError::TransferFromError(
@@ -142,11 +481,21 @@ pub async fn open_vault(ckbtc_margin: u64) -> Result<OpenVaultSuccess, ProtocolE
     }
 }
 
-pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, ProtocolError> {
+pub async fn open_vault_and_borrow(
+    ckbtc_margin: u64,
+    tal_amount: u64,
+    collateral_type: CollateralType,
+    stablecoin: StablecoinType,
+    from_subaccount: Option<Subaccount>,
+    to: Option<Account>,
+) -> Result<OpenVaultAndBorrowSuccess, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let amount: TAL = arg.amount.into();
+    let ckbtc_margin_amount: CKBTC = ckbtc_margin.into();
+    let amount: TAL = tal_amount.into();
+
+    check_open_vault(ckbtc_margin_amount, collateral_type, stablecoin)?;
 
     if amount < MIN_TAL_AMOUNT {
         return Err(ProtocolError::AmountTooLow {
@@ -154,33 +503,118 @@ pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, Protocol
         });
     }
 
-    let (vault_id, amount) = (arg.vault_id, amount);
+    let mut vault = Vault {
+        owner: caller,
+        borrowed_tal_amount: 0.into(),
+        ckbtc_margin_amount,
+        vault_id: 0,
+        collateral_type,
+        stablecoin,
+    };
+    let fee: TAL = check_borrow(&vault, amount)?;
 
-    let (vault, last_btc_rate) = read_state(|s| {
-        (
-            s.vault_id_to_vaults.get(&vault_id).cloned().unwrap(),
-            s.last_btc_rate.expect("no btc rate"),
-        )
+    let block_index = match transfer_collateral_from(
+        ckbtc_margin_amount,
+        caller,
+        from_subaccount,
+        collateral_type,
+    )
+    .await
+    {
+        Ok(block_index) => block_index,
+        Err(transfer_from_error) => {
+            if let TransferFromError::BadFee { expected_fee } = transfer_from_error.clone() {
+                mutate_state(|s| {
+                    let expected_fee: u64 = expected_fee
+                        .0
+                        .try_into()
+                        .expect("failed to convert Nat to u64");
+                    s.set_collateral_ledger_fee(collateral_type, CKBTC::from(expected_fee));
+                });
+            };
+            return Err(ProtocolError::TransferFromError(
+                transfer_from_error,
+                ckbtc_margin_amount.to_u64(),
+            ));
+        }
+    };
+
+    let vault_id = mutate_state(|s| {
+        vault.vault_id = s.increment_vault_id();
+        record_open_vault(s, vault.clone(), from_subaccount, block_index);
+        vault.vault_id
     });
 
-    if caller != vault.owner {
-        return Err(ProtocolError::CallerNotOwner);
+    match mint_stablecoin(
+        amount - fee,
+        to.unwrap_or(Account::from(caller)),
+        stablecoin,
+    )
+    .await
+    {
+        Ok(borrow_block_index) => {
+            mutate_state(|s| {
+                record_borrow_from_vault(s, vault_id, amount, fee, to, borrow_block_index);
+            });
+            log!(
+                INFO,
+                "[open_vault_and_borrow] opened vault {vault_id} backed by {collateral_type} and borrowed {amount} {stablecoin} with a fee of {fee}"
+            );
+            Ok(OpenVaultAndBorrowSuccess {
+                vault_id,
+                block_index,
+                borrow_block_index,
+                fee_amount_paid: fee.to_u64(),
+            })
+        }
+        Err(mint_error) => {
+            log!(
+                INFO,
+                "[open_vault_and_borrow] failed to mint {stablecoin}, closing vault {vault_id} and returning its margin: {mint_error:?}"
+            );
+            // Return the margin to the subaccount it was taken from.
+            let from = Account {
+                owner: caller,
+                subaccount: from_subaccount,
+            };
+            mutate_state(|s| crate::event::record_close_vault(s, vault_id, None, Some(from)));
+            ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+                ic_cdk::spawn(crate::process_pending_transfer())
+            });
+            Err(ProtocolError::TransferError(mint_error))
+        }
     }
+}
 
-    let max_borrowable_amount = vault.ckbtc_margin_amount * last_btc_rate
-        / read_state(|s| s.mode.get_minimum_liquidation_collateral_ratio());
+pub async fn borrow_from_vault(
+    arg: VaultArg,
+    to: Option<Account>,
+) -> Result<SuccessWithFee, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
 
-    if vault.borrowed_tal_amount + amount > max_borrowable_amount {
-        return Err(ProtocolError::GenericError(format!("failed to borrow from vault, max borrowable amount: {max_borrowable_amount}, already borrowed: {}, asked to borrow {amount} \n last_btc_rate: {last_btc_rate}", vault.borrowed_tal_amount)));
+    let amount: TAL = arg.amount.into();
+
+    if amount < MIN_TAL_AMOUNT {
+        return Err(ProtocolError::AmountTooLow {
+            minimum_amount: MIN_TAL_AMOUNT.to_u64(),
+        });
     }
 
-    let fee: TAL = read_state(|s| amount * s.get_borrowing_fee());
+    let (vault_id, amount) = (arg.vault_id, amount);
+
+    let vault = read_state(|s| s.get_vault(vault_id).unwrap());
+
+    let _guard_owner = check_vault_access(&vault, caller, VaultOperation::Borrow(amount))?;
+    let recipient = borrow_recipient(&vault, caller, to)?;
 
-    match mint_tal(amount - fee, caller).await {
+    let fee: TAL = check_borrow(&vault, amount)?;
+
+    match mint_stablecoin(amount - fee, recipient, vault.stablecoin).await {
         Ok(block_index) => {
             log!(DEBUG, "[borrow_from_vault] {caller} borrowed {amount}, from vault {vault_id} with a fee of {fee} at block {block_index}");
             mutate_state(|s| {
-                record_borrow_from_vault(s, vault_id, amount, fee, block_index);
+                record_borrow_from_vault(s, vault_id, amount, fee, to, block_index);
             });
             Ok(SuccessWithFee {
                 block_index,
@@ -191,16 +625,17 @@ pub async fn borrow_from_vault(arg: VaultArg) -> Result<SuccessWithFee, Protocol
     }
 }
 
-pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+pub async fn repay_to_vault(
+    arg: VaultArg,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let vault = read_state(|s| s.vault_id_to_vaults.get(&arg.vault_id).cloned().unwrap());
+    let vault = read_state(|s| s.get_vault(arg.vault_id).unwrap());
     let amount: TAL = arg.amount.into();
 
-    if caller != vault.owner {
-        return Err(ProtocolError::CallerNotOwner);
-    }
+    let _guard_owner = check_vault_access(&vault, caller, VaultOperation::Repay)?;
 
     if amount < MIN_TAL_AMOUNT {
         return Err(ProtocolError::AmountTooLow {
@@ -216,7 +651,7 @@ pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
         )));
     }
 
-    match transfer_tal_from(amount, caller).await {
+    match transfer_stablecoin_from(amount, caller, from_subaccount, vault.stablecoin).await {
         Ok(block_index) => {
             log!(
                 DEBUG,
@@ -224,7 +659,9 @@ pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
                 arg.amount,
                 arg.vault_id
             );
-            mutate_state(|s| record_repayed_to_vault(s, arg.vault_id, amount, block_index));
+            mutate_state(|s| {
+                record_repayed_to_vault(s, arg.vault_id, amount, from_subaccount, block_index)
+            });
             Ok(block_index)
         }
         Err(transfer_from_error) => Err(ProtocolError::TransferFromError(
@@ -234,25 +671,22 @@ pub async fn repay_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
     }
 }
 
-pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
+pub async fn add_margin_to_vault(
+    arg: VaultArg,
+    from_subaccount: Option<Subaccount>,
+) -> Result<u64, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
     let amount = arg.amount.into();
 
-    if amount < MIN_CKBTC_AMOUNT {
-        return Err(ProtocolError::AmountTooLow {
-            minimum_amount: MIN_CKBTC_AMOUNT.to_u64(),
-        });
-    }
+    let vault = read_state(|s| s.get_vault(arg.vault_id).unwrap());
 
-    let vault = read_state(|s| s.vault_id_to_vaults.get(&arg.vault_id).cloned().unwrap());
+    let _guard_owner = check_vault_access(&vault, caller, VaultOperation::AddMargin)?;
 
-    if caller != vault.owner {
-        return Err(ProtocolError::CallerNotOwner);
-    }
+    check_margin_amount(amount, vault.collateral_type)?;
 
-    match transfer_ckbtc_from(amount, caller).await {
+    match transfer_collateral_from(amount, caller, from_subaccount, vault.collateral_type).await {
         Ok(block_index) => {
             log!(
                 DEBUG,
@@ -260,7 +694,9 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
                 amount,
                 arg.vault_id
             );
-            mutate_state(|s| record_add_margin_to_vault(s, arg.vault_id, amount, block_index));
+            mutate_state(|s| {
+                record_add_margin_to_vault(s, arg.vault_id, amount, from_subaccount, block_index)
+            });
             Ok(block_index)
         }
         Err(error) => {
@@ -270,7 +706,7 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
                         .0
                         .try_into()
                         .expect("failed to convert Nat to u64");
-                    s.ckbtc_ledger_fee = CKBTC::from(expected_fee);
+                    s.set_collateral_ledger_fee(vault.collateral_type, CKBTC::from(expected_fee));
                 });
             };
             Err(ProtocolError::TransferFromError(error, amount.to_u64()))
@@ -278,36 +714,247 @@ pub async fn add_margin_to_vault(arg: VaultArg) -> Result<u64, ProtocolError> {
     }
 }
 
+pub async fn withdraw_margin_from_vault(
+    arg: VaultArg,
+    to: Option<Account>,
+) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let amount: CKBTC = arg.amount.into();
+
+    let vault = read_state(|s| s.get_vault(arg.vault_id).unwrap());
+
+    check_vault_access(&vault, caller, VaultOperation::Manage)?;
+
+    check_margin_amount(amount, vault.collateral_type)?;
+
+    if amount > vault.ckbtc_margin_amount {
+        return Err(ProtocolError::GenericError(format!(
+            "cannot withdraw more than the vault margin, margin: {}, asked to withdraw: {amount}",
+            vault.ckbtc_margin_amount
+        )));
+    }
+
+    if read_state(|s| s.pending_margin_transfers.contains_key(&arg.vault_id)) {
+        return Err(ProtocolError::TemporarilyUnavailable(format!(
+            "a margin transfer is already pending for vault {}",
+            arg.vault_id
+        )));
+    }
+
+    check_minimum_collateral_ratio(&Vault {
+        ckbtc_margin_amount: vault.ckbtc_margin_amount - amount,
+        ..vault.clone()
+    })?;
+
+    mutate_state(|s| record_withdraw_margin_from_vault(s, arg.vault_id, amount, to));
+    log!(
+        DEBUG,
+        "[withdraw_margin_from_vault] {caller} withdrew margin {amount} from vault {}",
+        arg.vault_id
+    );
+    ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+        ic_cdk::spawn(crate::process_pending_transfer())
+    });
+    Ok(())
+}
+
+pub fn merge_vaults(target_vault_id: u64, source_vault_id: u64) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    if target_vault_id == source_vault_id {
+        return Err(ProtocolError::GenericError(
+            "cannot merge a vault into itself".to_string(),
+        ));
+    }
+
+    let (target, source) =
+        match read_state(|s| (s.get_vault(target_vault_id), s.get_vault(source_vault_id))) {
+            (Some(target), Some(source)) => (target, source),
+            _ => {
+                return Err(ProtocolError::GenericError(format!(
+                    "unknown vault: {target_vault_id} or {source_vault_id}"
+                )))
+            }
+        };
+
+    check_vault_access(&target, caller, VaultOperation::Manage)?;
+    check_vault_access(&source, caller, VaultOperation::Manage)?;
+
+    if target.collateral_type != source.collateral_type || target.stablecoin != source.stablecoin {
+        return Err(ProtocolError::GenericError(
+            "only vaults with the same collateral and stablecoin can be merged".to_string(),
+        ));
+    }
+
+    check_minimum_collateral_ratio(&Vault {
+        ckbtc_margin_amount: target.ckbtc_margin_amount + source.ckbtc_margin_amount,
+        borrowed_tal_amount: target.borrowed_tal_amount + source.borrowed_tal_amount,
+        ..target
+    })?;
+
+    mutate_state(|s| record_merge_vaults(s, target_vault_id, source_vault_id));
+    log!(
+        INFO,
+        "[merge_vaults] {caller} merged vault {source_vault_id} into vault {target_vault_id}"
+    );
+    Ok(())
+}
+
+pub fn split_vault(vault_id: u64, margin: u64, debt: u64) -> Result<u64, ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let margin: CKBTC = margin.into();
+    let debt: TAL = debt.into();
+
+    let vault = match read_state(|s| s.get_vault(vault_id)) {
+        Some(vault) => vault,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "unknown vault: {vault_id}"
+            )))
+        }
+    };
+
+    check_vault_access(&vault, caller, VaultOperation::Manage)?;
+
+    check_margin_amount(margin, vault.collateral_type)?;
+
+    if margin >= vault.ckbtc_margin_amount || debt > vault.borrowed_tal_amount {
+        return Err(ProtocolError::GenericError(format!(
+            "cannot split more than the vault holds, margin: {}, debt: {}",
+            vault.ckbtc_margin_amount, vault.borrowed_tal_amount
+        )));
+    }
+
+    check_minimum_collateral_ratio(&Vault {
+        ckbtc_margin_amount: vault.ckbtc_margin_amount - margin,
+        borrowed_tal_amount: vault.borrowed_tal_amount - debt,
+        ..vault.clone()
+    })?;
+    check_minimum_collateral_ratio(&Vault {
+        ckbtc_margin_amount: margin,
+        borrowed_tal_amount: debt,
+        ..vault.clone()
+    })?;
+
+    let new_vault_id = mutate_state(|s| {
+        let new_vault_id = s.increment_vault_id();
+        record_split_vault(s, vault_id, new_vault_id, margin, debt);
+        new_vault_id
+    });
+    log!(
+        INFO,
+        "[split_vault] {caller} split vault {new_vault_id} with margin {margin} and debt {debt} out of vault {vault_id}"
+    );
+    Ok(new_vault_id)
+}
+
+pub fn transfer_vault(vault_id: u64, new_owner: Principal) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let vault = match read_state(|s| s.get_vault(vault_id)) {
+        Some(vault) => vault,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "unknown vault: {vault_id}"
+            )))
+        }
+    };
+
+    check_vault_access(&vault, caller, VaultOperation::Manage)?;
+
+    if new_owner == Principal::anonymous() {
+        return Err(ProtocolError::AnonymousCallerNotAllowed);
+    }
+
+    if new_owner == vault.owner {
+        return Err(ProtocolError::GenericError(
+            "vault is already owned by this principal".to_string(),
+        ));
+    }
+
+    read_state(|s| s.check_transfer_debt_ceiling(&vault, new_owner))?;
+
+    mutate_state(|s| record_transfer_vault(s, vault_id, new_owner));
+    log!(
+        INFO,
+        "[transfer_vault] transferred vault {vault_id} from {caller} to {new_owner}"
+    );
+    Ok(())
+}
+
+pub fn set_vault_operator(
+    vault_id: u64,
+    operator: Principal,
+    permissions: Option<OperatorPermissions>,
+) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let vault = match read_state(|s| s.get_vault(vault_id)) {
+        Some(vault) => vault,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "unknown vault: {vault_id}"
+            )))
+        }
+    };
+
+    check_vault_access(&vault, caller, VaultOperation::Manage)?;
+
+    if operator == Principal::anonymous() || operator == vault.owner {
+        return Err(ProtocolError::GenericError(
+            "operator must be a principal other than the owner".to_string(),
+        ));
+    }
+
+    mutate_state(|s| record_set_vault_operator(s, vault_id, operator, permissions));
+    log!(
+        INFO,
+        "[set_vault_operator] set operator {operator} of vault {vault_id} to {permissions:?}"
+    );
+    Ok(())
+}
+
 pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
     let caller = ic_cdk::api::caller();
     let _guard_principal = GuardPrincipal::new(caller)?;
 
-    let vault = read_state(|s| s.vault_id_to_vaults.get(&vault_id).cloned().unwrap());
-    if caller != vault.owner {
-        return Err(ProtocolError::CallerNotOwner);
+    let vault = read_state(|s| s.get_vault(vault_id).unwrap());
+    check_vault_access(&vault, caller, VaultOperation::Manage)?;
+
+    if read_state(|s| s.pending_margin_transfers.contains_key(&vault_id)) {
+        return Err(ProtocolError::TemporarilyUnavailable(format!(
+            "a margin transfer is already pending for vault {vault_id}"
+        )));
     }
 
-    let amount_to_pay_off = read_state(|s| match s.vault_id_to_vaults.get(&vault_id) {
+    let amount_to_pay_off = read_state(|s| match s.get_vault(vault_id) {
         Some(vault) => vault.borrowed_tal_amount,
         None => panic!("vault not found"),
     });
     if amount_to_pay_off == 0 {
         mutate_state(|s| {
-            crate::event::record_close_vault(s, vault_id, None);
+            crate::event::record_close_vault(s, vault_id, None, None);
         });
         ic_cdk_timers::set_timer(Duration::from_secs(0), || {
             ic_cdk::spawn(crate::process_pending_transfer())
         });
         return Ok(None);
     }
-    match transfer_tal_from(amount_to_pay_off, caller).await {
+    match transfer_stablecoin_from(amount_to_pay_off, caller, None, vault.stablecoin).await {
         Ok(block_index) => {
             log!(
                 DEBUG,
                 "[close_vault] closed vault {vault_id} at block {block_index}"
             );
             mutate_state(|s| {
-                crate::event::record_close_vault(s, vault_id, Some(block_index));
+                crate::event::record_close_vault(s, vault_id, Some(block_index), None);
             });
             ic_cdk_timers::set_timer(Duration::from_secs(0), || {
                 ic_cdk::spawn(crate::process_pending_transfer())
@@ -320,3 +967,68 @@ pub async fn close_vault(vault_id: u64) -> Result<Option<u64>, ProtocolError> {
         )),
     }
 }
+
+/// Liquidates a vault below the minimum collateral ratio to the liquidity pool,
+/// rewarding the caller with a share of the liquidated margin.
+pub fn liquidate(vault_id: u64, to: Option<Account>) -> Result<(), ProtocolError> {
+    let caller = ic_cdk::api::caller();
+    let _guard_principal = GuardPrincipal::new(caller)?;
+
+    let vault = match read_state(|s| s.get_vault(vault_id)) {
+        Some(vault) => vault,
+        None => {
+            return Err(ProtocolError::GenericError(format!(
+                "unknown vault: {vault_id}"
+            )))
+        }
+    };
+    // Keep the owner from operating on the vault while it is liquidated.
+    let _guard_owner = if vault.owner != caller {
+        Some(GuardPrincipal::new(vault.owner)?)
+    } else {
+        None
+    };
+
+    if read_state(|s| s.liquidations_frozen) {
+        return Err(ProtocolError::TemporarilyUnavailable(
+            "liquidations are frozen until the btc rate settles".to_string(),
+        ));
+    }
+    read_state(|s| s.check_collateral_price_not_too_old(vault.collateral_type))?;
+    read_state(|s| s.check_peg_price_not_too_old(vault.stablecoin))?;
+    let (collateral_rate, minimum_collateral_ratio) = read_state(|s| {
+        (
+            s.get_liquidation_rate_in(vault.collateral_type, vault.stablecoin)
+                .expect("no collateral rate"),
+            s.get_minimum_liquidation_collateral_ratio(vault.collateral_type),
+        )
+    });
+    let collateral_ratio = crate::compute_collateral_ratio(&vault, collateral_rate);
+    if collateral_ratio >= minimum_collateral_ratio {
+        return Err(ProtocolError::GenericError(format!(
+            "vault {vault_id} has a collateral ratio of {} above the minimum of {}",
+            collateral_ratio.to_f64(),
+            minimum_collateral_ratio.to_f64()
+        )));
+    }
+
+    let provided_liquidity = read_state(|s| s.total_provided_liquidity_amount(vault.stablecoin));
+    if vault.borrowed_tal_amount > provided_liquidity {
+        return Err(ProtocolError::TemporarilyUnavailable(format!(
+            "liquidity pool of {provided_liquidity} {} cannot cover the vault debt",
+            vault.stablecoin
+        )));
+    }
+
+    let keeper = to.unwrap_or(Account::from(caller));
+    log!(
+        INFO,
+        "[liquidate] {caller} liquidates vault {vault_id} with collateral ratio {}",
+        collateral_ratio.to_f64()
+    );
+    mutate_state(|s| record_liquidate_vault(s, vault_id, s.mode, collateral_rate, Some(keeper)));
+    ic_cdk_timers::set_timer(Duration::from_secs(0), || {
+        ic_cdk::spawn(crate::process_pending_transfer())
+    });
+    Ok(())
+}
@@ -1,8 +1,9 @@
 use crate::logs::TRACE_XRC;
-use crate::numeric::UsdBtc;
+use crate::numeric::{Ratio, UsdBtc};
 use crate::state::{mutate_state, read_state};
 use crate::Decimal;
 use crate::Mode;
+use candid::Principal;
 use ic_canister_log::log;
 use ic_xrc_types::GetExchangeRateResult;
 use rust_decimal::prelude::FromPrimitive;
@@ -17,53 +18,132 @@ pub async fn fetch_btc_rate() {
         None => return,
     };
 
-    match crate::management::fetch_btc_price().await {
-        Ok(call_result) => match call_result {
-            GetExchangeRateResult::Ok(exchange_rate_result) => {
-                let rate = Decimal::from_u64(exchange_rate_result.rate).unwrap()
+    match crate::oracle::fetch_btc_price().await {
+        Some(price) => {
+            if price.rate < UsdBtc::from(dec!(1000)) {
+                log!(
+                    TRACE_XRC,
+                    "[FetchPrice] bug: btc rate is below 1000$ switching to read-only at timestamp: {}",
+                    price.timestamp
+                );
+                mutate_state(|s| s.mode = Mode::ReadOnly);
+            };
+            log!(
+                TRACE_XRC,
+                "[FetchPrice] fetched new btc rate: {} with timestamp: {} from {:?}",
+                price.rate,
+                price.timestamp,
+                price.sources
+            );
+            mutate_state(|s| {
+                if s.should_record_btc_price(price.rate, price.timestamp) {
+                    crate::event::record_btc_price_update(
+                        s,
+                        price.rate,
+                        price.timestamp,
+                        price.sources,
+                    );
+                }
+            });
+        }
+        None => log!(
+            TRACE_XRC,
+            "[FetchPrice] failed to fetch the btc rate from any source"
+        ),
+    }
+    fetch_collateral_rates().await;
+    fetch_peg_rates().await;
+    let now = ic_cdk::api::time();
+    if read_state(|s| s.should_accrue_stability_fee(now)) {
+        mutate_state(|s| crate::event::record_accrue_stability_fee(s, now));
+    }
+    if let Some(last_btc_rate) = read_state(|s| s.last_btc_rate) {
+        mutate_state(|s| s.update_total_collateral_ratio_and_mode(last_btc_rate));
+    }
+    if read_state(|s| s.mode != crate::Mode::ReadOnly) {
+        crate::check_vaults();
+    }
+}
+
+/// Refreshes the price of every collateral registered on top of ckBTC.
+async fn fetch_collateral_rates() {
+    let collaterals: Vec<(Principal, String)> = read_state(|s| {
+        s.collaterals
+            .iter()
+            .map(|(ledger_principal, config)| (*ledger_principal, config.xrc_symbol.clone()))
+            .collect()
+    });
+    for (ledger_principal, symbol) in collaterals {
+        match crate::management::fetch_usd_price(&symbol).await {
+            Ok(GetExchangeRateResult::Ok(exchange_rate_result)) => {
+                let price = Decimal::from_u64(exchange_rate_result.rate).unwrap()
                     / Decimal::from_u64(10_u64.pow(exchange_rate_result.metadata.decimals))
                         .unwrap();
-                if rate < dec!(1000) {
-                    log!(
-                        TRACE_XRC,
-                        "[FetchPrice] bug: btc rate is below 1000$ switching to read-only at timestamp: {}",
-                        exchange_rate_result.timestamp
-                    );
-                    mutate_state(|s| s.mode = Mode::ReadOnly);
-                };
                 log!(
                     TRACE_XRC,
-                    "[FetchPrice] fetched new btc rate: {rate} with timestamp: {}",
+                    "[FetchPrice] fetched new {symbol} rate: {price} with timestamp: {}",
                     exchange_rate_result.timestamp
                 );
-                mutate_state(|s| match s.last_btc_timestamp {
-                    Some(last_btc_timestamp) => {
-                        if last_btc_timestamp < exchange_rate_result.timestamp * 1_000_000_000 {
-                            s.last_btc_rate = Some(UsdBtc::from(rate));
-                            s.last_btc_timestamp =
-                                Some(exchange_rate_result.timestamp * 1_000_000_000);
+                let timestamp = exchange_rate_result.timestamp * 1_000_000_000;
+                mutate_state(|s| {
+                    if let Some(config) = s.collaterals.get_mut(&ledger_principal) {
+                        if config.last_timestamp.unwrap_or(0) < timestamp {
+                            config.last_rate = Some(config.normalize_rate(price));
+                            config.last_timestamp = Some(timestamp);
                         }
                     }
-                    None => {
-                        s.last_btc_rate = Some(UsdBtc::from(rate));
-                        s.last_btc_timestamp = Some(exchange_rate_result.timestamp * 1_000_000_000);
-                    }
                 });
             }
-            GetExchangeRateResult::Err(error) => ic_canister_log::log!(
+            Ok(GetExchangeRateResult::Err(error)) => log!(
                 TRACE_XRC,
-                "[FetchPrice] failed to call XRC canister with error: {error:?}"
+                "[FetchPrice] failed to fetch {symbol} rate with error: {error:?}"
             ),
-        },
-        Err(error) => ic_canister_log::log!(
-            TRACE_XRC,
-            "[FetchPrice] failed to call XRC canister with error: {error}"
-        ),
-    }
-    if let Some(last_btc_rate) = read_state(|s| s.last_btc_rate) {
-        mutate_state(|s| s.update_total_collateral_ratio_and_mode(last_btc_rate));
+            Err(error) => log!(
+                TRACE_XRC,
+                "[FetchPrice] failed to call XRC canister for {symbol} with error: {error}"
+            ),
+        }
     }
-    if read_state(|s| s.mode != crate::Mode::ReadOnly) {
-        crate::check_vaults();
+}
+
+/// Refreshes the USD price of the peg asset of every stablecoin not pegged to USD.
+async fn fetch_peg_rates() {
+    let stablecoins: Vec<(Principal, String)> = read_state(|s| {
+        s.stablecoins
+            .iter()
+            .filter(|(_ledger_principal, config)| !config.is_usd_pegged())
+            .map(|(ledger_principal, config)| (*ledger_principal, config.peg_symbol.clone()))
+            .collect()
+    });
+    for (ledger_principal, symbol) in stablecoins {
+        match crate::management::fetch_fiat_usd_price(&symbol).await {
+            Ok(GetExchangeRateResult::Ok(exchange_rate_result)) => {
+                let rate = Decimal::from_u64(exchange_rate_result.rate).unwrap()
+                    / Decimal::from_u64(10_u64.pow(exchange_rate_result.metadata.decimals))
+                        .unwrap();
+                log!(
+                    TRACE_XRC,
+                    "[FetchPrice] fetched new {symbol} peg rate: {rate} with timestamp: {}",
+                    exchange_rate_result.timestamp
+                );
+                let timestamp = exchange_rate_result.timestamp * 1_000_000_000;
+                mutate_state(|s| {
+                    if let Some(config) = s.stablecoins.get_mut(&ledger_principal) {
+                        if config.last_peg_timestamp.unwrap_or(0) < timestamp {
+                            config.last_peg_rate = Some(Ratio::from(rate));
+                            config.last_peg_timestamp = Some(timestamp);
+                        }
+                    }
+                });
+            }
+            Ok(GetExchangeRateResult::Err(error)) => log!(
+                TRACE_XRC,
+                "[FetchPrice] failed to fetch {symbol} peg rate with error: {error:?}"
+            ),
+            Err(error) => log!(
+                TRACE_XRC,
+                "[FetchPrice] failed to call XRC canister for {symbol} with error: {error}"
+            ),
+        }
     }
 }